@@ -0,0 +1,149 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::persist;
+use crate::quota::key_hash;
+
+/// Which clients may be served a cached entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Isolation {
+    /// Any client, whatever its API key.
+    #[default]
+    Shared,
+    /// Entries are partitioned by API key; clients only see what their own
+    /// key fetched.
+    Key,
+    /// Entries are shared, but only served to API keys that Solcast has
+    /// already accepted for the site. Other keys go upstream first.
+    Validated,
+}
+
+/// Serializable form for disk persistence.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DiskKeys {
+    sites: HashMap<String, HashSet<String>>,
+}
+
+/// Hashes of the API keys Solcast has returned data for, per site (the
+/// `site` of a cache key). Persisted to `keys.json` so clients stay
+/// validated across restarts.
+pub struct KeyRegistry {
+    sites: RwLock<HashMap<String, HashSet<String>>>,
+    path: PathBuf,
+    /// Serializes writes to disk, so the newest registry is written last.
+    save_lock: Mutex<()>,
+}
+
+impl KeyRegistry {
+    /// Create the registry, loading known keys from disk if available.
+    pub fn new(cache_dir: &Path) -> Self {
+        let path = cache_dir.join("keys.json");
+        let sites = Self::load_from_disk(&path).unwrap_or_default();
+        Self {
+            sites: RwLock::new(sites),
+            path,
+            save_lock: Mutex::new(()),
+        }
+    }
+
+    /// Remember that upstream accepted `api_key` for `site`.
+    pub async fn record(&self, site: &str, api_key: &str) {
+        let added = {
+            let mut sites = self.sites.write().await;
+            sites
+                .entry(site.to_string())
+                .or_default()
+                .insert(key_hash(api_key))
+        };
+        if added {
+            tracing::info!("{}: API key {} validated", site, key_hash(api_key));
+            self.save_to_disk().await;
+        }
+    }
+
+    /// Whether upstream has accepted `api_key` for `site`.
+    pub async fn is_known(&self, site: &str, api_key: &str) -> bool {
+        self.sites
+            .read()
+            .await
+            .get(site)
+            .is_some_and(|keys| keys.contains(&key_hash(api_key)))
+    }
+
+    async fn save_to_disk(&self) {
+        let _guard = self.save_lock.lock().await;
+        // Read under the lock, so a slower earlier write can't land last
+        let disk = DiskKeys {
+            sites: self.sites.read().await.clone(),
+        };
+        let json = match serde_json::to_string_pretty(&disk) {
+            Ok(j) => j,
+            Err(e) => {
+                tracing::error!("Failed to serialize key registry: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = persist::write_atomic(&self.path, json.into_bytes()).await {
+            tracing::error!(
+                "Failed to write key registry to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn load_from_disk(path: &Path) -> Option<HashMap<String, HashSet<String>>> {
+        let data = std::fs::read_to_string(path).ok()?;
+        let disk: DiskKeys = serde_json::from_str(&data).ok()?;
+        Some(disk.sites)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_record_and_persist() {
+        let dir = TempDir::new().unwrap();
+        {
+            let keys = KeyRegistry::new(dir.path());
+            assert!(!keys.is_known("site1", "key-a").await);
+            keys.record("site1", "key-a").await;
+            assert!(keys.is_known("site1", "key-a").await);
+            assert!(!keys.is_known("site1", "key-b").await);
+            assert!(!keys.is_known("site2", "key-a").await);
+        }
+        let data = std::fs::read_to_string(dir.path().join("keys.json")).unwrap();
+        assert!(!data.contains("key-a"), "raw keys must not be persisted");
+
+        let keys = KeyRegistry::new(dir.path());
+        assert!(keys.is_known("site1", "key-a").await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_records_all_persisted() {
+        let dir = TempDir::new().unwrap();
+        {
+            let keys = std::sync::Arc::new(KeyRegistry::new(dir.path()));
+            let tasks: Vec<_> = (0..20)
+                .map(|i| {
+                    let keys = keys.clone();
+                    tokio::spawn(async move { keys.record("site1", &format!("key-{i}")).await })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        }
+        let keys = KeyRegistry::new(dir.path());
+        for i in 0..20 {
+            assert!(keys.is_known("site1", &format!("key-{i}")).await);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::archive::parse_time;
+use crate::forecast::{parse_periods, Period};
+use crate::proxy::client_key;
+use crate::AppState;
+
+/// Upper bounds, in hours ahead, of every horizon bucket but the last.
+const HORIZON_BOUNDS: [i64; 4] = [6, 24, 48, 72];
+
+/// Error statistics for one group of forecast/actual pairs. `None` when the
+/// group is empty.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub samples: usize,
+    pub mae: Option<f64>,
+    pub rmse: Option<f64>,
+    /// Mean of forecast minus actual; positive means over-forecasting.
+    pub bias: Option<f64>,
+    /// Share of samples whose actual fell within `pv_estimate10..=pv_estimate90`.
+    pub p10_p90_coverage: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HorizonSummary {
+    pub horizon: String,
+    #[serde(flatten)]
+    pub summary: Summary,
+}
+
+/// Accuracy of a site's archived forecasts against its estimated actuals.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub site: String,
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    /// Forecast snapshots that contributed at least one sample.
+    pub snapshots: usize,
+    #[serde(flatten)]
+    pub overall: Summary,
+    pub by_horizon: Vec<HorizonSummary>,
+}
+
+#[derive(Default)]
+struct Score {
+    samples: usize,
+    abs_error: f64,
+    sq_error: f64,
+    error: f64,
+    intervals: usize,
+    covered: usize,
+}
+
+impl Score {
+    fn add(&mut self, forecast: &Period, actual: f64) {
+        let Some(estimate) = field(forecast, "pv_estimate") else {
+            return;
+        };
+        let error = estimate - actual;
+        self.samples += 1;
+        self.abs_error += error.abs();
+        self.sq_error += error * error;
+        self.error += error;
+        if let (Some(p10), Some(p90)) = (
+            field(forecast, "pv_estimate10"),
+            field(forecast, "pv_estimate90"),
+        ) {
+            self.intervals += 1;
+            if (p10..=p90).contains(&actual) {
+                self.covered += 1;
+            }
+        }
+    }
+
+    fn summary(&self) -> Summary {
+        let n = self.samples as f64;
+        let mean = |total: f64| (self.samples > 0).then(|| total / n);
+        Summary {
+            samples: self.samples,
+            mae: mean(self.abs_error),
+            rmse: mean(self.sq_error).map(f64::sqrt),
+            bias: mean(self.error),
+            p10_p90_coverage: (self.intervals > 0)
+                .then(|| self.covered as f64 / self.intervals as f64),
+        }
+    }
+}
+
+fn field(period: &Period, name: &str) -> Option<f64> {
+    period.fields.get(name)?.as_f64()
+}
+
+fn horizon_bucket(hours: i64) -> usize {
+    HORIZON_BOUNDS
+        .iter()
+        .position(|bound| hours < *bound)
+        .unwrap_or(HORIZON_BOUNDS.len())
+}
+
+fn horizon_label(bucket: usize) -> String {
+    let lower = bucket.checked_sub(1).map_or(0, |i| HORIZON_BOUNDS[i]);
+    match HORIZON_BOUNDS.get(bucket) {
+        Some(upper) => format!("{lower}-{upper}h"),
+        None => format!("{lower}h+"),
+    }
+}
+
+/// Score every forecast period in `snapshots` (fetch time, body) that has a
+/// matching actual. Only periods that hadn't started when the snapshot was
+/// fetched count, and each snapshot is a separate sample, so a period
+/// forecast several times is scored at each of those horizons.
+pub fn build_report(
+    site: &str,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+    snapshots: &[(DateTime<Utc>, String)],
+    actuals: &[Period],
+) -> Report {
+    let actuals: HashMap<DateTime<Utc>, (u32, f64)> = actuals
+        .iter()
+        .filter_map(|p| Some((p.end, (p.minutes, field(p, "pv_estimate")?))))
+        .collect();
+
+    let mut overall = Score::default();
+    let mut by_horizon: Vec<Score> = (0..=HORIZON_BOUNDS.len())
+        .map(|_| Score::default())
+        .collect();
+    let mut used = 0;
+    for (fetched_at, body) in snapshots {
+        let Some(periods) = parse_periods(body) else {
+            continue;
+        };
+        let before = overall.samples;
+        for period in &periods {
+            let Some(&(minutes, actual)) = actuals.get(&period.end) else {
+                continue;
+            };
+            let period_start = period.end - chrono::Duration::minutes(minutes as i64);
+            if minutes != period.minutes || period_start < *fetched_at {
+                continue;
+            }
+            let hours = (period.end - *fetched_at).num_hours();
+            overall.add(period, actual);
+            by_horizon[horizon_bucket(hours)].add(period, actual);
+        }
+        if overall.samples > before {
+            used += 1;
+        }
+    }
+
+    Report {
+        site: site.to_string(),
+        start,
+        end,
+        snapshots: used,
+        overall: overall.summary(),
+        by_horizon: by_horizon
+            .iter()
+            .enumerate()
+            .map(|(i, score)| HorizonSummary {
+                horizon: horizon_label(i),
+                summary: score.summary(),
+            })
+            .collect(),
+    }
+}
+
+/// `GET /archive/{rooftop_id}/accuracy?start=&end=`: how well the archived
+/// forecast snapshots matched the archived estimated actuals for periods
+/// ending in `[start, end)`.
+pub async fn accuracy_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(rooftop_id): UrlPath<String>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    if !state
+        .may_read(
+            &rooftop_id,
+            &client_key(&state, Some(&rooftop_id), &headers),
+        )
+        .await
+    {
+        return (StatusCode::FORBIDDEN, "API key not validated for this site").into_response();
+    }
+    let mut start = None;
+    let mut end = None;
+    for (name, value) in &params {
+        let slot = match name.as_str() {
+            "start" => &mut start,
+            "end" => &mut end,
+            _ => continue,
+        };
+        match parse_time(value) {
+            Ok(t) => *slot = Some(t),
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        }
+    }
+
+    let actuals: Vec<Period> = state
+        .archive
+        .query(&rooftop_id, start, end)
+        .await
+        .into_iter()
+        .filter_map(Period::from_fields)
+        .collect();
+    let snapshots = state
+        .history
+        .before(&rooftop_id, end.unwrap_or_else(Utc::now))
+        .await;
+    Json(build_report(&rooftop_id, start, end, &snapshots, &actuals)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        parse_time(s).unwrap()
+    }
+
+    /// A body of PT30M periods `(period_end, pv_estimate, p10, p90)`.
+    fn body(key: &str, periods: &[(&str, f64, f64, f64)]) -> String {
+        let items: Vec<serde_json::Value> = periods
+            .iter()
+            .map(|(end, pv, p10, p90)| {
+                serde_json::json!({
+                    "pv_estimate": pv,
+                    "pv_estimate10": p10,
+                    "pv_estimate90": p90,
+                    "period_end": end,
+                    "period": "PT30M",
+                })
+            })
+            .collect();
+        serde_json::json!({ key: items }).to_string()
+    }
+
+    #[test]
+    fn test_report() {
+        let actuals = parse_periods(&body(
+            "estimated_actuals",
+            &[
+                ("2026-06-20T10:30:00Z", 2.0, 0.0, 0.0),
+                ("2026-06-21T10:30:00Z", 4.0, 0.0, 0.0),
+            ],
+        ))
+        .unwrap();
+        let snapshots = vec![
+            (
+                utc("2026-06-20T09:00:00Z"),
+                body(
+                    "forecasts",
+                    &[
+                        ("2026-06-20T10:30:00Z", 3.0, 1.0, 4.0),
+                        ("2026-06-21T10:30:00Z", 3.0, 1.0, 3.5),
+                        // No actual for this one
+                        ("2026-06-22T10:30:00Z", 3.0, 1.0, 4.0),
+                    ],
+                ),
+            ),
+            (
+                // Already inside the first period, so only the second counts
+                utc("2026-06-20T10:15:00Z"),
+                body(
+                    "forecasts",
+                    &[
+                        ("2026-06-20T10:30:00Z", 2.0, 1.0, 4.0),
+                        ("2026-06-21T10:30:00Z", 5.0, 3.0, 6.0),
+                    ],
+                ),
+            ),
+            (utc("2026-06-20T11:00:00Z"), "not json".to_string()),
+        ];
+        let report = build_report("site1", None, None, &snapshots, &actuals);
+
+        // Errors: +1 (1.5h ahead), -1 (25.5h ahead), +1 (24.25h ahead)
+        assert_eq!(report.snapshots, 2);
+        assert_eq!(report.overall.samples, 3);
+        assert_eq!(report.overall.mae, Some(1.0));
+        assert_eq!(report.overall.rmse, Some(1.0));
+        assert!((report.overall.bias.unwrap() - 1.0 / 3.0).abs() < 1e-9);
+        assert!((report.overall.p10_p90_coverage.unwrap() - 2.0 / 3.0).abs() < 1e-9);
+
+        let labels: Vec<&str> = report
+            .by_horizon
+            .iter()
+            .map(|h| h.horizon.as_str())
+            .collect();
+        assert_eq!(labels, ["0-6h", "6-24h", "24-48h", "48-72h", "72h+"]);
+        assert_eq!(report.by_horizon[0].summary.samples, 1);
+        assert_eq!(report.by_horizon[0].summary.bias, Some(1.0));
+        assert_eq!(report.by_horizon[2].summary.samples, 2);
+        assert_eq!(report.by_horizon[2].summary.bias, Some(0.0));
+        assert_eq!(report.by_horizon[1].summary.mae, None);
+    }
+
+    #[test]
+    fn test_empty_report_serializes_nulls() {
+        let report = build_report("site1", None, None, &[], &[]);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["samples"], 0);
+        assert!(json["mae"].is_null());
+        assert_eq!(json["by_horizon"].as_array().unwrap().len(), 5);
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::RwLock;
+
+use crate::forecast::parse_periods;
+use crate::format::Format;
+use crate::persist;
+use crate::proxy::client_key;
+use crate::AppState;
+
+type Series = BTreeMap<DateTime<Utc>, Map<String, Value>>;
+
+/// Serializable form of one site's archive.
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskSeries {
+    site: String,
+    periods: Vec<Map<String, Value>>,
+}
+
+/// Per-site history of `estimated_actuals` periods, kept beyond the 7 days
+/// Solcast returns.
+///
+/// Every successful response is merged in by `period_end`; a later response
+/// replaces earlier values for the same period, since Solcast revises recent
+/// estimates. Each site is persisted to its own file under `archive/`.
+pub struct ActualsArchive {
+    sites: RwLock<HashMap<String, Series>>,
+    dir: PathBuf,
+}
+
+impl ActualsArchive {
+    /// Create the archive, loading any persisted sites from disk.
+    pub fn new(cache_dir: &Path) -> Self {
+        let dir = cache_dir.join("archive");
+        let sites = Self::load_from_disk(&dir);
+        if !sites.is_empty() {
+            tracing::info!("Loaded estimated actuals archive for {} sites", sites.len());
+        }
+        Self {
+            sites: RwLock::new(sites),
+            dir,
+        }
+    }
+
+    /// Merge the periods of an `estimated_actuals` body into a site's history.
+    /// Returns how many periods were new.
+    pub async fn record(&self, rooftop_id: &str, body: &str) -> usize {
+        let Some(periods) = parse_periods(body) else {
+            tracing::warn!(
+                "{}/estimated_actuals: not archived, unrecognised body",
+                rooftop_id
+            );
+            return 0;
+        };
+        let mut sites = self.sites.write().await;
+        let series = sites.entry(rooftop_id.to_string()).or_default();
+        let before = series.len();
+        for period in periods {
+            series.insert(period.end, period.fields);
+        }
+        let added = series.len() - before;
+        let disk = DiskSeries {
+            site: rooftop_id.to_string(),
+            periods: series.values().cloned().collect(),
+        };
+        tracing::debug!(
+            "{}/estimated_actuals: archived {} new periods",
+            rooftop_id,
+            added
+        );
+        // Still holding the lock, so concurrent merges reach disk in order
+        self.save_to_disk(&disk).await;
+        drop(sites);
+        added
+    }
+
+    /// Archived periods with `start <= period_end < end`, oldest first.
+    pub async fn query(
+        &self,
+        rooftop_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Vec<Map<String, Value>> {
+        let sites = self.sites.read().await;
+        let Some(series) = sites.get(rooftop_id) else {
+            return Vec::new();
+        };
+        series
+            .iter()
+            .filter(|(t, _)| start.is_none_or(|s| **t >= s) && end.is_none_or(|e| **t < e))
+            .map(|(_, fields)| fields.clone())
+            .collect()
+    }
+
+    async fn save_to_disk(&self, disk: &DiskSeries) {
+        let path = self.dir.join(format!("{}.json", file_stem(&disk.site)));
+        let json = match serde_json::to_string(disk) {
+            Ok(j) => j,
+            Err(e) => {
+                tracing::error!("Failed to serialize archive for {}: {}", disk.site, e);
+                return;
+            }
+        };
+        if let Err(e) = tokio::fs::create_dir_all(&self.dir).await {
+            tracing::error!("Failed to create {}: {}", self.dir.display(), e);
+            return;
+        }
+        if let Err(e) = persist::write_atomic(&path, json.into_bytes()).await {
+            tracing::error!("Failed to write archive to {}: {}", path.display(), e);
+        }
+    }
+
+    fn load_from_disk(dir: &Path) -> HashMap<String, Series> {
+        let mut sites = HashMap::new();
+        let Ok(files) = std::fs::read_dir(dir) else {
+            return sites;
+        };
+        for file in files.flatten() {
+            let path = file.path();
+            let Some(disk) = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|data| serde_json::from_str::<DiskSeries>(&data).ok())
+            else {
+                tracing::warn!("Skipping unreadable archive file {}", path.display());
+                continue;
+            };
+            let series: Series = disk
+                .periods
+                .into_iter()
+                .filter_map(|fields| Some((period_end(&fields)?, fields)))
+                .collect();
+            sites.insert(disk.site, series);
+        }
+        sites
+    }
+}
+
+fn period_end(fields: &Map<String, Value>) -> Option<DateTime<Utc>> {
+    let end = fields.get("period_end")?.as_str()?;
+    Some(DateTime::parse_from_rfc3339(end).ok()?.with_timezone(&Utc))
+}
+
+/// A file name for a site id, which comes from the request path. Bytes
+/// other than lowercase letters, digits, `-` and `_` are percent-encoded,
+/// so distinct ids never share a file, even on case-insensitive file
+/// systems.
+pub fn file_stem(rooftop_id: &str) -> String {
+    let mut stem = String::with_capacity(rooftop_id.len());
+    for b in rooftop_id.bytes() {
+        if b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_' {
+            stem.push(b as char);
+        } else {
+            stem.push_str(&format!("%{b:02X}"));
+        }
+    }
+    stem
+}
+
+/// An RFC 3339 timestamp or a `YYYY-MM-DD` date (UTC midnight).
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|d| d.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("Invalid time '{s}', expected RFC 3339 or YYYY-MM-DD"))
+}
+
+/// `GET /archive/{rooftop_id}/actuals?start=&end=`: archived estimated
+/// actuals for a site, in the same shape (and formats) as Solcast's.
+pub async fn actuals_handler(
+    State(state): State<Arc<AppState>>,
+    UrlPath(rooftop_id): UrlPath<String>,
+    Query(mut params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    if !state
+        .may_read(
+            &rooftop_id,
+            &client_key(&state, Some(&rooftop_id), &headers),
+        )
+        .await
+    {
+        return (StatusCode::FORBIDDEN, "API key not validated for this site").into_response();
+    }
+    let format = match Format::negotiate(&mut params, &headers) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let mut start = None;
+    let mut end = None;
+    for (name, value) in &params {
+        let slot = match name.as_str() {
+            "start" => &mut start,
+            "end" => &mut end,
+            _ => continue,
+        };
+        match parse_time(value) {
+            Ok(t) => *slot = Some(t),
+            Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        }
+    }
+
+    let periods = state.archive.query(&rooftop_id, start, end).await;
+    let json = serde_json::json!({ "estimated_actuals": periods }).to_string();
+    let body = format.render(&json).unwrap_or(json);
+    ([("Content-Type", format.content_type())], body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use tempfile::TempDir;
+
+    fn actuals(periods: &[(&str, f64)]) -> String {
+        let items: Vec<Value> = periods
+            .iter()
+            .map(|(end, pv)| {
+                serde_json::json!({ "pv_estimate": pv, "period_end": end, "period": "PT30M" })
+            })
+            .collect();
+        serde_json::json!({ "estimated_actuals": items }).to_string()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        parse_time(s).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_merge_dedupes_and_revises() {
+        let dir = TempDir::new().unwrap();
+        let archive = ActualsArchive::new(dir.path());
+
+        let first = actuals(&[("2026-06-20T10:00:00Z", 1.0), ("2026-06-20T10:30:00Z", 2.0)]);
+        assert_eq!(archive.record("site1", &first).await, 2);
+        let second = actuals(&[("2026-06-20T10:30:00Z", 2.5), ("2026-06-20T11:00:00Z", 3.0)]);
+        assert_eq!(archive.record("site1", &second).await, 1);
+
+        let all = archive.query("site1", None, None).await;
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[1]["pv_estimate"], 2.5);
+        assert!(archive.query("site2", None, None).await.is_empty());
+        assert_eq!(archive.record("site1", "not json").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_query_range() {
+        let dir = TempDir::new().unwrap();
+        let archive = ActualsArchive::new(dir.path());
+        let body = actuals(&[
+            ("2026-06-19T23:30:00Z", 0.0),
+            ("2026-06-20T00:00:00Z", 0.0),
+            ("2026-06-20T12:00:00Z", 4.0),
+            ("2026-06-21T00:00:00Z", 0.0),
+        ]);
+        archive.record("site1", &body).await;
+
+        let day = archive
+            .query("site1", Some(utc("2026-06-20")), Some(utc("2026-06-21")))
+            .await;
+        assert_eq!(day.len(), 2);
+        let since = archive
+            .query("site1", Some(utc("2026-06-20T12:00:00Z")), None)
+            .await;
+        assert_eq!(since.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let dir = TempDir::new().unwrap();
+        {
+            let archive = ActualsArchive::new(dir.path());
+            archive
+                .record("site/1", &actuals(&[("2026-06-20T10:00:00Z", 1.0)]))
+                .await;
+        }
+        assert!(dir.path().join("archive/site%2F1.json").exists());
+        let archive = ActualsArchive::new(dir.path());
+        assert_eq!(archive.query("site/1", None, None).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_similar_ids_kept_apart() {
+        let dir = TempDir::new().unwrap();
+        let ids = ["a.b", "a_b", "A_b", "a%2Eb"];
+        let stems: HashSet<String> = ids.iter().map(|id| file_stem(id)).collect();
+        assert_eq!(stems.len(), ids.len());
+        assert_eq!(file_stem("abcd-1234_x"), "abcd-1234_x");
+
+        {
+            let archive = ActualsArchive::new(dir.path());
+            archive
+                .record("a.b", &actuals(&[("2026-06-20T10:00:00Z", 1.0)]))
+                .await;
+            archive
+                .record("a_b", &actuals(&[("2026-06-20T10:00:00Z", 2.0)]))
+                .await;
+        }
+        let archive = ActualsArchive::new(dir.path());
+        assert_eq!(
+            archive.query("a.b", None, None).await[0]["pv_estimate"],
+            1.0
+        );
+        assert_eq!(
+            archive.query("a_b", None, None).await[0]["pv_estimate"],
+            2.0
+        );
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2026-06-20").unwrap(),
+            parse_time("2026-06-20T00:00:00Z").unwrap()
+        );
+        assert!(parse_time("yesterday").is_err());
+    }
+}
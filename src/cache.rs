@@ -1,72 +1,153 @@
 use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tokio::time::Instant;
 
+use crate::compress::{Body, Variants};
+use crate::store::{CacheStore, JsonStore};
+
 /// A single cached response.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheEntry {
-    pub body: String,
+    /// Kept gzipped, in memory and on disk, with a hash of its content.
+    #[serde(rename = "body_gzip")]
+    pub body: Body,
     pub content_type: String,
     pub fetched_at: DateTime<Utc>,
+    /// When upstream's `Cache-Control` or `Expires` said the body stops
+    /// being fresh, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The body as already rendered for clients. Shared by clones, so a
+    /// view is rendered and compressed once per entry rather than per hit.
+    #[serde(skip)]
+    pub variants: Variants,
+}
+
+impl CacheEntry {
+    pub fn new(body: Body, content_type: String, fetched_at: DateTime<Utc>) -> Self {
+        Self {
+            body,
+            content_type,
+            fetched_at,
+            expires_at: None,
+            variants: Variants::default(),
+        }
+    }
+
+    /// Bytes held for the entry: its compressed body and rendered views.
+    pub fn stored_len(&self) -> usize {
+        self.body.stored_len() + self.variants.stored_len()
+    }
+
+    /// Seconds the entry stays fresh after it was fetched: `ttl_secs`, or
+    /// less if upstream said it expires sooner.
+    pub fn lifetime(&self, ttl_secs: u64) -> u64 {
+        match self.expires_at {
+            // Rounded, as `fetched_at` is taken just after upstream answered
+            Some(at) => {
+                let millis = (at - self.fetched_at).num_milliseconds().max(0) as u64;
+                ttl_secs.min((millis + 500) / 1000)
+            }
+            None => ttl_secs,
+        }
+    }
+}
+
+/// Size and age of one cached entry, for metrics.
+#[derive(Debug, Clone)]
+pub struct EntrySummary {
+    pub key: String,
+    pub bytes: usize,
+    pub fetched_at: DateTime<Utc>,
 }
 
-/// Serializable form for disk persistence (without Instant fields).
-#[derive(Debug, Serialize, Deserialize)]
-struct DiskCache {
-    entries: HashMap<String, CacheEntry>,
+/// Bounds on what the cache keeps; zero means unbounded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheLimits {
+    /// Most entries kept.
+    pub max_entries: usize,
+    /// Most bytes of compressed bodies kept, in memory and on disk.
+    pub max_bytes: u64,
+    /// Entries neither fetched nor read for this long are deleted.
+    pub max_idle: Option<chrono::Duration>,
 }
 
 /// Cache key: (rooftop_id, endpoint_type) serialized as "rooftop_id:endpoint_type".
-fn cache_key(rooftop_id: &str, endpoint: &str) -> String {
+pub fn cache_key(rooftop_id: &str, endpoint: &str) -> String {
     format!("{rooftop_id}:{endpoint}")
 }
 
-/// In-memory + file-backed cache with TTL and rate limiting.
+/// In-memory cache with TTL and rate limiting, persisted to a `CacheStore`.
 pub struct ProxyCache {
     entries: RwLock<HashMap<String, CacheEntry>>,
     /// Tracks when we last attempted an upstream fetch per key (for rate limiting).
     last_attempt: RwLock<HashMap<String, Instant>>,
-    cache_path: PathBuf,
+    /// When each entry was last read, for eviction. Kept in memory only, so
+    /// after a restart entries count as last used when fetched.
+    last_read: std::sync::Mutex<HashMap<String, DateTime<Utc>>>,
+    limits: CacheLimits,
+    store: Arc<dyn CacheStore>,
+    /// Serializes writes to the store, so the newest entry is written last.
+    save_lock: Mutex<()>,
 }
 
 impl ProxyCache {
-    /// Create a new cache, loading persisted entries from disk if available.
-    pub fn new(cache_dir: &Path) -> Self {
-        let cache_path = cache_dir.join("cache.json");
-        let entries = Self::load_from_disk(&cache_path).unwrap_or_default();
+    /// Create a cache persisted to `cache.json`, loading its entries if
+    /// available and keeping `snapshots` previous versions of the file.
+    pub fn new(cache_dir: &Path, snapshots: usize) -> Self {
+        Self::with_store(Arc::new(JsonStore::open(cache_dir, snapshots)))
+    }
+
+    /// Create a cache persisted to `store`, loading its entries.
+    pub fn with_store(store: Arc<dyn CacheStore>) -> Self {
+        let entries = store.load();
         let count = entries.len();
         if count > 0 {
-            tracing::info!("Loaded {} cache entries from disk", count);
+            tracing::info!("Loaded {} cache entries from {}", count, store.describe());
         }
         Self {
             entries: RwLock::new(entries),
             last_attempt: RwLock::new(HashMap::new()),
-            cache_path,
+            last_read: std::sync::Mutex::new(HashMap::new()),
+            limits: CacheLimits::default(),
+            store,
+            save_lock: Mutex::new(()),
         }
     }
 
-    /// Get a cached entry.
+    /// Keep the cache within `limits`, enforced as entries are added and by
+    /// `sweep`.
+    pub fn with_limits(mut self, limits: CacheLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Get a cached entry, marking it used.
     pub async fn get(&self, rooftop_id: &str, endpoint: &str) -> Option<(CacheEntry, i64)> {
         let key = cache_key(rooftop_id, endpoint);
         let entries = self.entries.read().await;
-        entries.get(&key).map(|e| {
-            let age = Utc::now().signed_duration_since(e.fetched_at).num_seconds();
-            (e.clone(), age)
-        })
+        let entry = entries.get(&key)?;
+        let now = Utc::now();
+        let age = now.signed_duration_since(entry.fetched_at).num_seconds();
+        let entry = entry.clone();
+        drop(entries);
+        self.last_read.lock().unwrap().insert(key, now);
+        Some((entry, age))
     }
 
-    /// Check if cached entry is fresh (within TTL).
+    /// Check if cached entry is fresh (within TTL and upstream's expiry).
     pub async fn is_fresh(&self, rooftop_id: &str, endpoint: &str, ttl_secs: u64) -> bool {
         let key = cache_key(rooftop_id, endpoint);
         let entries = self.entries.read().await;
         match entries.get(&key) {
             Some(e) => {
                 let age = Utc::now().signed_duration_since(e.fetched_at).num_seconds();
-                age >= 0 && (age as u64) < ttl_secs
+                age >= 0 && (age as u64) < e.lifetime(ttl_secs)
             }
             None => false,
         }
@@ -74,11 +155,19 @@ impl ProxyCache {
 
     /// Check if rate limit allows a new upstream fetch.
     pub async fn can_fetch(&self, rooftop_id: &str, endpoint: &str, rate_limit_secs: u64) -> bool {
+        self.retry_in(rooftop_id, endpoint, rate_limit_secs).await == 0
+    }
+
+    /// Seconds until the rate limit allows a new upstream fetch, 0 if it does now.
+    pub async fn retry_in(&self, rooftop_id: &str, endpoint: &str, rate_limit_secs: u64) -> u64 {
         let key = cache_key(rooftop_id, endpoint);
         let attempts = self.last_attempt.read().await;
         match attempts.get(&key) {
-            Some(last) => last.elapsed().as_secs() >= rate_limit_secs,
-            None => true,
+            Some(last) => (*last + std::time::Duration::from_secs(rate_limit_secs))
+                .saturating_duration_since(Instant::now())
+                .as_secs_f64()
+                .ceil() as u64,
+            None => 0,
         }
     }
 
@@ -106,19 +195,129 @@ impl ProxyCache {
         attempts.insert(key, fake_past);
     }
 
-    /// Store a response in cache and persist to disk.
-    pub async fn set(&self, rooftop_id: &str, endpoint: &str, body: String, content_type: String) {
+    /// Store a response in cache and persist to disk. Returns the entry.
+    pub async fn set(
+        &self,
+        rooftop_id: &str,
+        endpoint: &str,
+        body: String,
+        content_type: String,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> CacheEntry {
         let key = cache_key(rooftop_id, endpoint);
-        let entry = CacheEntry {
-            body,
-            content_type,
-            fetched_at: Utc::now(),
-        };
+        let mut entry = CacheEntry::new(Body::new(&body), content_type, Utc::now());
+        entry.expires_at = expires_at;
         {
             let mut entries = self.entries.write().await;
-            entries.insert(key, entry);
+            entries.insert(key.clone(), entry.clone());
+        }
+        self.save(rooftop_id, endpoint).await;
+        self.evict_over_limits(Some(&key)).await;
+        entry
+    }
+
+    /// Delete entries idle for longer than the limit allows, evict any over
+    /// the size limits, and forget upstream attempts older than
+    /// `attempt_window` (the longest rate limit), which hold nothing back.
+    pub async fn sweep(&self, attempt_window: std::time::Duration) {
+        if let Some(max_idle) = self.limits.max_idle {
+            let cutoff = Utc::now() - max_idle;
+            let expired: Vec<String> = {
+                let mut entries = self.entries.write().await;
+                let reads = self.last_read.lock().unwrap();
+                let expired: Vec<String> = entries
+                    .iter()
+                    .filter(|(key, e)| last_used(&reads, key, e) < cutoff)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in &expired {
+                    entries.remove(key);
+                }
+                expired
+            };
+            self.forget(expired, "unused").await;
+        }
+        self.evict_over_limits(None).await;
+        let now = Instant::now();
+        self.last_attempt
+            .write()
+            .await
+            .retain(|_, last| *last + attempt_window > now);
+    }
+
+    /// Evict least recently used entries, other than `keep`, until the
+    /// cache is within its entry and byte limits.
+    async fn evict_over_limits(&self, keep: Option<&str>) {
+        let CacheLimits {
+            max_entries,
+            max_bytes,
+            ..
+        } = self.limits;
+        if max_entries == 0 && max_bytes == 0 {
+            return;
+        }
+        let evicted = {
+            let mut entries = self.entries.write().await;
+            let reads = self.last_read.lock().unwrap();
+            let mut bytes: u64 = entries.values().map(|e| e.stored_len() as u64).sum();
+            let mut by_use: Vec<(DateTime<Utc>, String)> = entries
+                .iter()
+                .filter(|(key, _)| Some(key.as_str()) != keep)
+                .map(|(key, e)| (last_used(&reads, key, e), key.clone()))
+                .collect();
+            by_use.sort();
+            let mut evicted = Vec::new();
+            for (_, key) in by_use {
+                let over = (max_entries > 0 && entries.len() > max_entries)
+                    || (max_bytes > 0 && bytes > max_bytes);
+                if !over {
+                    break;
+                }
+                if let Some(e) = entries.remove(&key) {
+                    bytes = bytes.saturating_sub(e.stored_len() as u64);
+                    evicted.push(key);
+                }
+            }
+            evicted
+        };
+        self.forget(evicted, "over the size limit").await;
+    }
+
+    /// Remove entries already taken out of memory from the store.
+    async fn forget(&self, keys: Vec<String>, reason: &str) {
+        if keys.is_empty() {
+            return;
+        }
+        {
+            let mut reads = self.last_read.lock().unwrap();
+            for key in &keys {
+                reads.remove(key);
+            }
+        }
+        tracing::info!(
+            "Removed {} cache entries ({}): {}",
+            keys.len(),
+            reason,
+            keys.join(", ")
+        );
+        let _guard = self.save_lock.lock().await;
+        // Any stored again since are kept
+        let keys: Vec<String> = {
+            let entries = self.entries.read().await;
+            keys.into_iter()
+                .filter(|key| !entries.contains_key(key))
+                .collect()
+        };
+        let store = self.store.clone();
+        match tokio::task::spawn_blocking(move || store.remove(&keys)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!(
+                "Failed to remove cache entries from {}: {}",
+                self.store.describe(),
+                e
+            ),
+            Err(e) => tracing::error!("Failed to remove cache entries: {}", e),
         }
-        self.save_to_disk().await;
     }
 
     /// Number of cached entries.
@@ -126,33 +325,54 @@ impl ProxyCache {
         self.entries.read().await.len()
     }
 
-    async fn save_to_disk(&self) {
+    /// Key, uncompressed body size and fetch time of every entry, sorted by key.
+    pub async fn summaries(&self) -> Vec<EntrySummary> {
         let entries = self.entries.read().await;
-        let disk = DiskCache {
-            entries: entries.clone(),
-        };
-        let json = match serde_json::to_string_pretty(&disk) {
-            Ok(j) => j,
-            Err(e) => {
-                tracing::error!("Failed to serialize cache: {}", e);
-                return;
-            }
+        let mut summaries: Vec<EntrySummary> = entries
+            .iter()
+            .map(|(key, e)| EntrySummary {
+                key: key.clone(),
+                bytes: e.body.len(),
+                fetched_at: e.fetched_at,
+            })
+            .collect();
+        summaries.sort_by(|a, b| a.key.cmp(&b.key));
+        summaries
+    }
+
+    /// Write an entry's current value to the store.
+    async fn save(&self, rooftop_id: &str, endpoint: &str) {
+        let _guard = self.save_lock.lock().await;
+        // Read under the lock, so a slower earlier write can't land last
+        let key = cache_key(rooftop_id, endpoint);
+        let Some(entry) = self.entries.read().await.get(&key).cloned() else {
+            return;
         };
-        if let Err(e) = tokio::fs::write(&self.cache_path, json).await {
-            tracing::error!(
-                "Failed to write cache to {}: {}",
-                self.cache_path.display(),
+        let store = self.store.clone();
+        let (site, endpoint) = (rooftop_id.to_string(), endpoint.to_string());
+        let result = tokio::task::spawn_blocking(move || store.put(&site, &endpoint, &entry)).await;
+        match result {
+            Ok(Ok(())) => tracing::debug!("Cache entry {} saved", key),
+            Ok(Err(e)) => tracing::error!(
+                "Failed to write cache entry {} to {}: {}",
+                key,
+                self.store.describe(),
                 e
-            );
-        } else {
-            tracing::debug!("Cache saved to {}", self.cache_path.display());
+            ),
+            Err(e) => tracing::error!("Failed to write cache entry {}: {}", key, e),
         }
     }
+}
 
-    fn load_from_disk(path: &Path) -> Option<HashMap<String, CacheEntry>> {
-        let data = std::fs::read_to_string(path).ok()?;
-        let disk: DiskCache = serde_json::from_str(&data).ok()?;
-        Some(disk.entries)
+/// When an entry was last fetched or read.
+fn last_used(
+    reads: &HashMap<String, DateTime<Utc>>,
+    key: &str,
+    entry: &CacheEntry,
+) -> DateTime<Utc> {
+    match reads.get(key) {
+        Some(read) => entry.fetched_at.max(*read),
+        None => entry.fetched_at,
     }
 }
 
@@ -164,7 +384,7 @@ mod tests {
     #[tokio::test]
     async fn test_cache_miss_then_hit() {
         let dir = TempDir::new().unwrap();
-        let cache = ProxyCache::new(dir.path());
+        let cache = ProxyCache::new(dir.path(), 2);
 
         // Initially empty
         assert!(cache.get("site1", "forecasts").await.is_none());
@@ -172,20 +392,26 @@ mod tests {
 
         // Insert
         cache
-            .set("site1", "forecasts", "{}".into(), "application/json".into())
+            .set(
+                "site1",
+                "forecasts",
+                "{}".into(),
+                "application/json".into(),
+                None,
+            )
             .await;
 
         // Now fresh
         assert!(cache.is_fresh("site1", "forecasts", 7200).await);
         let (entry, age) = cache.get("site1", "forecasts").await.unwrap();
-        assert_eq!(entry.body, "{}");
+        assert_eq!(entry.body.text(), "{}");
         assert!(age < 2);
     }
 
     #[tokio::test]
     async fn test_rate_limiting() {
         let dir = TempDir::new().unwrap();
-        let cache = ProxyCache::new(dir.path());
+        let cache = ProxyCache::new(dir.path(), 2);
 
         // Can fetch initially
         assert!(cache.can_fetch("site1", "forecasts", 9000).await);
@@ -200,10 +426,30 @@ mod tests {
         assert!(cache.can_fetch("site1", "forecasts", 0).await);
     }
 
+    #[tokio::test]
+    async fn test_failed_attempt_backoff() {
+        let dir = TempDir::new().unwrap();
+        let cache = ProxyCache::new(dir.path(), 2);
+
+        cache
+            .mark_failed_attempt("site1", "forecasts", 9000, 60)
+            .await;
+        let wait = cache.retry_in("site1", "forecasts", 9000).await;
+        assert!((59..=60).contains(&wait), "{wait}");
+
+        // A backoff never outlasts the rate limit
+        cache
+            .mark_failed_attempt("site1", "forecasts", 60, 3600)
+            .await;
+        let wait = cache.retry_in("site1", "forecasts", 60).await;
+        assert!((59..=60).contains(&wait), "{wait}");
+        assert_eq!(cache.retry_in("site2", "forecasts", 60).await, 0);
+    }
+
     #[tokio::test]
     async fn test_different_endpoints_independent() {
         let dir = TempDir::new().unwrap();
-        let cache = ProxyCache::new(dir.path());
+        let cache = ProxyCache::new(dir.path(), 2);
 
         cache
             .set(
@@ -211,6 +457,7 @@ mod tests {
                 "forecasts",
                 "{\"f\":1}".into(),
                 "application/json".into(),
+                None,
             )
             .await;
 
@@ -218,19 +465,51 @@ mod tests {
         assert!(!cache.is_fresh("site1", "estimated_actuals", 7200).await);
     }
 
+    #[tokio::test]
+    async fn test_upstream_expiry_shortens_ttl() {
+        let dir = TempDir::new().unwrap();
+        let cache = ProxyCache::new(dir.path(), 2);
+
+        let expires_at = Utc::now() + chrono::Duration::seconds(60);
+        let entry = cache
+            .set(
+                "site1",
+                "forecasts",
+                "{}".into(),
+                "application/json".into(),
+                Some(expires_at),
+            )
+            .await;
+        assert!((59..=60).contains(&entry.lifetime(7200)));
+        assert_eq!(entry.lifetime(30), 30);
+        assert!(cache.is_fresh("site1", "forecasts", 7200).await);
+
+        cache
+            .set(
+                "site1",
+                "forecasts",
+                "{}".into(),
+                "application/json".into(),
+                Some(Utc::now()),
+            )
+            .await;
+        assert!(!cache.is_fresh("site1", "forecasts", 7200).await);
+    }
+
     #[tokio::test]
     async fn test_disk_persistence() {
         let dir = TempDir::new().unwrap();
 
         // Write to cache
         {
-            let cache = ProxyCache::new(dir.path());
+            let cache = ProxyCache::new(dir.path(), 2);
             cache
                 .set(
                     "site1",
                     "forecasts",
                     "{\"data\":true}".into(),
                     "application/json".into(),
+                    None,
                 )
                 .await;
             assert_eq!(cache.entry_count().await, 1);
@@ -238,10 +517,124 @@ mod tests {
 
         // Load from disk in new instance
         {
-            let cache = ProxyCache::new(dir.path());
+            let cache = ProxyCache::new(dir.path(), 2);
             assert_eq!(cache.entry_count().await, 1);
             let (entry, _) = cache.get("site1", "forecasts").await.unwrap();
-            assert_eq!(entry.body, "{\"data\":true}");
+            assert_eq!(entry.body.text(), "{\"data\":true}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_persistence() {
+        use crate::store::SqliteStore;
+        let dir = TempDir::new().unwrap();
+        {
+            let cache = ProxyCache::with_store(Arc::new(SqliteStore::open(dir.path()).unwrap()));
+            cache
+                .set(
+                    "site1",
+                    "forecasts",
+                    "{}".into(),
+                    "application/json".into(),
+                    None,
+                )
+                .await;
         }
+        let cache = ProxyCache::with_store(Arc::new(SqliteStore::open(dir.path()).unwrap()));
+        assert!(cache.is_fresh("site1", "forecasts", 7200).await);
+        assert!(!dir.path().join("cache.json").exists());
+    }
+
+    async fn set_json(cache: &ProxyCache, site: &str, body: &str) {
+        cache
+            .set(
+                site,
+                "forecasts",
+                body.into(),
+                "application/json".into(),
+                None,
+            )
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let dir = TempDir::new().unwrap();
+        let limits = CacheLimits {
+            max_entries: 2,
+            ..Default::default()
+        };
+        {
+            let cache = ProxyCache::new(dir.path(), 2).with_limits(limits);
+            set_json(&cache, "site1", "{}").await;
+            set_json(&cache, "site2", "{}").await;
+            // Reading site1 makes site2 the least recently used
+            cache.get("site1", "forecasts").await.unwrap();
+            set_json(&cache, "site3", "{}").await;
+            assert_eq!(cache.entry_count().await, 2);
+            assert!(cache.get("site2", "forecasts").await.is_none());
+        }
+        // Gone from disk too
+        let cache = ProxyCache::new(dir.path(), 2);
+        let keys: Vec<String> = cache.summaries().await.into_iter().map(|s| s.key).collect();
+        assert_eq!(keys, ["site1:forecasts", "site3:forecasts"]);
+    }
+
+    #[tokio::test]
+    async fn test_byte_limit() {
+        let dir = TempDir::new().unwrap();
+        let body = |seed: u64| {
+            let values: Vec<String> = (0..500).map(|i| (i * 7919 + seed).to_string()).collect();
+            format!("[{}]", values.join(","))
+        };
+        let size = CacheEntry::new(Body::new(&body(1)), String::new(), Utc::now()).stored_len();
+        let cache = ProxyCache::new(dir.path(), 2).with_limits(CacheLimits {
+            max_bytes: (size * 5 / 2) as u64,
+            ..Default::default()
+        });
+        for seed in 1..=4 {
+            set_json(&cache, &format!("site{seed}"), &body(seed)).await;
+        }
+        // The newest entry is always kept
+        assert_eq!(cache.entry_count().await, 2);
+        assert!(cache.get("site4", "forecasts").await.is_some());
+        assert!(cache.get("site1", "forecasts").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_removes_idle_entries_and_attempts() {
+        use crate::store::SqliteStore;
+        let dir = TempDir::new().unwrap();
+        let limits = CacheLimits {
+            max_idle: Some(chrono::Duration::days(7)),
+            ..Default::default()
+        };
+        let open = || {
+            ProxyCache::with_store(Arc::new(SqliteStore::open(dir.path()).unwrap()))
+                .with_limits(limits)
+        };
+        let cache = open();
+        set_json(&cache, "old", "{}").await;
+        set_json(&cache, "read", "{}").await;
+        set_json(&cache, "new", "{}").await;
+        {
+            let mut entries = cache.entries.write().await;
+            for key in ["old:forecasts", "read:forecasts"] {
+                entries.get_mut(key).unwrap().fetched_at = Utc::now() - chrono::Duration::days(8);
+            }
+        }
+        cache.get("read", "forecasts").await.unwrap();
+        cache.mark_attempt("old", "forecasts").await;
+        cache.mark_attempt("new", "forecasts").await;
+
+        cache.sweep(std::time::Duration::from_secs(9000)).await;
+        assert!(cache.get("old", "forecasts").await.is_none());
+        assert!(cache.get("read", "forecasts").await.is_some());
+        assert_eq!(open().entry_count().await, 2);
+        // Attempts are kept while the rate limit still applies
+        assert!(!cache.can_fetch("new", "forecasts", 9000).await);
+
+        cache.sweep(std::time::Duration::ZERO).await;
+        assert!(cache.last_attempt.read().await.is_empty());
     }
 }
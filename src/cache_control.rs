@@ -0,0 +1,125 @@
+/// What a client's `Cache-Control` request header asks of the cache
+/// (RFC 9111 5.2.1). Unknown directives are ignored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Directives {
+    /// Go upstream whatever the cache holds.
+    pub no_cache: bool,
+    /// Oldest response, in seconds, the client accepts.
+    pub max_age: Option<u64>,
+    /// How many seconds past its freshness lifetime the client accepts a
+    /// response; `u64::MAX` for `max-stale` without a value.
+    pub max_stale: Option<u64>,
+    /// Seconds of freshness a response must have left.
+    pub min_fresh: Option<u64>,
+    /// Never go upstream; answer from the cache or not at all.
+    pub only_if_cached: bool,
+}
+
+impl Directives {
+    pub fn parse(header: Option<&str>) -> Self {
+        let mut directives = Self::default();
+        for item in header.unwrap_or("").split(',') {
+            let (name, value) = match item.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (item.trim(), None),
+            };
+            let secs = value.and_then(|v| v.parse::<u64>().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "no-cache" => directives.no_cache = true,
+                "max-age" => directives.max_age = secs,
+                "max-stale" => {
+                    directives.max_stale = if value.is_some() {
+                        secs
+                    } else {
+                        Some(u64::MAX)
+                    }
+                }
+                "min-fresh" => directives.min_fresh = secs,
+                "only-if-cached" => directives.only_if_cached = true,
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    /// Whether the client takes data fresher than the cache's TTL alone
+    /// would give it.
+    pub fn wants_fresher(&self) -> bool {
+        self.max_age.is_some() || self.min_fresh.is_some()
+    }
+
+    /// Whether an entry `age` seconds old that stays fresh for `lifetime`
+    /// seconds may be served as fresh.
+    pub fn accepts_fresh(&self, age: i64, lifetime: u64) -> bool {
+        let age = age.max(0) as u64;
+        age < lifetime && self.within_limits(age, lifetime)
+    }
+
+    /// Whether the client has asked for a stale entry rather than waiting
+    /// on upstream, with `max-stale`.
+    pub fn accepts_stale(&self, age: i64, lifetime: u64) -> bool {
+        self.max_stale.is_some() && self.accepts_fallback(age, lifetime)
+    }
+
+    /// Whether an entry may be served stale when upstream can't provide
+    /// fresh data. Without directives saying otherwise, any entry will do.
+    pub fn accepts_fallback(&self, age: i64, lifetime: u64) -> bool {
+        let age = age.max(0) as u64;
+        self.max_stale
+            .is_none_or(|stale| age < lifetime.saturating_add(stale))
+            && self.within_limits(age, lifetime)
+    }
+
+    fn within_limits(&self, age: u64, lifetime: u64) -> bool {
+        self.max_age.is_none_or(|max| age <= max)
+            && self
+                .min_fresh
+                .is_none_or(|min| age.saturating_add(min) < lifetime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Directives::parse(None), Directives::default());
+        let d = Directives::parse(Some("max-age=60, MIN-FRESH=\"10\", only-if-cached"));
+        assert_eq!(d.max_age, Some(60));
+        assert_eq!(d.min_fresh, Some(10));
+        assert!(d.only_if_cached && !d.no_cache);
+        assert_eq!(
+            Directives::parse(Some("max-stale")).max_stale,
+            Some(u64::MAX)
+        );
+        assert_eq!(Directives::parse(Some("max-stale=30")).max_stale, Some(30));
+        assert!(Directives::parse(Some("no-cache")).no_cache);
+        // Not a substring match
+        assert!(!Directives::parse(Some("x-no-cache-please")).no_cache);
+    }
+
+    #[test]
+    fn test_acceptance() {
+        let none = Directives::default();
+        assert!(none.accepts_fresh(100, 7200));
+        assert!(!none.accepts_fresh(7200, 7200));
+        assert!(!none.accepts_stale(8000, 7200));
+        assert!(none.accepts_fallback(1_000_000, 7200));
+
+        let max_age = Directives::parse(Some("max-age=60"));
+        assert!(max_age.accepts_fresh(60, 7200));
+        assert!(!max_age.accepts_fresh(61, 7200));
+        assert!(!max_age.accepts_fallback(8000, 7200));
+
+        let min_fresh = Directives::parse(Some("min-fresh=600"));
+        assert!(min_fresh.accepts_fresh(6500, 7200));
+        assert!(!min_fresh.accepts_fresh(6700, 7200));
+
+        let max_stale = Directives::parse(Some("max-stale=600"));
+        assert!(max_stale.accepts_stale(7700, 7200));
+        assert!(!max_stale.accepts_stale(7900, 7200));
+        assert!(!max_stale.accepts_fallback(7900, 7200));
+        assert!(Directives::parse(Some("max-stale")).accepts_stale(1_000_000, 7200));
+    }
+}
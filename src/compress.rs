@@ -0,0 +1,313 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+/// Brotli quality for responses. 11 is the maximum; this is close in size
+/// and several times faster.
+const BROTLI_QUALITY: u32 = 9;
+
+/// Rendered variants kept per cache entry; older ones are dropped first.
+const MAX_VARIANTS: usize = 16;
+
+/// A content coding a client accepts, from `Accept-Encoding`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// The best coding an `Accept-Encoding` header allows: brotli, then
+    /// gzip, then none. Codings with `q=0` are refused.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let Some(header) = accept_encoding else {
+            return Self::Identity;
+        };
+        let accepted = |name: &str| {
+            let mut wildcard = None;
+            for item in header.split(',') {
+                let mut parts = item.split(';');
+                let coding = parts.next().unwrap_or("").trim();
+                let q = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                if coding.eq_ignore_ascii_case(name) {
+                    return q > 0.0;
+                }
+                if coding == "*" {
+                    wildcard = Some(q > 0.0);
+                }
+            }
+            wildcard.unwrap_or(false)
+        };
+        if accepted("br") {
+            Self::Brotli
+        } else if accepted("gzip") {
+            Self::Gzip
+        } else {
+            Self::Identity
+        }
+    }
+
+    /// `Content-Encoding` value, `None` for identity.
+    pub fn header(self) -> Option<&'static str> {
+        match self {
+            Self::Identity => None,
+            Self::Gzip => Some("gzip"),
+            Self::Brotli => Some("br"),
+        }
+    }
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    // Writing to a Vec can't fail
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn gunzip(data: &[u8]) -> io::Result<String> {
+    let mut text = String::new();
+    GzDecoder::new(data).read_to_string(&mut text)?;
+    Ok(text)
+}
+
+fn hash(text: &str) -> String {
+    let digest = Sha256::digest(text.as_bytes());
+    digest[..16].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut out, 4096, BROTLI_QUALITY, 22);
+        writer.write_all(data).unwrap();
+    }
+    out
+}
+
+/// A text body kept gzipped. Other encodings are made the first time a
+/// client asks for them and reused after that; clones share them.
+#[derive(Clone)]
+pub struct Body(Arc<Inner>);
+
+struct Inner {
+    gzip: Vec<u8>,
+    /// Uncompressed size in bytes.
+    len: usize,
+    /// Hash of the uncompressed text.
+    hash: String,
+    brotli: OnceLock<Vec<u8>>,
+}
+
+impl Body {
+    pub fn new(text: &str) -> Self {
+        Self(Arc::new(Inner {
+            gzip: gzip(text.as_bytes()),
+            len: text.len(),
+            hash: hash(text),
+            brotli: OnceLock::new(),
+        }))
+    }
+
+    /// A body stored gzipped, checked by decompressing it.
+    pub fn from_gzip(data: Vec<u8>) -> io::Result<Self> {
+        let text = gunzip(&data)?;
+        Ok(Self(Arc::new(Inner {
+            gzip: data,
+            len: text.len(),
+            hash: hash(&text),
+            brotli: OnceLock::new(),
+        })))
+    }
+
+    /// The body as text.
+    pub fn text(&self) -> String {
+        // Only ever built from text, so it decompresses to valid UTF-8
+        gunzip(&self.0.gzip).expect("stored body decompresses")
+    }
+
+    /// Hex hash of the text, the same for equal bodies, for `ETag`.
+    pub fn hash(&self) -> &str {
+        &self.0.hash
+    }
+
+    pub fn gzip(&self) -> &[u8] {
+        &self.0.gzip
+    }
+
+    /// Uncompressed size in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.len == 0
+    }
+
+    /// Size in memory.
+    pub fn stored_len(&self) -> usize {
+        self.0.gzip.len() + self.0.brotli.get().map_or(0, Vec::len)
+    }
+
+    /// The body in `encoding`.
+    pub fn encoded(&self, encoding: Encoding) -> Vec<u8> {
+        match encoding {
+            Encoding::Identity => self.text().into_bytes(),
+            Encoding::Gzip => self.0.gzip.clone(),
+            Encoding::Brotli => self
+                .0
+                .brotli
+                .get_or_init(|| brotli(self.text().as_bytes()))
+                .clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Body({}B, {}B gzipped)", self.0.len, self.0.gzip.len())
+    }
+}
+
+/// Serialized as its gzipped bytes in base64.
+impl Serialize for Body {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(self.gzip()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Body {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let data = BASE64.decode(encoded).map_err(serde::de::Error::custom)?;
+        Self::from_gzip(data).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Bodies rendered from one cache entry for different views, with their
+/// content types, so repeated requests for the same view reuse the
+/// compressed result. Clones share them.
+#[derive(Clone, Default)]
+pub struct Variants(Arc<Mutex<Vec<(String, Body, String)>>>);
+
+impl Variants {
+    /// The variant for `key`, rendering it with `render` if there is none.
+    pub fn get_or_insert(
+        &self,
+        key: &str,
+        render: impl FnOnce() -> (String, String),
+    ) -> (Body, String) {
+        if let Some((_, body, content_type)) =
+            self.0.lock().unwrap().iter().find(|(k, _, _)| k == key)
+        {
+            return (body.clone(), content_type.clone());
+        }
+        // Rendered outside the lock; a racing request may render it too
+        let (text, content_type) = render();
+        let body = Body::new(&text);
+        let mut variants = self.0.lock().unwrap();
+        if variants.len() >= MAX_VARIANTS {
+            variants.remove(0);
+        }
+        variants.push((key.to_string(), body.clone(), content_type.clone()));
+        (body, content_type)
+    }
+
+    /// Number of rendered variants.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// Bytes held by the rendered bodies.
+    pub fn stored_len(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, body, _)| body.stored_len())
+            .sum()
+    }
+}
+
+impl std::fmt::Debug for Variants {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Variants({})", self.0.lock().unwrap().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate(None), Encoding::Identity);
+        assert_eq!(
+            Encoding::negotiate(Some("gzip, deflate, br")),
+            Encoding::Brotli
+        );
+        assert_eq!(Encoding::negotiate(Some("gzip")), Encoding::Gzip);
+        assert_eq!(
+            Encoding::negotiate(Some("br;q=0, gzip;q=0.5")),
+            Encoding::Gzip
+        );
+        assert_eq!(Encoding::negotiate(Some("*")), Encoding::Brotli);
+        assert_eq!(Encoding::negotiate(Some("*;q=0, gzip")), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate(Some("identity")), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(Some("GZIP")), Encoding::Gzip);
+    }
+
+    #[test]
+    fn test_body_encodings() {
+        let text = r#"{"forecasts":[]}"#.repeat(100);
+        let body = Body::new(&text);
+        assert_eq!(body.len(), text.len());
+        assert!(body.stored_len() < text.len() / 10);
+        assert_eq!(body.text(), text);
+        assert_eq!(gunzip(&body.encoded(Encoding::Gzip)).unwrap(), text);
+
+        let br = body.encoded(Encoding::Brotli);
+        let mut decoded = String::new();
+        brotli::Decompressor::new(br.as_slice(), 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+        // Made once, kept with the body
+        assert_eq!(body.stored_len(), body.gzip().len() + br.len());
+
+        let loaded = Body::from_gzip(body.gzip().to_vec()).unwrap();
+        assert_eq!(loaded.text(), text);
+        assert_eq!(loaded.hash(), body.hash());
+        assert_ne!(Body::new("{}").hash(), body.hash());
+        assert!(Body::from_gzip(b"plain".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_variants_reused() {
+        let variants = Variants::default();
+        let mut renders = 0;
+        for _ in 0..2 {
+            let (body, content_type) = variants.get_or_insert("csv", || {
+                renders += 1;
+                ("a,b".into(), "text/csv".into())
+            });
+            assert_eq!(body.text(), "a,b");
+            assert_eq!(content_type, "text/csv");
+        }
+        assert_eq!(renders, 1);
+        assert_eq!(variants.len(), 1);
+    }
+}
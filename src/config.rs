@@ -0,0 +1,639 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::access::Isolation;
+use crate::cache::CacheLimits;
+use crate::credentials::read_key_file;
+use crate::endpoints::KNOWN_ENDPOINTS;
+use crate::scheduler::ScheduleMode;
+use crate::solar::Location;
+use crate::store::Backend;
+
+/// Full proxy configuration, as read from a TOML file (`--config`) and then
+/// overridden by CLI flags and environment variables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to listen on.
+    pub listen: SocketAddr,
+    /// Base URL of the upstream Solcast API.
+    pub upstream_url: String,
+    /// Upstream request timeout in seconds.
+    pub upstream_timeout: u64,
+    /// Maximum upstream calls per API key per UTC day.
+    pub daily_budget: u32,
+    /// Default API key for scheduled refreshes of sites without their own,
+    /// and for client requests that carry no key.
+    pub api_key: Option<String>,
+    /// File holding `api_key`, readable by its owner only.
+    pub api_key_file: Option<PathBuf>,
+    pub cache: CacheSettings,
+    pub fallback: FallbackSettings,
+    pub schedule: ScheduleSettings,
+    pub history: HistorySettings,
+    /// Per-endpoint TTL and rate limit, keyed by endpoint name (e.g.
+    /// `forecasts` or `data/live/rooftop_pv_power`).
+    pub endpoints: HashMap<String, EndpointSettings>,
+    pub sites: Vec<SiteConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8888)),
+            upstream_url: "https://api.solcast.com.au".to_string(),
+            upstream_timeout: 30,
+            daily_budget: 10,
+            api_key: None,
+            api_key_file: None,
+            cache: CacheSettings::default(),
+            fallback: FallbackSettings::default(),
+            schedule: ScheduleSettings::default(),
+            history: HistorySettings::default(),
+            endpoints: HashMap::new(),
+            sites: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// Directory for `cache.json` and other persisted state.
+    pub dir: PathBuf,
+    /// Default cache TTL in seconds.
+    pub ttl: u64,
+    /// Default minimum seconds between upstream calls per endpoint.
+    pub rate_limit: u64,
+    /// Which clients cached entries are served to.
+    pub isolation: Isolation,
+    /// Previous versions of `cache.json` kept to recover from if it is
+    /// found unreadable.
+    pub snapshots: usize,
+    /// Where cached entries are persisted.
+    pub backend: Backend,
+    /// Seconds past its TTL that an entry is still served straight away
+    /// while it is refreshed in the background; 0 makes clients wait.
+    pub stale_while_revalidate: u64,
+    /// Seconds past its TTL after which an entry is never served stale,
+    /// even when upstream can't be reached. Unset for no limit.
+    pub stale_if_error: Option<u64>,
+    /// Most entries kept; the least recently used are evicted past it.
+    /// 0 for no limit.
+    pub max_entries: usize,
+    /// Most bytes of (compressed) bodies kept; 0 for no limit.
+    pub max_bytes: u64,
+    /// Days after which an entry nobody has fetched or read is deleted;
+    /// 0 keeps entries forever.
+    pub max_idle_days: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./data"),
+            ttl: 7200,
+            rate_limit: 9000,
+            isolation: Isolation::Shared,
+            snapshots: 3,
+            backend: Backend::Json,
+            stale_while_revalidate: 0,
+            stale_if_error: None,
+            max_entries: 0,
+            max_bytes: 0,
+            max_idle_days: 0,
+        }
+    }
+}
+
+/// How long an account waits before retrying after a failed upstream call.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackSettings {
+    /// Backoff in seconds after an upstream 429.
+    pub rate_limited_backoff: u64,
+    /// Backoff in seconds after an upstream error or failed request.
+    pub error_backoff: u64,
+}
+
+impl Default for FallbackSettings {
+    fn default() -> Self {
+        Self {
+            rate_limited_backoff: 3600,
+            error_backoff: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleSettings {
+    pub mode: ScheduleMode,
+    /// Local hours `[start, end)` used by the daylight schedule for sites
+    /// without coordinates.
+    pub daylight_hours: (u32, u32),
+    /// Daily calls the scheduler leaves for on-demand client requests.
+    pub reserve: u32,
+    /// Minutes before sunrise that upstream fetching resumes.
+    pub sunrise_offset: i64,
+}
+
+impl Default for ScheduleSettings {
+    fn default() -> Self {
+        Self {
+            mode: ScheduleMode::Daylight,
+            daylight_hours: (6, 20),
+            reserve: 2,
+            sunrise_offset: 30,
+        }
+    }
+}
+
+/// How long forecast snapshots are kept for `?as_of=` queries.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistorySettings {
+    /// Days of snapshots kept per site; 0 disables the history.
+    pub retention_days: u32,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self { retention_days: 7 }
+    }
+}
+
+/// TTL and rate limit overrides for one endpoint.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EndpointSettings {
+    pub ttl: Option<u64>,
+    pub rate_limit: Option<u64>,
+}
+
+/// A rooftop site known to the proxy.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteConfig {
+    /// Solcast rooftop resource id.
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// API key used for this site's scheduled refreshes and for client
+    /// requests for it that carry no key.
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// File holding `api_key`, readable by its owner only.
+    #[serde(default)]
+    pub api_key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    /// Installed capacity in kW (informational).
+    #[serde(default)]
+    pub capacity: Option<f64>,
+    /// Whether the scheduler refreshes this site in the background.
+    #[serde(default = "default_true")]
+    pub refresh: bool,
+    /// Days of forecast snapshots kept for this site, overriding
+    /// `history.retention_days`.
+    #[serde(default)]
+    pub history_retention_days: Option<u32>,
+    /// Per-endpoint overrides for this site only.
+    #[serde(default)]
+    pub endpoints: HashMap<String, EndpointSettings>,
+    /// Accounts tried in order when the primary key is rate limited, out of
+    /// quota or failing.
+    #[serde(default)]
+    pub fallbacks: Vec<FallbackConfig>,
+}
+
+/// A fallback account for a site: another Solcast account with its own
+/// rooftop site and key.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackConfig {
+    /// Label for logs, metrics and headers; defaults to `site_id`.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The account's own rooftop id.
+    pub site_id: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// File holding `api_key`, readable by its owner only.
+    #[serde(default)]
+    pub api_key_file: Option<PathBuf>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl SiteConfig {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            name: None,
+            api_key: None,
+            api_key_file: None,
+            latitude: None,
+            longitude: None,
+            capacity: None,
+            refresh: true,
+            history_retention_days: None,
+            endpoints: HashMap::new(),
+            fallbacks: Vec::new(),
+        }
+    }
+
+    pub fn location(&self) -> Option<Location> {
+        Some(Location {
+            latitude: self.latitude?,
+            longitude: self.longitude?,
+        })
+    }
+}
+
+impl Config {
+    /// Read and parse a TOML config file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&data).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// Fill in `api_key` from `api_key_file`, globally and for each site.
+    pub fn read_key_files(&mut self) -> Result<(), String> {
+        fn read(
+            label: &str,
+            key: &mut Option<String>,
+            file: &Option<PathBuf>,
+        ) -> Result<(), String> {
+            let Some(path) = file else {
+                return Ok(());
+            };
+            if key.is_some() {
+                return Err(format!("{label}: set api_key or api_key_file, not both"));
+            }
+            *key = Some(read_key_file(path)?);
+            Ok(())
+        }
+        read("config", &mut self.api_key, &self.api_key_file)?;
+        for site in &mut self.sites {
+            let label = format!("site '{}'", site.id);
+            read(&label, &mut site.api_key, &site.api_key_file)?;
+            for fb in &mut site.fallbacks {
+                let label = format!("{label}: fallback '{}'", fb.site_id);
+                read(&label, &mut fb.api_key, &fb.api_key_file)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The site with this id, added with defaults if not yet configured.
+    pub fn site_mut(&mut self, id: &str) -> &mut SiteConfig {
+        let idx = match self.sites.iter().position(|s| s.id == id) {
+            Some(idx) => idx,
+            None => {
+                self.sites.push(SiteConfig::new(id));
+                self.sites.len() - 1
+            }
+        };
+        &mut self.sites[idx]
+    }
+
+    /// Check the configuration for mistakes, returning every problem found.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if !self.upstream_url.starts_with("http://") && !self.upstream_url.starts_with("https://") {
+            errors.push(format!(
+                "upstream_url must start with http:// or https://, got '{}'",
+                self.upstream_url
+            ));
+        }
+        if self.upstream_timeout == 0 {
+            errors.push("upstream_timeout must be greater than 0".to_string());
+        }
+        let (start, end) = self.schedule.daylight_hours;
+        if start >= end || end > 24 {
+            errors.push(format!(
+                "schedule.daylight_hours must satisfy start < end <= 24, got [{start}, {end}]"
+            ));
+        }
+        if self.schedule.sunrise_offset < 0 {
+            errors.push("schedule.sunrise_offset must not be negative".to_string());
+        }
+        for name in self.endpoints.keys() {
+            if !KNOWN_ENDPOINTS.contains(&name.as_str()) {
+                errors.push(format!("endpoints.{name}: unknown endpoint"));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for site in &self.sites {
+            let label = format!("site '{}'", site.id);
+            if site.id.trim().is_empty() {
+                errors.push("site with empty id".to_string());
+            } else if !seen.insert(site.id.as_str()) {
+                errors.push(format!("{label}: defined more than once"));
+            }
+            match (site.latitude, site.longitude) {
+                (Some(lat), Some(lon)) => {
+                    if !(-90.0..=90.0).contains(&lat) {
+                        errors.push(format!("{label}: latitude {lat} out of range"));
+                    }
+                    if !(-180.0..=180.0).contains(&lon) {
+                        errors.push(format!("{label}: longitude {lon} out of range"));
+                    }
+                }
+                (None, None) => {}
+                _ => errors.push(format!(
+                    "{label}: latitude and longitude must be given together"
+                )),
+            }
+            if site.capacity.is_some_and(|c| c <= 0.0) {
+                errors.push(format!("{label}: capacity must be positive"));
+            }
+            if site.api_key.as_deref().is_some_and(|k| k.trim().is_empty()) {
+                errors.push(format!("{label}: api_key is empty"));
+            }
+            for name in site.endpoints.keys() {
+                if !KNOWN_ENDPOINTS.contains(&name.as_str()) {
+                    errors.push(format!("{label}: endpoints.{name}: unknown endpoint"));
+                }
+            }
+            for fb in &site.fallbacks {
+                if fb.site_id.trim().is_empty() {
+                    errors.push(format!("{label}: fallback with empty site_id"));
+                } else if fb.api_key.is_none() && fb.api_key_file.is_none() {
+                    errors.push(format!(
+                        "{label}: fallback '{}' needs api_key or api_key_file",
+                        fb.site_id
+                    ));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Size and idle limits for the cache.
+    pub fn cache_limits(&self) -> CacheLimits {
+        CacheLimits {
+            max_entries: self.cache.max_entries,
+            max_bytes: self.cache.max_bytes,
+            max_idle: (self.cache.max_idle_days > 0)
+                .then(|| chrono::Duration::days(self.cache.max_idle_days as i64)),
+        }
+    }
+
+    /// History retention of every site that overrides the default.
+    pub fn history_retention(&self) -> HashMap<String, u32> {
+        self.sites
+            .iter()
+            .filter_map(|s| Some((s.id.clone(), s.history_retention_days?)))
+            .collect()
+    }
+
+    /// Coordinates of every site that has them.
+    pub fn locations(&self) -> HashMap<String, Location> {
+        self.sites
+            .iter()
+            .filter_map(|s| Some((s.id.clone(), s.location()?)))
+            .collect()
+    }
+}
+
+/// Per-site and per-endpoint TTL and rate limit overrides, resolved in the
+/// order site endpoint setting, global endpoint setting, global default.
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    endpoints: HashMap<String, EndpointSettings>,
+    sites: HashMap<String, HashMap<String, EndpointSettings>>,
+}
+
+impl Policies {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            endpoints: config.endpoints.clone(),
+            sites: config
+                .sites
+                .iter()
+                .filter(|s| !s.endpoints.is_empty())
+                .map(|s| (s.id.clone(), s.endpoints.clone()))
+                .collect(),
+        }
+    }
+
+    fn lookup<T>(
+        &self,
+        rooftop_id: &str,
+        endpoint: &str,
+        field: impl Fn(&EndpointSettings) -> Option<T>,
+    ) -> Option<T> {
+        self.sites
+            .get(rooftop_id)
+            .and_then(|eps| eps.get(endpoint))
+            .and_then(&field)
+            .or_else(|| self.endpoints.get(endpoint).and_then(&field))
+    }
+
+    pub fn ttl(&self, rooftop_id: &str, endpoint: &str) -> Option<u64> {
+        self.lookup(rooftop_id, endpoint, |s| s.ttl)
+    }
+
+    pub fn rate_limit(&self, rooftop_id: &str, endpoint: &str) -> Option<u64> {
+        self.lookup(rooftop_id, endpoint, |s| s.rate_limit)
+    }
+
+    /// The longest rate limit set for any endpoint or site.
+    pub fn max_rate_limit(&self) -> Option<u64> {
+        self.endpoints
+            .values()
+            .chain(self.sites.values().flat_map(|eps| eps.values()))
+            .filter_map(|s| s.rate_limit)
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+listen = "127.0.0.1:9000"
+daily_budget = 20
+
+[cache]
+dir = "/var/lib/solcast-proxy"
+ttl = 3600
+isolation = "validated"
+backend = "sqlite"
+stale_while_revalidate = 600
+stale_if_error = 86400
+max_entries = 500
+max_bytes = 50000000
+max_idle_days = 30
+
+[fallback]
+rate_limited_backoff = 1800
+
+[schedule]
+mode = "even"
+daylight_hours = [5, 21]
+
+[history]
+retention_days = 30
+
+[endpoints.estimated_actuals]
+ttl = 21600
+rate_limit = 43200
+
+[endpoints."data/live/rooftop_pv_power"]
+ttl = 900
+
+[[sites]]
+id = "abcd-1234"
+name = "House"
+latitude = -33.87
+longitude = 151.21
+capacity = 6.6
+history_retention_days = 90
+
+[sites.endpoints.forecasts]
+ttl = 1800
+
+[[sites.fallbacks]]
+name = "backup"
+site_id = "wxyz-0000"
+api_key = "backup-key"
+
+[[sites.fallbacks]]
+site_id = "wxyz-1111"
+api_key = "other-key"
+
+[[sites]]
+id = "efgh-5678"
+refresh = false
+"#;
+
+    #[test]
+    fn test_parse_example() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.daily_budget, 20);
+        assert_eq!(config.cache.dir, PathBuf::from("/var/lib/solcast-proxy"));
+        assert_eq!(config.cache.ttl, 3600);
+        // Unset fields keep their defaults
+        assert_eq!(config.cache.rate_limit, 9000);
+        assert_eq!(config.cache.isolation, Isolation::Validated);
+        assert_eq!(config.cache.backend, Backend::Sqlite);
+        assert_eq!(config.cache.stale_while_revalidate, 600);
+        assert_eq!(config.cache.stale_if_error, Some(86400));
+        let limits = config.cache_limits();
+        assert_eq!(limits.max_entries, 500);
+        assert_eq!(limits.max_bytes, 50_000_000);
+        assert_eq!(limits.max_idle, Some(chrono::Duration::days(30)));
+        assert_eq!(Config::default().cache_limits().max_idle, None);
+        assert_eq!(config.fallback.rate_limited_backoff, 1800);
+        assert_eq!(config.fallback.error_backoff, 60);
+        assert_eq!(config.schedule.mode, ScheduleMode::Even);
+        assert_eq!(config.schedule.daylight_hours, (5, 21));
+        assert_eq!(config.sites.len(), 2);
+        assert_eq!(config.sites[0].name.as_deref(), Some("House"));
+        assert_eq!(config.sites[0].fallbacks.len(), 2);
+        assert_eq!(config.sites[0].fallbacks[1].site_id, "wxyz-1111");
+        assert!(config.sites[0].refresh);
+        assert!(!config.sites[1].refresh);
+        assert_eq!(config.locations().len(), 1);
+        assert_eq!(config.history.retention_days, 30);
+        assert_eq!(config.history_retention().get("abcd-1234"), Some(&90));
+        assert_eq!(config.history_retention().len(), 1);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_policies() {
+        let config: Config = toml::from_str(EXAMPLE).unwrap();
+        let policies = Policies::from_config(&config);
+        assert_eq!(policies.ttl("abcd-1234", "forecasts"), Some(1800));
+        assert_eq!(policies.ttl("efgh-5678", "forecasts"), None);
+        assert_eq!(policies.ttl("abcd-1234", "estimated_actuals"), Some(21600));
+        assert_eq!(policies.ttl("-", "data/live/rooftop_pv_power"), Some(900));
+        assert_eq!(policies.rate_limit("abcd-1234", "forecasts"), None);
+        assert_eq!(
+            policies.rate_limit("efgh-5678", "estimated_actuals"),
+            Some(43200)
+        );
+        assert_eq!(policies.max_rate_limit(), Some(43200));
+        assert_eq!(Policies::default().max_rate_limit(), None);
+    }
+
+    #[test]
+    fn test_unknown_fields_rejected() {
+        assert!(toml::from_str::<Config>("ttl = 5").is_err());
+        assert!(toml::from_str::<Config>("[cache]\nttl_secs = 5").is_err());
+    }
+
+    #[test]
+    fn test_validate_reports_all_problems() {
+        let mut config = Config {
+            upstream_url: "ftp://example".into(),
+            ..Config::default()
+        };
+        config
+            .endpoints
+            .insert("bogus".into(), EndpointSettings::default());
+        config.sites.push(SiteConfig {
+            latitude: Some(100.0),
+            longitude: Some(0.0),
+            ..SiteConfig::new("a")
+        });
+        config.sites.push(SiteConfig {
+            latitude: Some(1.0),
+            ..SiteConfig::new("a")
+        });
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 5, "{errors:?}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_key_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, "from-file\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut config = Config::default();
+        config.site_mut("a").api_key_file = Some(path.clone());
+        config.read_key_files().unwrap();
+        assert_eq!(config.sites[0].api_key.as_deref(), Some("from-file"));
+
+        let mut config = Config {
+            api_key: Some("inline".into()),
+            api_key_file: Some(path),
+            ..Config::default()
+        };
+        assert!(config.read_key_files().unwrap_err().contains("not both"));
+    }
+
+    #[test]
+    fn test_site_mut_adds_once() {
+        let mut config = Config::default();
+        config.site_mut("a").latitude = Some(1.0);
+        config.site_mut("a").longitude = Some(2.0);
+        assert_eq!(config.sites.len(), 1);
+        assert!(config.sites[0].location().is_some());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::config::Config;
+
+/// Solcast API keys held by the proxy, for background refreshes and for
+/// clients that send none to a configured site.
+///
+/// Keys come from `api_key` / `api_key_file` in the config, per site and as
+/// a default, and are swapped in place when the config is reloaded.
+pub struct Credentials {
+    keys: RwLock<KeySet>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct KeySet {
+    default: Option<String>,
+    sites: HashMap<String, String>,
+    /// Every `[[sites]]` id, with or without its own key.
+    configured: HashSet<String>,
+}
+
+impl KeySet {
+    fn from_config(config: &Config) -> Self {
+        Self {
+            default: config.api_key.clone(),
+            sites: config
+                .sites
+                .iter()
+                .filter_map(|s| Some((s.id.clone(), s.api_key.clone()?)))
+                .collect(),
+            configured: config.sites.iter().map(|s| s.id.clone()).collect(),
+        }
+    }
+}
+
+impl Credentials {
+    /// Keys from a config whose key files have already been read.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            keys: RwLock::new(KeySet::from_config(config)),
+        }
+    }
+
+    /// Replace every key with those of a reloaded config.
+    pub fn reload(&self, config: &Config) {
+        let keys = KeySet::from_config(config);
+        let mut current = self.keys.write().unwrap();
+        if *current != keys {
+            tracing::info!(
+                "Credentials reloaded ({} site keys, default {})",
+                keys.sites.len(),
+                if keys.default.is_some() {
+                    "set"
+                } else {
+                    "unset"
+                }
+            );
+        }
+        *current = keys;
+    }
+
+    /// The key for refreshing a rooftop site: its own, or the default key.
+    pub fn key_for(&self, rooftop_id: &str) -> Option<String> {
+        let keys = self.keys.read().unwrap();
+        keys.sites
+            .get(rooftop_id)
+            .or(keys.default.as_ref())
+            .cloned()
+    }
+
+    /// The key for a client that sent none. Only configured sites get one,
+    /// so keyless clients can't spend the account's quota on other ids.
+    pub fn client_key_for(&self, rooftop_id: &str) -> Option<String> {
+        if !self.keys.read().unwrap().configured.contains(rooftop_id) {
+            return None;
+        }
+        self.key_for(rooftop_id)
+    }
+}
+
+/// Read an API key from a file that only its owner can read.
+pub fn read_key_file(path: &Path) -> Result<String, String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let meta = std::fs::metadata(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mode = meta.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(format!(
+                "{} is accessible by other users (mode {:o}), chmod 600 it",
+                path.display(),
+                mode & 0o777
+            ));
+        }
+    }
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let key = data.trim();
+    if key.is_empty() {
+        return Err(format!("{} is empty", path.display()));
+    }
+    Ok(key.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SiteConfig;
+
+    #[test]
+    fn test_key_for_and_reload() {
+        let mut config = Config {
+            api_key: Some("default".into()),
+            ..Config::default()
+        };
+        config.sites.push(SiteConfig {
+            api_key: Some("own".into()),
+            ..SiteConfig::new("a")
+        });
+        let credentials = Credentials::from_config(&config);
+        assert_eq!(credentials.key_for("a").as_deref(), Some("own"));
+        assert_eq!(credentials.key_for("b").as_deref(), Some("default"));
+        assert_eq!(credentials.client_key_for("a").as_deref(), Some("own"));
+        // Unconfigured sites never get a key on a client's behalf
+        assert_eq!(credentials.client_key_for("b"), None);
+
+        config.api_key = None;
+        config.sites[0].api_key = Some("rotated".into());
+        credentials.reload(&config);
+        assert_eq!(credentials.key_for("a").as_deref(), Some("rotated"));
+        assert_eq!(credentials.key_for("b"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_key_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, "secret\n").unwrap();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(read_key_file(&path).unwrap_err().contains("chmod 600"));
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(read_key_file(&path).unwrap(), "secret");
+
+        std::fs::write(&path, "  \n").unwrap();
+        assert!(read_key_file(&path).is_err());
+        assert!(read_key_file(&dir.path().join("missing")).is_err());
+    }
+}
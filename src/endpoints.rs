@@ -0,0 +1,348 @@
+use crate::forecast::Window;
+use crate::quota::key_hash;
+
+/// Every endpoint the proxy serves, by the name used for policies and metrics.
+pub const KNOWN_ENDPOINTS: [&str; 12] = [
+    "forecasts",
+    "estimated_actuals",
+    "rooftop_sites",
+    "site",
+    "data/forecast/rooftop_pv_power",
+    "data/forecast/radiation_and_weather",
+    "data/forecast/advanced_pv_power",
+    "data/live/rooftop_pv_power",
+    "data/live/radiation_and_weather",
+    "data/live/advanced_pv_power",
+    "world_radiation/forecasts",
+    "world_radiation/estimated_actuals",
+];
+
+/// Decimal places coordinates are rounded to before caching, about 1km.
+/// Solcast's grid is coarser than this, so nearby requests share an entry.
+const COORDINATE_DECIMALS: usize = 2;
+
+/// Built-in TTL for endpoints whose data changes on a very different cadence
+/// from forecasts. Others use the global TTL.
+pub fn default_ttl(endpoint: &str) -> Option<u64> {
+    match endpoint {
+        "rooftop_sites" | "site" => Some(86400),
+        e if e.starts_with("data/live/") => Some(1800),
+        _ => None,
+    }
+}
+
+/// Whether an endpoint returns forecast periods that can be sliced from a
+/// wider cached forecast.
+fn sliceable(endpoint: &str) -> bool {
+    endpoint == "forecasts"
+        || endpoint == "world_radiation/forecasts"
+        || endpoint.starts_with("data/forecast/")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    /// `/rooftop_sites/{id}/{endpoint}`: a fallback account uses its own site id.
+    Rooftop,
+    /// Coordinate or resource based: a fallback account requests the same path.
+    Shared,
+    /// Account-specific metadata, never fetched with another account's key.
+    Account,
+}
+
+/// One proxied Solcast resource: where it lives upstream and how it is cached.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// Site part of the cache key: a rooftop id, rounded `lat,lon`, or an
+    /// account hash for the site listing.
+    pub site: String,
+    /// Endpoint name, one of `KNOWN_ENDPOINTS`.
+    pub endpoint: String,
+    /// Upstream path, relative to the API base URL.
+    pub path: String,
+    /// Query parameters that identify the resource, sorted by name.
+    pub params: Vec<(String, String)>,
+    /// For forecasts, the `hours` and `period` the client asked for. These
+    /// are kept out of the cache key and applied to the cached forecast.
+    pub window: Option<Window>,
+    /// Hash of the API key whose private copy of the entry this request
+    /// reads and refreshes, when the cache isn't shared between keys.
+    pub partition: Option<String>,
+    kind: Kind,
+}
+
+impl Target {
+    /// `/rooftop_sites/{rooftop_id}/{endpoint}`.
+    pub fn rooftop(
+        rooftop_id: &str,
+        endpoint: &str,
+        params: Vec<(String, String)>,
+    ) -> Option<Self> {
+        if endpoint != "forecasts" && endpoint != "estimated_actuals" {
+            return None;
+        }
+        Some(Self::new(
+            rooftop_id.to_string(),
+            endpoint.to_string(),
+            format!("/rooftop_sites/{rooftop_id}/{endpoint}"),
+            params,
+            Kind::Rooftop,
+        ))
+    }
+
+    /// `/rooftop_sites`: the sites visible to the calling API key.
+    pub fn site_list(api_key: &str, params: Vec<(String, String)>) -> Self {
+        Self::new(
+            format!("account-{}", key_hash(api_key)),
+            "rooftop_sites".to_string(),
+            "/rooftop_sites".to_string(),
+            params,
+            Kind::Account,
+        )
+    }
+
+    /// `/rooftop_sites/{rooftop_id}`: one site's metadata.
+    pub fn site(rooftop_id: &str, params: Vec<(String, String)>) -> Self {
+        Self::new(
+            rooftop_id.to_string(),
+            "site".to_string(),
+            format!("/rooftop_sites/{rooftop_id}"),
+            params,
+            Kind::Account,
+        )
+    }
+
+    /// `/data/{forecast,live}/{resource}`.
+    pub fn data(group: &str, resource: &str, params: Vec<(String, String)>) -> Option<Self> {
+        Self::located(format!("data/{group}/{resource}"), params)
+    }
+
+    /// `/world_radiation/{forecasts,estimated_actuals}`.
+    pub fn world_radiation(resource: &str, params: Vec<(String, String)>) -> Option<Self> {
+        Self::located(format!("world_radiation/{resource}"), params)
+    }
+
+    /// An endpoint addressed by `latitude`/`longitude` or `resource_id`
+    /// query parameters rather than by path.
+    fn located(endpoint: String, mut params: Vec<(String, String)>) -> Option<Self> {
+        if !KNOWN_ENDPOINTS.contains(&endpoint.as_str()) {
+            return None;
+        }
+        let site = match round_coordinates(&mut params) {
+            Some(coords) => coords,
+            None => params
+                .iter()
+                .find(|(k, _)| k == "resource_id")
+                .map(|(_, v)| v.clone())
+                .unwrap_or_else(|| "-".to_string()),
+        };
+        Some(Self::new(
+            site,
+            endpoint.clone(),
+            format!("/{endpoint}"),
+            params,
+            Kind::Shared,
+        ))
+    }
+
+    fn new(
+        site: String,
+        endpoint: String,
+        path: String,
+        mut params: Vec<(String, String)>,
+        kind: Kind,
+    ) -> Self {
+        let window = if sliceable(&endpoint) {
+            Window::take(&mut params)
+        } else {
+            None
+        };
+        // Parameter order doesn't matter upstream, so it mustn't split the cache
+        params.sort();
+        Self {
+            site,
+            endpoint,
+            path,
+            params,
+            window,
+            partition: None,
+            kind,
+        }
+    }
+
+    /// Endpoint part of the cache key, including the query parameters and
+    /// any partition.
+    pub fn cache_endpoint(&self) -> String {
+        match &self.partition {
+            Some(partition) => format!("{}#{partition}", self.shared_cache_endpoint()),
+            None => self.shared_cache_endpoint(),
+        }
+    }
+
+    /// Endpoint part of the cache key shared by every API key.
+    pub fn shared_cache_endpoint(&self) -> String {
+        if self.params.is_empty() {
+            self.endpoint.clone()
+        } else {
+            let qs: Vec<String> = self
+                .params
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect();
+            format!("{}?{}", self.endpoint, qs.join("&"))
+        }
+    }
+
+    /// Query parameters to send upstream: the resource's own, plus the
+    /// widest horizon and finest period any client of this entry needs.
+    pub fn upstream_params(&self) -> Vec<(String, String)> {
+        let mut params = self.params.clone();
+        if let Some(window) = &self.window {
+            params.extend(window.fetch_params());
+            params.sort();
+        }
+        params
+    }
+
+    /// Key for coalescing concurrent upstream fetches of this target.
+    pub fn flight_key(&self) -> String {
+        let qs: Vec<String> = self
+            .upstream_params()
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect();
+        let key = format!("{}:{}?{}", self.site, self.endpoint, qs.join("&"));
+        match &self.partition {
+            Some(partition) => format!("{key}#{partition}"),
+            None => key,
+        }
+    }
+
+    /// Whether the data follows the sun, so night-time suppression applies.
+    pub fn follows_daylight(&self) -> bool {
+        self.kind != Kind::Account
+    }
+
+    /// Upstream path to request with a fallback account whose own site is
+    /// `fallback_site`, or `None` if the resource is tied to the caller's account.
+    pub fn fallback_path(&self, fallback_site: &str) -> Option<String> {
+        match self.kind {
+            Kind::Rooftop => Some(format!("/rooftop_sites/{fallback_site}/{}", self.endpoint)),
+            Kind::Shared => Some(self.path.clone()),
+            Kind::Account => None,
+        }
+    }
+}
+
+/// Round `latitude` and `longitude` in place and return them as `lat,lon`,
+/// if both are present and numeric.
+fn round_coordinates(params: &mut [(String, String)]) -> Option<String> {
+    let find = |params: &[(String, String)], name: &str| {
+        params
+            .iter()
+            .position(|(k, _)| k == name)
+            .and_then(|i| Some((i, params[i].1.trim().parse::<f64>().ok()?)))
+    };
+    let (lat_idx, lat) = find(params, "latitude")?;
+    let (lon_idx, lon) = find(params, "longitude")?;
+    let lat = format!("{lat:.COORDINATE_DECIMALS$}");
+    let lon = format!("{lon:.COORDINATE_DECIMALS$}");
+    params[lat_idx].1 = lat.clone();
+    params[lon_idx].1 = lon.clone();
+    Some(format!("{lat},{lon}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_rooftop() {
+        let t = Target::rooftop("site1", "forecasts", params(&[("hours", "24")])).unwrap();
+        assert_eq!(t.path, "/rooftop_sites/site1/forecasts");
+        // hours is served from the cached forecast, not part of the key
+        assert_eq!(t.cache_endpoint(), "forecasts");
+        assert_eq!(t.upstream_params(), params(&[("hours", "168")]));
+        assert_eq!(
+            t.fallback_path("site2").as_deref(),
+            Some("/rooftop_sites/site2/forecasts")
+        );
+        let mut t =
+            Target::rooftop("site1", "estimated_actuals", params(&[("hours", "24")])).unwrap();
+        assert_eq!(t.cache_endpoint(), "estimated_actuals?hours=24");
+        assert_eq!(t.window, None);
+        t.partition = Some("abcd".into());
+        assert_eq!(t.cache_endpoint(), "estimated_actuals?hours=24#abcd");
+        assert_eq!(t.shared_cache_endpoint(), "estimated_actuals?hours=24");
+        assert_eq!(t.flight_key(), "site1:estimated_actuals?hours=24#abcd");
+        assert!(Target::rooftop("site1", "bogus", Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_coordinates_rounded_and_params_sorted() {
+        let a = Target::data(
+            "forecast",
+            "radiation_and_weather",
+            params(&[
+                ("longitude", "151.2093"),
+                ("latitude", "-33.8688"),
+                ("hours", "24"),
+            ]),
+        )
+        .unwrap();
+        let b = Target::data(
+            "forecast",
+            "radiation_and_weather",
+            params(&[
+                ("hours", "24"),
+                ("latitude", "-33.871"),
+                ("longitude", "151.2149"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(a.site, "-33.87,151.21");
+        assert_eq!(a.site, b.site);
+        assert_eq!(a.cache_endpoint(), b.cache_endpoint());
+        assert_eq!(
+            a.cache_endpoint(),
+            "data/forecast/radiation_and_weather?latitude=-33.87&longitude=151.21"
+        );
+        assert_eq!(a.path, "/data/forecast/radiation_and_weather");
+        assert_eq!(a.fallback_path("x").as_deref(), Some(a.path.as_str()));
+    }
+
+    #[test]
+    fn test_resource_id_site() {
+        let t = Target::data(
+            "live",
+            "advanced_pv_power",
+            params(&[("resource_id", "abcd")]),
+        )
+        .unwrap();
+        assert_eq!(t.site, "abcd");
+        assert!(Target::data("live", "bogus", Vec::new()).is_none());
+        assert!(Target::data("history", "rooftop_pv_power", Vec::new()).is_none());
+        assert!(Target::world_radiation("forecasts", Vec::new()).is_some());
+        assert!(Target::world_radiation("live", Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_account_targets() {
+        let a = Target::site_list("key1", Vec::new());
+        let b = Target::site_list("key2", Vec::new());
+        assert_ne!(a.site, b.site);
+        assert_eq!(a.path, "/rooftop_sites");
+        assert_eq!(a.fallback_path("x"), None);
+        let t = Target::site("site1", Vec::new());
+        assert_eq!(t.path, "/rooftop_sites/site1");
+        assert_eq!(t.endpoint, "site");
+        assert_eq!(default_ttl("site"), Some(86400));
+        assert_eq!(default_ttl("forecasts"), None);
+    }
+}
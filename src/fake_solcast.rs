@@ -0,0 +1,256 @@
+//! Scriptable in-process stand-in for the Solcast API, used by tests.
+//!
+//! Responses are queued per request path; anything unscripted gets a
+//! `200` with a small forecast body. Every request is recorded so tests can
+//! assert how many upstream calls the proxy actually made.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::access::{Isolation, KeyRegistry};
+use crate::archive::ActualsArchive;
+use crate::cache::ProxyCache;
+use crate::config::{Config, FallbackSettings, Policies};
+use crate::credentials::Credentials;
+use crate::fallback::FallbackPool;
+use crate::history::ForecastHistory;
+use crate::metrics::Metrics;
+use crate::quota::QuotaLedger;
+use crate::ratelimit::UpstreamLimits;
+use crate::singleflight::SingleFlight;
+use crate::solar::NightPolicy;
+use crate::AppState;
+
+/// A scripted upstream response.
+#[derive(Debug, Clone)]
+pub struct FakeResponse {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: String,
+    delay: Option<Duration>,
+}
+
+impl FakeResponse {
+    /// `200 OK` with a JSON body.
+    pub fn ok(body: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::OK,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body: body.into(),
+            delay: None,
+        }
+    }
+
+    /// `429 Too Many Requests` with Solcast-style rate-limit headers.
+    pub fn rate_limited() -> Self {
+        Self::status(StatusCode::TOO_MANY_REQUESTS, "Too Many Requests")
+            .with_header("x-rate-limit", "10")
+            .with_header("x-rate-limit-remaining", "0")
+            .with_header("x-rate-limit-reset", "1767225600")
+            .with_header("retry-after", "3600")
+    }
+
+    /// Arbitrary status with a plain-text body.
+    pub fn status(status: StatusCode, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+            delay: None,
+        }
+    }
+
+    /// A response that only arrives after `delay` (use with a short client timeout).
+    pub fn timeout(delay: Duration) -> Self {
+        Self::ok(forecast_body(1)).with_delay(delay)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+}
+
+/// A request received by the fake server.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub path: String,
+    pub query: Option<String>,
+    pub authorization: Option<String>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    scripts: Mutex<HashMap<String, VecDeque<FakeResponse>>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+/// Running fake Solcast server bound to an ephemeral local port.
+pub struct FakeSolcast {
+    addr: SocketAddr,
+    state: Arc<FakeState>,
+    handle: JoinHandle<()>,
+}
+
+impl FakeSolcast {
+    pub async fn start() -> Self {
+        let state = Arc::new(FakeState::default());
+        let app = Router::new()
+            .fallback(fake_handler)
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        Self {
+            addr,
+            state,
+            handle,
+        }
+    }
+
+    /// Base URL to use as the proxy's upstream.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Queue a response for the next request to `path`.
+    pub fn push(&self, path: &str, response: FakeResponse) {
+        self.state
+            .scripts
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .push_back(response);
+    }
+
+    /// All requests received so far.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Number of requests received so far.
+    pub fn request_count(&self) -> usize {
+        self.state.requests.lock().unwrap().len()
+    }
+}
+
+impl Drop for FakeSolcast {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn fake_handler(
+    State(state): State<Arc<FakeState>>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let path = uri.path().to_string();
+    state.requests.lock().unwrap().push(RecordedRequest {
+        path: path.clone(),
+        query: uri.query().map(str::to_string),
+        authorization: headers
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+    });
+
+    let scripted = state
+        .scripts
+        .lock()
+        .unwrap()
+        .get_mut(&path)
+        .and_then(VecDeque::pop_front);
+    let response = scripted.unwrap_or_else(|| FakeResponse::ok(forecast_body(4)));
+
+    if let Some(delay) = response.delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    let mut out = (response.status, response.body).into_response();
+    for (name, value) in &response.headers {
+        out.headers_mut().insert(
+            axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+    }
+    out
+}
+
+/// A Solcast-shaped forecasts body with `periods` half-hour periods.
+pub fn forecast_body(periods: usize) -> String {
+    let start = chrono::Utc::now();
+    let forecasts: Vec<serde_json::Value> = (1..=periods)
+        .map(|i| {
+            let end = start + chrono::Duration::minutes(30 * i as i64);
+            serde_json::json!({
+                "pv_estimate": 1.5,
+                "pv_estimate10": 1.0,
+                "pv_estimate90": 2.0,
+                "period_end": end.to_rfc3339(),
+                "period": "PT30M",
+            })
+        })
+        .collect();
+    serde_json::json!({ "forecasts": forecasts }).to_string()
+}
+
+/// Proxy state pointing at `upstream_url` with caching effectively disabled
+/// (`ttl = 0`, `rate_limit = 0`); tests adjust the fields they care about.
+pub fn test_state(upstream_url: &str, cache_dir: &Path) -> AppState {
+    AppState {
+        cache: ProxyCache::new(cache_dir, 0),
+        upstream_url: upstream_url.to_string(),
+        client: reqwest::Client::builder()
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap(),
+        start_time: Instant::now(),
+        ttl: 0,
+        rate_limit: 0,
+        stale_while_revalidate: 0,
+        stale_if_error: None,
+        policies: Policies::default(),
+        backoff: FallbackSettings::default(),
+        quota: QuotaLedger::new(cache_dir, 1000),
+        metrics: Metrics::new(),
+        night: NightPolicy::default(),
+        inflight: SingleFlight::new(),
+        archive: ActualsArchive::new(cache_dir),
+        history: ForecastHistory::new(cache_dir, 7, HashMap::new()),
+        isolation: Isolation::Shared,
+        keys: KeyRegistry::new(cache_dir),
+        credentials: Credentials::from_config(&Config::default()),
+        fallbacks: FallbackPool::from_config(&Config::default()),
+        limits: UpstreamLimits::default(),
+    }
+}
+
+/// Serve the proxy router on an ephemeral port and return its base URL.
+pub async fn serve_proxy(state: Arc<AppState>) -> String {
+    let app = crate::router(state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    format!("http://{addr}")
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::config::{Config, FallbackSettings};
+use crate::quota::key_hash;
+
+/// Longest an account is skipped after repeated errors, in multiples of
+/// `error_backoff`.
+const MAX_ERROR_BACKOFF_FACTOR: u32 = 64;
+
+/// An account whose own site is fetched when the primary key can't be used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallbackAccount {
+    /// Label used in logs, metrics and the `X-Fallback-*` headers.
+    pub name: String,
+    pub api_key: String,
+    /// The account's own rooftop id, requested in place of the client's.
+    pub site_id: String,
+}
+
+/// How an attempt on a fallback account ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttemptResult {
+    Success,
+    /// A 429, with when upstream said the account may retry.
+    RateLimited {
+        until: Option<DateTime<Utc>>,
+    },
+    Error,
+}
+
+/// Recent results of one fallback account, shared by every site using it.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Health {
+    pub consecutive_failures: u32,
+    /// The account is skipped until then.
+    pub unhealthy_until: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+}
+
+impl Health {
+    pub fn is_healthy(&self, now: DateTime<Utc>) -> bool {
+        self.unhealthy_until.is_none_or(|until| now >= until)
+    }
+}
+
+/// Health of one account, for `/health`.
+#[derive(Debug, Serialize)]
+pub struct AccountHealth {
+    pub account: String,
+    pub healthy: bool,
+    #[serde(flatten)]
+    pub health: Health,
+}
+
+/// The configured, ordered fallback accounts of every site, and the health
+/// of each account.
+///
+/// Health is tracked per API key: a 429 takes the account out of rotation
+/// until upstream's retry time, or for `rate_limited_backoff` if it gave
+/// none, and consecutive errors for `error_backoff`, doubling with each
+/// failure. A success restores it.
+pub struct FallbackPool {
+    sites: RwLock<HashMap<String, Vec<FallbackAccount>>>,
+    /// Account key hash -> (name, health)
+    health: Mutex<HashMap<String, (String, Health)>>,
+    backoff: FallbackSettings,
+}
+
+impl FallbackPool {
+    /// Pools from a config whose key files have already been read.
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            sites: RwLock::new(Self::pools(config)),
+            health: Mutex::new(HashMap::new()),
+            backoff: config.fallback.clone(),
+        }
+    }
+
+    fn pools(config: &Config) -> HashMap<String, Vec<FallbackAccount>> {
+        config
+            .sites
+            .iter()
+            .filter(|s| !s.fallbacks.is_empty())
+            .map(|s| {
+                let accounts = s
+                    .fallbacks
+                    .iter()
+                    .filter_map(|f| {
+                        Some(FallbackAccount {
+                            name: f.name.clone().unwrap_or_else(|| f.site_id.clone()),
+                            api_key: f.api_key.clone()?,
+                            site_id: f.site_id.clone(),
+                        })
+                    })
+                    .collect();
+                (s.id.clone(), accounts)
+            })
+            .collect()
+    }
+
+    /// Replace every site's pool with those of a reloaded config. Health is
+    /// kept for accounts whose key didn't change.
+    pub fn reload(&self, config: &Config) {
+        *self.sites.write().unwrap() = Self::pools(config);
+    }
+
+    /// A site's fallback accounts, in the order they are tried.
+    pub fn accounts_for(&self, rooftop_id: &str) -> Vec<FallbackAccount> {
+        self.sites
+            .read()
+            .unwrap()
+            .get(rooftop_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn is_healthy(&self, account: &FallbackAccount) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .get(&key_hash(&account.api_key))
+            .is_none_or(|(_, h)| h.is_healthy(Utc::now()))
+    }
+
+    /// Update an account's health after an attempt.
+    pub fn record(&self, account: &FallbackAccount, result: AttemptResult) {
+        let now = Utc::now();
+        let mut health = self.health.lock().unwrap();
+        let (name, h) = health
+            .entry(key_hash(&account.api_key))
+            .or_insert_with(|| (account.name.clone(), Health::default()));
+        name.clone_from(&account.name);
+        match result {
+            AttemptResult::Success => {
+                h.consecutive_failures = 0;
+                h.unhealthy_until = None;
+                h.last_success = Some(now);
+            }
+            AttemptResult::RateLimited { until } => {
+                h.consecutive_failures += 1;
+                h.last_failure = Some(now);
+                h.unhealthy_until = Some(until.unwrap_or_else(|| {
+                    now + Duration::seconds(self.backoff.rate_limited_backoff as i64)
+                }));
+            }
+            AttemptResult::Error => {
+                h.consecutive_failures += 1;
+                h.last_failure = Some(now);
+                let factor = 1u32
+                    .checked_shl(h.consecutive_failures - 1)
+                    .unwrap_or(u32::MAX)
+                    .min(MAX_ERROR_BACKOFF_FACTOR);
+                h.unhealthy_until = Some(
+                    now + Duration::seconds(self.backoff.error_backoff as i64 * factor as i64),
+                );
+            }
+        }
+    }
+
+    /// Health of every account tried so far, sorted by name.
+    pub fn health(&self) -> Vec<AccountHealth> {
+        let now = Utc::now();
+        let mut out: Vec<AccountHealth> = self
+            .health
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(name, h)| AccountHealth {
+                account: name.clone(),
+                healthy: h.is_healthy(now),
+                health: h.clone(),
+            })
+            .collect();
+        out.sort_by(|a, b| a.account.cmp(&b.account));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FallbackConfig, SiteConfig};
+
+    fn account(name: &str) -> FallbackAccount {
+        FallbackAccount {
+            name: name.into(),
+            api_key: format!("{name}-key"),
+            site_id: format!("{name}-site"),
+        }
+    }
+
+    #[test]
+    fn test_accounts_from_config() {
+        let mut config = Config::default();
+        config.sites.push(SiteConfig {
+            fallbacks: vec![
+                FallbackConfig {
+                    name: Some("first".into()),
+                    site_id: "s1".into(),
+                    api_key: Some("k1".into()),
+                    api_key_file: None,
+                },
+                FallbackConfig {
+                    name: None,
+                    site_id: "s2".into(),
+                    api_key: Some("k2".into()),
+                    api_key_file: None,
+                },
+            ],
+            ..SiteConfig::new("a")
+        });
+        let pool = FallbackPool::from_config(&config);
+        let names: Vec<String> = pool.accounts_for("a").into_iter().map(|a| a.name).collect();
+        assert_eq!(names, ["first", "s2"]);
+        assert!(pool.accounts_for("b").is_empty());
+    }
+
+    #[test]
+    fn test_health_backoff() {
+        let pool = FallbackPool::from_config(&Config::default());
+        let a = account("a");
+        assert!(pool.is_healthy(&a));
+
+        pool.record(&a, AttemptResult::RateLimited { until: None });
+        assert!(!pool.is_healthy(&a));
+        let until = pool.health()[0].health.unhealthy_until.unwrap();
+        assert!(until - Utc::now() > Duration::seconds(3500));
+        pool.record(
+            &a,
+            AttemptResult::RateLimited {
+                until: Some(Utc::now() + Duration::seconds(10)),
+            },
+        );
+        let until = pool.health()[0].health.unhealthy_until.unwrap();
+        assert!(until - Utc::now() <= Duration::seconds(10));
+        pool.record(&a, AttemptResult::Success);
+        assert!(pool.is_healthy(&a));
+
+        pool.record(&a, AttemptResult::Error);
+        pool.record(&a, AttemptResult::Error);
+        let health = pool.health();
+        assert_eq!(health[0].account, "a");
+        assert_eq!(health[0].health.consecutive_failures, 2);
+        // Second error in a row doubles the default 60s backoff
+        let until = health[0].health.unhealthy_until.unwrap();
+        assert!(until - Utc::now() > Duration::seconds(110));
+        assert!(!health[0].healthy);
+    }
+}
@@ -0,0 +1,378 @@
+//! Typed view of Solcast forecast bodies, used to answer narrower `hours` and
+//! coarser `period` requests from one cached wide forecast.
+
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+/// Horizon fetched upstream when clients ask for less (Solcast's default).
+pub const FETCH_HOURS: u32 = 168;
+
+/// Longest `hours` handled locally, a year. Larger values go upstream
+/// unparsed.
+pub const MAX_HOURS: u32 = 24 * 366;
+
+/// Solcast's default period length in minutes.
+pub const DEFAULT_PERIOD: u32 = 30;
+
+/// The slice of a forecast a client asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub hours: u32,
+    /// Period length in minutes.
+    pub period: u32,
+}
+
+impl Window {
+    /// Take `hours` and `period` out of `params`. If either is present but
+    /// can't be parsed, `params` is left alone and `None` returned, so the
+    /// request goes upstream as-is and Solcast reports the problem.
+    pub fn take(params: &mut Vec<(String, String)>) -> Option<Self> {
+        let get = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v);
+        let hours = match get("hours") {
+            Some(v) => v
+                .trim()
+                .parse()
+                .ok()
+                .filter(|h| (1..=MAX_HOURS).contains(h))?,
+            None => FETCH_HOURS,
+        };
+        let period = match get("period") {
+            Some(v) => parse_period(v).filter(|p| *p > 0)?,
+            None => DEFAULT_PERIOD,
+        };
+        params.retain(|(k, _)| k != "hours" && k != "period");
+        Some(Self { hours, period })
+    }
+
+    /// Query parameters to fetch upstream so the result can serve this
+    /// window: at least `FETCH_HOURS`, and a period fine enough to
+    /// aggregate from.
+    pub fn fetch_params(&self) -> Vec<(String, String)> {
+        let mut params = vec![("hours".to_string(), self.hours.max(FETCH_HOURS).to_string())];
+        let period = gcd(self.period, DEFAULT_PERIOD);
+        if period != DEFAULT_PERIOD {
+            params.push(("period".to_string(), format!("PT{period}M")));
+        }
+        params
+    }
+
+    /// Whether a cached body fetched at `fetched_at` has periods fine enough
+    /// and a horizon long enough for this window. Every fetch asks for at
+    /// least `FETCH_HOURS`, so the horizon only needs checking beyond that.
+    /// Bodies that can't be parsed are assumed to cover it.
+    pub fn covered_by(&self, body: &str, fetched_at: DateTime<Utc>) -> bool {
+        let Some(forecast) = Forecast::parse(body) else {
+            return true;
+        };
+        let (Some(first), Some(last)) = (forecast.periods.first(), forecast.periods.last()) else {
+            return true;
+        };
+        if !self.period.is_multiple_of(first.minutes) {
+            return false;
+        }
+        let needed =
+            Duration::minutes((self.hours as i64 * 60).saturating_sub(first.minutes as i64));
+        self.hours <= FETCH_HOURS || last.end - fetched_at >= needed
+    }
+
+    /// Cut a cached body down to this window: upcoming periods only,
+    /// aggregated to the requested period, limited to `hours`. `None` if the
+    /// body isn't a forecast this can handle.
+    pub fn slice(&self, body: &str, now: DateTime<Utc>) -> Option<String> {
+        let mut forecast = Forecast::parse(body)?;
+        let source = forecast.periods.first()?.minutes;
+        if !self.period.is_multiple_of(source) {
+            return None;
+        }
+        let upcoming: Vec<Period> = forecast.periods.drain(..).filter(|p| p.end > now).collect();
+        let periods = if self.period == source {
+            upcoming
+        } else {
+            aggregate(upcoming, self.period)
+        };
+        let count = (self.hours as u64 * 60 / self.period as u64) as usize;
+        forecast.periods = periods.into_iter().take(count).collect();
+        Some(forecast.into_json())
+    }
+}
+
+/// One forecast or estimated actuals period.
+#[derive(Debug, Clone)]
+pub struct Period {
+    pub end: DateTime<Utc>,
+    pub minutes: u32,
+    /// Every field, including `period_end` and `period`.
+    pub fields: Map<String, Value>,
+}
+
+impl Period {
+    fn parse(value: &Value) -> Option<Self> {
+        Self::from_fields(value.as_object()?.clone())
+    }
+
+    /// A period from its JSON fields, `None` without a valid `period_end`
+    /// and `period`.
+    pub fn from_fields(fields: Map<String, Value>) -> Option<Self> {
+        let end = DateTime::parse_from_rfc3339(fields.get("period_end")?.as_str()?)
+            .ok()?
+            .with_timezone(&Utc);
+        let minutes = parse_period(fields.get("period")?.as_str()?)?;
+        Some(Self {
+            end,
+            minutes,
+            fields,
+        })
+    }
+}
+
+/// The periods of a Solcast forecast or estimated actuals body, `None` if it
+/// isn't one.
+pub fn parse_periods(body: &str) -> Option<Vec<Period>> {
+    Forecast::parse(body).map(|f| f.periods)
+}
+
+/// A Solcast body whose records are one top-level array of periods.
+struct Forecast {
+    root: Map<String, Value>,
+    key: String,
+    periods: Vec<Period>,
+}
+
+impl Forecast {
+    fn parse(body: &str) -> Option<Self> {
+        let Value::Object(root) = serde_json::from_str(body).ok()? else {
+            return None;
+        };
+        let (key, items) = root.iter().find_map(|(k, v)| Some((k, v.as_array()?)))?;
+        let periods = items
+            .iter()
+            .map(Period::parse)
+            .collect::<Option<Vec<_>>>()?;
+        if periods.iter().any(|p| p.minutes == 0) {
+            return None;
+        }
+        Some(Self {
+            key: key.clone(),
+            periods,
+            root,
+        })
+    }
+
+    fn into_json(mut self) -> String {
+        let items = self
+            .periods
+            .into_iter()
+            .map(|p| Value::Object(p.fields))
+            .collect();
+        self.root.insert(self.key, Value::Array(items));
+        Value::Object(self.root).to_string()
+    }
+}
+
+/// Combine periods into `minutes`-long periods ending on multiples of
+/// `minutes`. Numeric fields are averaged (Solcast values are mean power or
+/// irradiance over the period); other fields keep the last value.
+fn aggregate(periods: Vec<Period>, minutes: u32) -> Vec<Period> {
+    let step = minutes as i64 * 60;
+    let mut groups: Vec<(i64, Vec<Period>)> = Vec::new();
+    for period in periods {
+        let bucket = (period.end.timestamp() + step - 1).div_euclid(step) * step;
+        match groups.last_mut() {
+            Some((b, group)) if *b == bucket => group.push(period),
+            _ => groups.push((bucket, vec![period])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter_map(|(bucket, group)| {
+            let end = DateTime::from_timestamp(bucket, 0)?;
+            let mut fields = group.last()?.fields.clone();
+            for (name, value) in fields.iter_mut() {
+                let values: Option<Vec<f64>> =
+                    group.iter().map(|p| p.fields.get(name)?.as_f64()).collect();
+                if let (Value::Number(_), Some(values)) = (&*value, values) {
+                    let mean = values.iter().sum::<f64>() / values.len() as f64;
+                    if let Some(n) = serde_json::Number::from_f64(mean) {
+                        *value = Value::Number(n);
+                    }
+                }
+            }
+            fields.insert(
+                "period_end".into(),
+                end.to_rfc3339_opts(SecondsFormat::Secs, true).into(),
+            );
+            if fields.contains_key("period_start") {
+                let start = end - Duration::minutes(minutes as i64);
+                fields.insert(
+                    "period_start".into(),
+                    start.to_rfc3339_opts(SecondsFormat::Secs, true).into(),
+                );
+            }
+            fields.insert("period".into(), format!("PT{minutes}M").into());
+            Some(Period {
+                end,
+                minutes,
+                fields,
+            })
+        })
+        .collect()
+}
+
+/// Minutes in an ISO 8601 period such as `PT30M` or `PT1H`.
+fn parse_period(s: &str) -> Option<u32> {
+    let rest = s.trim().strip_prefix("PT")?;
+    if let Some(m) = rest.strip_suffix('M') {
+        return m.parse().ok();
+    }
+    let h: u32 = rest.strip_suffix('H')?.parse().ok()?;
+    h.checked_mul(60)
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    /// `n` PT30M periods, the first ending at `first_end`, with pv_estimate
+    /// counting up from 1.
+    fn body(first_end: &str, n: usize) -> String {
+        let first = utc(first_end);
+        let forecasts: Vec<Value> = (0..n)
+            .map(|i| {
+                serde_json::json!({
+                    "pv_estimate": (i + 1) as f64,
+                    "period_end": (first + Duration::minutes(30 * i as i64)).to_rfc3339(),
+                    "period": "PT30M",
+                })
+            })
+            .collect();
+        serde_json::json!({ "forecasts": forecasts }).to_string()
+    }
+
+    fn window(query: &[(&str, &str)]) -> Option<Window> {
+        let mut params: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Window::take(&mut params)
+    }
+
+    fn periods(json: &str) -> Vec<Value> {
+        let value: Value = serde_json::from_str(json).unwrap();
+        value["forecasts"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn test_take() {
+        assert_eq!(
+            window(&[]),
+            Some(Window {
+                hours: FETCH_HOURS,
+                period: 30
+            })
+        );
+        assert_eq!(
+            window(&[("hours", "24"), ("period", "PT1H")]),
+            Some(Window {
+                hours: 24,
+                period: 60
+            })
+        );
+        assert_eq!(window(&[("hours", "soon")]), None);
+        assert_eq!(window(&[("period", "P1D")]), None);
+        assert_eq!(window(&[("hours", "80000000")]), None);
+        assert!(window(&[("hours", &MAX_HOURS.to_string())]).is_some());
+
+        let mut params = vec![
+            ("hours".to_string(), "24".to_string()),
+            ("x".to_string(), "1".to_string()),
+        ];
+        Window::take(&mut params);
+        assert_eq!(params, vec![("x".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn test_fetch_params() {
+        let w = window(&[("hours", "24"), ("period", "PT60M")]).unwrap();
+        assert_eq!(w.fetch_params(), vec![("hours".into(), "168".into())]);
+        let w = window(&[("hours", "336"), ("period", "PT15M")]).unwrap();
+        assert_eq!(
+            w.fetch_params(),
+            vec![
+                ("hours".into(), "336".into()),
+                ("period".into(), "PT15M".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_covered_by() {
+        let fetched = utc("2026-06-21T00:00:00Z");
+        let cached = body("2026-06-21T00:30:00Z", 48 * 8); // 8 days
+        let covered =
+            |query: &[(&str, &str)], body: &str| window(query).unwrap().covered_by(body, fetched);
+        assert!(covered(&[("hours", "24")], &cached));
+        assert!(covered(&[("hours", "192")], &cached));
+        assert!(covered(&[("hours", "12"), ("period", "PT60M")], &cached));
+        assert!(!covered(&[("hours", "336")], &cached));
+        assert!(!covered(&[("hours", "12"), ("period", "PT15M")], &cached));
+        // Within the default fetch, whatever upstream returned is all there is
+        let short = body("2026-06-21T00:30:00Z", 4);
+        assert!(covered(&[("hours", "48")], &short));
+        assert!(covered(&[("hours", "336")], "not json"));
+    }
+
+    #[test]
+    fn test_slice_hours() {
+        let cached = body("2026-06-21T00:30:00Z", 48);
+        let w = window(&[("hours", "2")]).unwrap();
+        let sliced = w.slice(&cached, utc("2026-06-21T01:10:00Z")).unwrap();
+        let out = periods(&sliced);
+        // Periods already over are dropped; then 2 hours of PT30M
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[0]["pv_estimate"], 3.0);
+
+        // No overflow for windows beyond anything `take` accepts
+        let huge = Window {
+            hours: u32::MAX,
+            period: 30,
+        };
+        assert!(!huge.covered_by(&cached, utc("2026-06-21T00:00:00Z")));
+        let sliced = huge.slice(&cached, utc("2026-06-21T01:10:00Z")).unwrap();
+        assert_eq!(periods(&sliced).len(), 46);
+    }
+
+    #[test]
+    fn test_slice_aggregates_to_hourly() {
+        let cached = body("2026-06-21T00:30:00Z", 8);
+        let w = window(&[("hours", "3"), ("period", "PT60M")]).unwrap();
+        let sliced = w.slice(&cached, utc("2026-06-21T00:00:00Z")).unwrap();
+        let out = periods(&sliced);
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[0]["period_end"], "2026-06-21T01:00:00Z");
+        assert_eq!(out[0]["period"], "PT60M");
+        assert_eq!(out[0]["pv_estimate"], 1.5);
+        assert_eq!(out[2]["pv_estimate"], 5.5);
+    }
+
+    #[test]
+    fn test_slice_rejects_finer_period_and_non_forecasts() {
+        let cached = body("2026-06-21T00:30:00Z", 8);
+        let w = window(&[("period", "PT15M")]).unwrap();
+        assert_eq!(w.slice(&cached, utc("2026-06-21T00:00:00Z")), None);
+        let w = window(&[]).unwrap();
+        assert_eq!(w.slice(r#"{"sites":[{"name":"a"}]}"#, Utc::now()), None);
+    }
+}
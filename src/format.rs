@@ -0,0 +1,290 @@
+use axum::http::HeaderMap;
+use serde_json::Value;
+
+/// Response format requested by a client. Upstream is always asked for JSON
+/// and other formats are rendered from the cached JSON body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    Xml,
+}
+
+impl Format {
+    /// Take the `format` query parameter out of `params`, falling back to the
+    /// `Accept` header. Errors on a `format` value Solcast wouldn't accept.
+    pub fn negotiate(
+        params: &mut Vec<(String, String)>,
+        headers: &HeaderMap,
+    ) -> Result<Self, String> {
+        let mut requested = None;
+        params.retain(|(k, v)| {
+            if k == "format" {
+                requested = Some(v.clone());
+                false
+            } else {
+                true
+            }
+        });
+        if let Some(value) = requested {
+            return match value.to_ascii_lowercase().as_str() {
+                "json" => Ok(Self::Json),
+                "csv" => Ok(Self::Csv),
+                "xml" => Ok(Self::Xml),
+                _ => Err(format!("Unsupported format '{value}'")),
+            };
+        }
+        let accept = headers
+            .get("Accept")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        Ok(Self::from_accept(accept))
+    }
+
+    /// The supported media type with the highest `q` in an `Accept` header,
+    /// JSON if none match.
+    fn from_accept(accept: &str) -> Self {
+        let mut best = (0.0, Self::Json);
+        for item in accept.split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let format = match parts.next().unwrap_or("") {
+                "application/json" => Self::Json,
+                "text/csv" | "application/csv" => Self::Csv,
+                "application/xml" | "text/xml" => Self::Xml,
+                _ => continue,
+            };
+            let q = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            if q > best.0 {
+                best = (q, format);
+            }
+        }
+        best.1
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json; charset=utf-8",
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Xml => "application/xml; charset=utf-8",
+        }
+    }
+
+    /// Render a cached JSON body in this format. `None` if the body isn't
+    /// JSON, in which case it should be served as it is.
+    pub fn render(self, json: &str) -> Option<String> {
+        let value = || serde_json::from_str::<Value>(json).ok();
+        match self {
+            Self::Json => Some(json.to_string()),
+            Self::Csv => Some(to_csv(&value()?)),
+            Self::Xml => Some(to_xml(&value()?)),
+        }
+    }
+}
+
+/// `pv_estimate10` -> `PvEstimate10`, the naming Solcast uses outside JSON.
+fn pascal_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Fields that lead Solcast's own CSV, in its order.
+const LEADING_COLUMNS: &[&str] = &["pv_estimate", "pv_estimate10", "pv_estimate90"];
+
+/// Fields that end each row of Solcast's own CSV.
+const TRAILING_COLUMNS: &[&str] = &["period_end", "period"];
+
+/// One CSV row per record. Solcast bodies wrap their records in a single
+/// top-level array (`forecasts`, `estimated_actuals`, `sites`, ...); any
+/// other object becomes a single row. Columns follow Solcast's order:
+/// estimates first, then any other fields, then `period_end` and `period`.
+fn to_csv(value: &Value) -> String {
+    let records: Vec<&Value> = match value {
+        Value::Array(items) => items.iter().collect(),
+        Value::Object(map) => match map.values().find_map(|v| v.as_array()) {
+            Some(items) => items.iter().collect(),
+            None => vec![value],
+        },
+        _ => vec![value],
+    };
+
+    let mut fields: Vec<&str> = Vec::new();
+    for record in &records {
+        if let Value::Object(map) = record {
+            for key in map.keys() {
+                if !fields.contains(&key.as_str()) {
+                    fields.push(key);
+                }
+            }
+        }
+    }
+    let known = |names: &[&'static str]| -> Vec<&'static str> {
+        names
+            .iter()
+            .copied()
+            .filter(|name| fields.contains(name))
+            .collect()
+    };
+    let mut columns = known(LEADING_COLUMNS);
+    columns.extend(
+        fields
+            .iter()
+            .filter(|f| !LEADING_COLUMNS.contains(f) && !TRAILING_COLUMNS.contains(f)),
+    );
+    columns.extend(known(TRAILING_COLUMNS));
+
+    let mut out = String::new();
+    let header: Vec<String> = columns.iter().map(|c| csv_field(&pascal_case(c))).collect();
+    out.push_str(&header.join(","));
+    out.push('\n');
+    for record in records {
+        let row: Vec<String> = columns
+            .iter()
+            .map(|c| match record.get(c) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => csv_field(s),
+                Some(v) => csv_field(&v.to_string()),
+            })
+            .collect();
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn to_xml(value: &Value) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml_element(&mut out, "Response", value);
+    out.push('\n');
+    out
+}
+
+/// Objects become child elements in PascalCase; array items are repeated
+/// elements named after the singular of the array (`Forecasts/Forecast`).
+fn xml_element(out: &mut String, name: &str, value: &Value) {
+    out.push_str(&format!("<{name}>"));
+    match value {
+        Value::Null => {}
+        Value::String(s) => out.push_str(&xml_escape(s)),
+        Value::Object(map) => {
+            for (key, child) in map {
+                xml_element(out, &pascal_case(key), child);
+            }
+        }
+        Value::Array(items) => {
+            let item_name = name.strip_suffix('s').unwrap_or("Item");
+            let item_name = if item_name.is_empty() {
+                "Item"
+            } else {
+                item_name
+            };
+            for item in items {
+                xml_element(out, item_name, item);
+            }
+        }
+        other => out.push_str(&other.to_string()),
+    }
+    out.push_str(&format!("</{name}>"));
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &str = r#"{"forecasts":[
+        {"pv_estimate":1.5,"period_end":"2026-06-21T02:00:00Z","period":"PT30M"},
+        {"pv_estimate":2,"period_end":"2026-06-21T02:30:00Z","period":"PT30M"}]}"#;
+
+    fn negotiate(query: &[(&str, &str)], accept: Option<&str>) -> Result<Format, String> {
+        let mut params: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut headers = HeaderMap::new();
+        if let Some(accept) = accept {
+            headers.insert("Accept", accept.parse().unwrap());
+        }
+        let format = Format::negotiate(&mut params, &headers);
+        assert!(params.iter().all(|(k, _)| k != "format"));
+        format
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(&[], None), Ok(Format::Json));
+        assert_eq!(negotiate(&[("format", "CSV")], None), Ok(Format::Csv));
+        // The query parameter wins over Accept
+        assert_eq!(
+            negotiate(&[("format", "json")], Some("text/csv")),
+            Ok(Format::Json)
+        );
+        assert_eq!(negotiate(&[], Some("application/xml")), Ok(Format::Xml));
+        assert_eq!(
+            negotiate(&[], Some("text/csv;q=0.5, application/json;q=0.9")),
+            Ok(Format::Json)
+        );
+        assert_eq!(negotiate(&[], Some("text/html, */*")), Ok(Format::Json));
+        assert!(negotiate(&[("format", "yaml")], None).is_err());
+    }
+
+    #[test]
+    fn test_csv() {
+        let csv = Format::Csv.render(BODY).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "PvEstimate,PeriodEnd,Period");
+        assert_eq!(lines[1], "1.5,2026-06-21T02:00:00Z,PT30M");
+        assert_eq!(lines.len(), 3);
+
+        let csv = Format::Csv
+            .render(r#"{"name":"a, \"b\"","capacity":null}"#)
+            .unwrap();
+        assert_eq!(csv, "Capacity,Name\n,\"a, \"\"b\"\"\"\n");
+
+        // Other fields sit between the estimates and the period
+        let csv = Format::Csv
+            .render(r#"{"estimated_actuals":[{"period":"PT30M","period_end":"2026-06-21T02:00:00Z","ghi":5,"air_temp":12}]}"#)
+            .unwrap();
+        assert!(csv.starts_with("AirTemp,Ghi,PeriodEnd,Period\n"));
+    }
+
+    #[test]
+    fn test_xml() {
+        let xml = Format::Xml.render(BODY).unwrap();
+        assert!(xml.starts_with("<?xml"));
+        assert!(xml.contains(
+            "<Response><Forecasts><Forecast><Period>PT30M</Period><PeriodEnd>2026-06-21T02:00:00Z</PeriodEnd><PvEstimate>1.5</PvEstimate></Forecast>"
+        ));
+        let xml = Format::Xml.render(r#"{"name":"a<b"}"#).unwrap();
+        assert!(xml.contains("<Name>a&lt;b</Name>"));
+    }
+
+    #[test]
+    fn test_render_non_json() {
+        assert_eq!(Format::Csv.render("not json"), None);
+        assert_eq!(Format::Json.render("not json").as_deref(), Some("not json"));
+    }
+}
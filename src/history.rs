@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, NaiveDateTime, SubsecRound, Utc};
+use tokio::sync::RwLock;
+
+use crate::archive::file_stem;
+use crate::persist;
+
+/// File name format of a snapshot, its fetch time in UTC.
+const SNAPSHOT_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Timestamped copies of every forecast fetched for a site, so the forecast
+/// as it stood at some earlier instant can be served again (`?as_of=`).
+///
+/// Each snapshot is its own file under `history/{site}/`, named after its
+/// fetch time; only the index is kept in memory. Snapshots older than the
+/// site's retention are deleted as new ones arrive.
+pub struct ForecastHistory {
+    /// Site file stem -> fetch time -> snapshot file.
+    snapshots: RwLock<HashMap<String, BTreeMap<DateTime<Utc>, PathBuf>>>,
+    dir: PathBuf,
+    /// Days kept for sites without their own setting; 0 keeps none.
+    retention_days: u32,
+    /// Per-site overrides of `retention_days`.
+    site_retention: HashMap<String, u32>,
+}
+
+impl ForecastHistory {
+    /// Create the history, indexing any snapshots already on disk.
+    pub fn new(
+        cache_dir: &Path,
+        retention_days: u32,
+        site_retention: HashMap<String, u32>,
+    ) -> Self {
+        let dir = cache_dir.join("history");
+        let snapshots = Self::index_disk(&dir);
+        let count: usize = snapshots.values().map(BTreeMap::len).sum();
+        if count > 0 {
+            tracing::info!("Indexed {} forecast snapshots", count);
+        }
+        Self {
+            snapshots: RwLock::new(snapshots),
+            dir,
+            retention_days,
+            site_retention,
+        }
+    }
+
+    fn retention(&self, rooftop_id: &str) -> u32 {
+        self.site_retention
+            .get(rooftop_id)
+            .copied()
+            .unwrap_or(self.retention_days)
+    }
+
+    /// Keep a forecast body fetched at `fetched_at`, and drop the site's
+    /// snapshots that have aged out.
+    pub async fn record(&self, rooftop_id: &str, body: &str, fetched_at: DateTime<Utc>) {
+        let retention = self.retention(rooftop_id);
+        if retention == 0 {
+            return;
+        }
+        // Snapshots are named to the second; a later one in the same second wins
+        let fetched_at = fetched_at.trunc_subsecs(0);
+        let stem = file_stem(rooftop_id);
+        let site_dir = self.dir.join(&stem);
+        if let Err(e) = tokio::fs::create_dir_all(&site_dir).await {
+            tracing::error!("Failed to create {}: {}", site_dir.display(), e);
+            return;
+        }
+        let path = site_dir.join(format!("{}.json", fetched_at.format(SNAPSHOT_FORMAT)));
+        if let Err(e) = persist::write_atomic(&path, body.as_bytes().to_vec()).await {
+            tracing::error!("Failed to write snapshot {}: {}", path.display(), e);
+            return;
+        }
+
+        let cutoff = fetched_at - Duration::days(retention as i64);
+        let expired = {
+            let mut snapshots = self.snapshots.write().await;
+            let site = snapshots.entry(stem).or_default();
+            site.insert(fetched_at, path);
+            let kept = site.split_off(&cutoff);
+            std::mem::replace(site, kept)
+        };
+        for path in expired.values() {
+            if let Err(e) = tokio::fs::remove_file(path).await {
+                tracing::warn!("Failed to remove snapshot {}: {}", path.display(), e);
+            }
+        }
+        if !expired.is_empty() {
+            tracing::debug!(
+                "{}/forecasts: dropped {} expired snapshots",
+                rooftop_id,
+                expired.len()
+            );
+        }
+    }
+
+    /// The latest snapshot fetched at or before `as_of`, with its fetch time.
+    pub async fn at(
+        &self,
+        rooftop_id: &str,
+        as_of: DateTime<Utc>,
+    ) -> Option<(DateTime<Utc>, String)> {
+        let (fetched_at, path) = {
+            let snapshots = self.snapshots.read().await;
+            let (t, p) = snapshots
+                .get(&file_stem(rooftop_id))?
+                .range(..=as_of)
+                .next_back()?;
+            (*t, p.clone())
+        };
+        match tokio::fs::read_to_string(&path).await {
+            Ok(body) => Some((fetched_at, body)),
+            Err(e) => {
+                tracing::error!("Failed to read snapshot {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Every snapshot fetched before `before`, oldest first.
+    pub async fn before(
+        &self,
+        rooftop_id: &str,
+        before: DateTime<Utc>,
+    ) -> Vec<(DateTime<Utc>, String)> {
+        let paths: Vec<(DateTime<Utc>, PathBuf)> = {
+            let snapshots = self.snapshots.read().await;
+            let Some(site) = snapshots.get(&file_stem(rooftop_id)) else {
+                return Vec::new();
+            };
+            site.range(..before).map(|(t, p)| (*t, p.clone())).collect()
+        };
+        let mut out = Vec::with_capacity(paths.len());
+        for (fetched_at, path) in paths {
+            match tokio::fs::read_to_string(&path).await {
+                Ok(body) => out.push((fetched_at, body)),
+                Err(e) => tracing::error!("Failed to read snapshot {}: {}", path.display(), e),
+            }
+        }
+        out
+    }
+
+    fn index_disk(dir: &Path) -> HashMap<String, BTreeMap<DateTime<Utc>, PathBuf>> {
+        let mut snapshots = HashMap::new();
+        let Ok(sites) = std::fs::read_dir(dir) else {
+            return snapshots;
+        };
+        for site in sites.flatten() {
+            let Ok(files) = std::fs::read_dir(site.path()) else {
+                continue;
+            };
+            let index: BTreeMap<DateTime<Utc>, PathBuf> = files
+                .flatten()
+                .filter_map(|f| {
+                    let path = f.path();
+                    let stem = path.file_stem()?.to_str()?;
+                    let t = NaiveDateTime::parse_from_str(stem, SNAPSHOT_FORMAT).ok()?;
+                    Some((t.and_utc(), path))
+                })
+                .collect();
+            snapshots.insert(site.file_name().to_string_lossy().into_owned(), index);
+        }
+        snapshots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[tokio::test]
+    async fn test_as_of() {
+        let dir = TempDir::new().unwrap();
+        let history = ForecastHistory::new(dir.path(), 7, HashMap::new());
+        history
+            .record("site1", "first", utc("2026-06-20T06:00:00Z"))
+            .await;
+        history
+            .record("site1", "second", utc("2026-06-20T12:00:00Z"))
+            .await;
+
+        assert_eq!(history.at("site1", utc("2026-06-20T05:59:59Z")).await, None);
+        let (t, body) = history
+            .at("site1", utc("2026-06-20T06:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!((t, body.as_str()), (utc("2026-06-20T06:00:00Z"), "first"));
+        let (_, body) = history
+            .at("site1", utc("2026-06-20T11:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!(body, "first");
+        let (_, body) = history
+            .at("site1", utc("2026-06-21T00:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!(body, "second");
+        assert_eq!(history.at("site2", utc("2026-06-21T00:00:00Z")).await, None);
+        let before = history.before("site1", utc("2026-06-20T12:00:00Z")).await;
+        assert_eq!(
+            before,
+            vec![(utc("2026-06-20T06:00:00Z"), "first".to_string())]
+        );
+
+        // Indexed again from disk after a restart
+        let history = ForecastHistory::new(dir.path(), 7, HashMap::new());
+        let (_, body) = history
+            .at("site1", utc("2026-06-21T00:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!(body, "second");
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let dir = TempDir::new().unwrap();
+        let mut site_retention = HashMap::new();
+        site_retention.insert("short".to_string(), 1);
+        site_retention.insert("off".to_string(), 0);
+        let history = ForecastHistory::new(dir.path(), 7, site_retention);
+
+        for site in ["short", "long", "off"] {
+            history
+                .record(site, "old", utc("2026-06-01T06:00:00Z"))
+                .await;
+            history
+                .record(site, "new", utc("2026-06-03T06:00:00Z"))
+                .await;
+        }
+        let as_of = utc("2026-06-01T12:00:00Z");
+        assert_eq!(history.at("short", as_of).await, None);
+        assert!(history.at("long", as_of).await.is_some());
+        assert_eq!(history.at("off", utc("2026-06-04T00:00:00Z")).await, None);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("history/short"))
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_similar_ids_kept_apart() {
+        let dir = TempDir::new().unwrap();
+        let fetched = utc("2026-06-20T06:00:00Z");
+        {
+            let history = ForecastHistory::new(dir.path(), 7, HashMap::new());
+            history.record("a.b", "dot", fetched).await;
+            history.record("a_b", "underscore", fetched).await;
+        }
+        let history = ForecastHistory::new(dir.path(), 7, HashMap::new());
+        let (_, body) = history.at("a.b", fetched).await.unwrap();
+        assert_eq!(body, "dot");
+        let (_, body) = history.at("a_b", fetched).await.unwrap();
+        assert_eq!(body, "underscore");
+    }
+}
@@ -1,17 +1,54 @@
+mod access;
+mod accuracy;
+mod archive;
 mod cache;
+mod cache_control;
+mod compress;
+mod config;
+mod credentials;
+mod endpoints;
+#[cfg(test)]
+mod fake_solcast;
+mod fallback;
+mod forecast;
+mod format;
+mod history;
+mod metrics;
+mod persist;
 mod proxy;
+mod quota;
+mod ratelimit;
+mod scheduler;
+mod singleflight;
+mod solar;
+mod store;
 
-use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
+use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::{extract::State, Json, Router};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::Serialize;
 use tokio::time::Instant;
 
+use access::{Isolation, KeyRegistry};
+use archive::ActualsArchive;
 use cache::ProxyCache;
+use config::{Config, FallbackSettings, Policies};
+use credentials::Credentials;
+use fallback::{AccountHealth, FallbackPool};
+use history::ForecastHistory;
+use metrics::Metrics;
+use proxy::FetchOutcome;
+use quota::{QuotaLedger, QuotaUsage};
+use ratelimit::{AccountLimit, UpstreamLimits};
+use scheduler::{ScheduleConfig, ScheduleMode};
+use singleflight::SingleFlight;
+use solar::{Location, NightPolicy};
+use store::Backend;
 
 #[derive(Parser)]
 #[command(
@@ -19,21 +56,219 @@ use cache::ProxyCache;
     about = "Caching reverse proxy for Solcast API"
 )]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML configuration file; flags and environment variables override it
+    #[arg(long, global = true, env = "SOLCAST_PROXY_CONFIG")]
+    config: Option<PathBuf>,
+
     /// Listen port
-    #[arg(short, long, default_value = "8888")]
-    port: u16,
+    #[arg(short, long, env = "SOLCAST_PROXY_PORT")]
+    port: Option<u16>,
 
     /// Cache directory
-    #[arg(short, long, default_value = "./data")]
-    cache_dir: PathBuf,
+    #[arg(short, long, env = "SOLCAST_PROXY_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
 
     /// Cache TTL in seconds
-    #[arg(long, default_value = "7200")]
-    ttl: u64,
+    #[arg(long, env = "SOLCAST_PROXY_TTL")]
+    ttl: Option<u64>,
 
     /// Minimum seconds between upstream calls per endpoint
-    #[arg(long, default_value = "9000")]
-    rate_limit: u64,
+    #[arg(long, env = "SOLCAST_PROXY_RATE_LIMIT")]
+    rate_limit: Option<u64>,
+
+    /// Which clients cached entries are served to
+    #[arg(long, value_enum, env = "SOLCAST_PROXY_CACHE_ISOLATION")]
+    cache_isolation: Option<Isolation>,
+
+    /// Where cached entries are persisted
+    #[arg(long, value_enum, env = "SOLCAST_PROXY_CACHE_BACKEND")]
+    cache_backend: Option<Backend>,
+
+    /// Seconds past TTL that stale entries are served at once while refreshed in the background
+    #[arg(long, env = "SOLCAST_PROXY_STALE_WHILE_REVALIDATE")]
+    stale_while_revalidate: Option<u64>,
+
+    /// Seconds past TTL after which entries are never served stale
+    #[arg(long, env = "SOLCAST_PROXY_STALE_IF_ERROR")]
+    stale_if_error: Option<u64>,
+
+    /// Most cache entries kept, evicting the least recently used (0 for no limit)
+    #[arg(long, env = "SOLCAST_PROXY_CACHE_MAX_ENTRIES")]
+    cache_max_entries: Option<usize>,
+
+    /// Most bytes of compressed bodies cached, evicting the least recently used (0 for no limit)
+    #[arg(long, env = "SOLCAST_PROXY_CACHE_MAX_BYTES")]
+    cache_max_bytes: Option<u64>,
+
+    /// Delete cache entries neither fetched nor read for this many days (0 to keep them)
+    #[arg(long, env = "SOLCAST_PROXY_CACHE_MAX_IDLE_DAYS")]
+    cache_max_idle_days: Option<u64>,
+
+    /// Maximum upstream calls per API key per UTC day
+    #[arg(long, env = "SOLCAST_PROXY_DAILY_BUDGET")]
+    daily_budget: Option<u32>,
+
+    /// Base URL of the upstream Solcast API
+    #[arg(long, env = "SOLCAST_PROXY_UPSTREAM_URL")]
+    upstream_url: Option<String>,
+
+    /// Upstream request timeout in seconds
+    #[arg(long, env = "SOLCAST_PROXY_UPSTREAM_TIMEOUT")]
+    upstream_timeout: Option<u64>,
+
+    /// Rooftop site to refresh proactively in the background (repeatable)
+    #[arg(long = "site", value_name = "ROOFTOP_ID")]
+    sites: Vec<String>,
+
+    /// Solcast API key used for scheduled refreshes
+    #[arg(long, env = "SOLCAST_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+
+    /// How scheduled refreshes are spread over the day
+    #[arg(long, value_enum)]
+    schedule: Option<ScheduleMode>,
+
+    /// Local hours treated as daylight by the daylight schedule, as START-END
+    #[arg(long, value_parser = parse_hours)]
+    daylight_hours: Option<(u32, u32)>,
+
+    /// Daily upstream calls the scheduler leaves for on-demand client requests
+    #[arg(long)]
+    schedule_reserve: Option<u32>,
+
+    /// Site coordinates for night-time fetch suppression, as ROOFTOP_ID=LAT,LON (repeatable)
+    #[arg(long = "site-location", value_name = "ROOFTOP_ID=LAT,LON", value_parser = parse_site_location)]
+    site_locations: Vec<(String, Location)>,
+
+    /// Minutes before sunrise that upstream fetching resumes
+    #[arg(long)]
+    sunrise_offset: Option<i64>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the configuration and print the effective settings
+    CheckConfig,
+}
+
+impl Cli {
+    /// Load the config file (if any) and apply flag and environment overrides.
+    fn resolve_config(&self) -> Result<Config, String> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(port) = self.port {
+            config.listen.set_port(port);
+        }
+        if let Some(dir) = &self.cache_dir {
+            config.cache.dir = dir.clone();
+        }
+        if let Some(ttl) = self.ttl {
+            config.cache.ttl = ttl;
+        }
+        if let Some(rate_limit) = self.rate_limit {
+            config.cache.rate_limit = rate_limit;
+        }
+        if let Some(isolation) = self.cache_isolation {
+            config.cache.isolation = isolation;
+        }
+        if let Some(backend) = self.cache_backend {
+            config.cache.backend = backend;
+        }
+        if let Some(secs) = self.stale_while_revalidate {
+            config.cache.stale_while_revalidate = secs;
+        }
+        if let Some(secs) = self.stale_if_error {
+            config.cache.stale_if_error = Some(secs);
+        }
+        if let Some(n) = self.cache_max_entries {
+            config.cache.max_entries = n;
+        }
+        if let Some(n) = self.cache_max_bytes {
+            config.cache.max_bytes = n;
+        }
+        if let Some(days) = self.cache_max_idle_days {
+            config.cache.max_idle_days = days;
+        }
+        if let Some(budget) = self.daily_budget {
+            config.daily_budget = budget;
+        }
+        if let Some(url) = &self.upstream_url {
+            config.upstream_url = url.clone();
+        }
+        if let Some(timeout) = self.upstream_timeout {
+            config.upstream_timeout = timeout;
+        }
+        if let Some(api_key) = &self.api_key {
+            config.api_key = Some(api_key.clone());
+            config.api_key_file = None;
+        }
+        if let Some(mode) = self.schedule {
+            config.schedule.mode = mode;
+        }
+        if let Some(hours) = self.daylight_hours {
+            config.schedule.daylight_hours = hours;
+        }
+        if let Some(reserve) = self.schedule_reserve {
+            config.schedule.reserve = reserve;
+        }
+        if let Some(offset) = self.sunrise_offset {
+            config.schedule.sunrise_offset = offset;
+        }
+        for id in &self.sites {
+            config.site_mut(id).refresh = true;
+        }
+        for (id, loc) in &self.site_locations {
+            let known = config.sites.iter().any(|s| &s.id == id);
+            let site = config.site_mut(id);
+            if !known {
+                // Coordinates alone don't opt a site into background refresh
+                site.refresh = false;
+            }
+            site.latitude = Some(loc.latitude);
+            site.longitude = Some(loc.longitude);
+        }
+        config.upstream_url = config.upstream_url.trim_end_matches('/').to_string();
+        config.read_key_files()?;
+        Ok(config)
+    }
+}
+
+fn parse_site_location(s: &str) -> Result<(String, Location), String> {
+    let (id, coords) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ROOFTOP_ID=LAT,LON, got '{s}'"))?;
+    let (lat, lon) = coords
+        .split_once(',')
+        .ok_or_else(|| format!("expected LAT,LON, got '{coords}'"))?;
+    let latitude: f64 = lat.trim().parse().map_err(|e| format!("latitude: {e}"))?;
+    let longitude: f64 = lon.trim().parse().map_err(|e| format!("longitude: {e}"))?;
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return Err(format!("coordinates out of range: '{coords}'"));
+    }
+    Ok((
+        id.to_string(),
+        Location {
+            latitude,
+            longitude,
+        },
+    ))
+}
+
+fn parse_hours(s: &str) -> Result<(u32, u32), String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected START-END, got '{s}'"))?;
+    let start: u32 = start.trim().parse().map_err(|e| format!("{e}"))?;
+    let end: u32 = end.trim().parse().map_err(|e| format!("{e}"))?;
+    if start >= end || end > 24 {
+        return Err(format!("invalid hour range '{s}'"));
+    }
+    Ok((start, end))
 }
 
 pub struct AppState {
@@ -43,6 +278,66 @@ pub struct AppState {
     pub start_time: Instant,
     pub ttl: u64,
     pub rate_limit: u64,
+    /// Seconds past TTL that entries are served while refreshed in the background.
+    pub stale_while_revalidate: u64,
+    /// Seconds past TTL after which entries are never served stale.
+    pub stale_if_error: Option<u64>,
+    /// Per-site and per-endpoint overrides of `ttl` and `rate_limit`.
+    pub policies: Policies,
+    /// Backoffs applied after failed upstream calls.
+    pub backoff: FallbackSettings,
+    /// Per-API-key daily upstream call counters.
+    pub quota: QuotaLedger,
+    /// Site locations used to skip upstream fetches at night.
+    pub night: NightPolicy,
+    /// Counters exposed on `/metrics`.
+    pub metrics: Metrics,
+    /// Upstream refreshes currently in flight, keyed by cache key.
+    pub inflight: SingleFlight<FetchOutcome>,
+    /// Long-term history of every estimated_actuals period seen.
+    pub archive: ActualsArchive,
+    /// Snapshots of past forecasts, served with `?as_of=`.
+    pub history: ForecastHistory,
+    /// Which clients cached entries are served to.
+    pub isolation: Isolation,
+    /// API keys Solcast has accepted, per site.
+    pub keys: KeyRegistry,
+    /// Keys held by the proxy for clients that send none.
+    pub credentials: Credentials,
+    /// Per-site fallback accounts and their health.
+    pub fallbacks: FallbackPool,
+    /// Rate-limit state upstream reported for each API key.
+    pub limits: UpstreamLimits,
+}
+
+impl AppState {
+    /// Cache TTL for an endpoint of a site.
+    pub fn ttl_for(&self, rooftop_id: &str, endpoint: &str) -> u64 {
+        self.policies
+            .ttl(rooftop_id, endpoint)
+            .or_else(|| endpoints::default_ttl(endpoint))
+            .unwrap_or(self.ttl)
+    }
+
+    /// Whether a client may read data archived for a site: anyone when the
+    /// cache is shared, otherwise only keys Solcast has accepted for it.
+    pub async fn may_read(&self, site: &str, api_key: &str) -> bool {
+        self.isolation == Isolation::Shared || self.keys.is_known(site, api_key).await
+    }
+
+    /// Minimum seconds between upstream calls for an endpoint of a site.
+    pub fn rate_limit_for(&self, rooftop_id: &str, endpoint: &str) -> u64 {
+        self.policies
+            .rate_limit(rooftop_id, endpoint)
+            .unwrap_or(self.rate_limit)
+    }
+
+    /// The longest rate limit any endpoint has.
+    pub fn max_rate_limit(&self) -> u64 {
+        self.policies
+            .max_rate_limit()
+            .map_or(self.rate_limit, |max| max.max(self.rate_limit))
+    }
 }
 
 #[derive(Serialize)]
@@ -50,6 +345,36 @@ struct HealthResponse {
     status: String,
     cache_entries: usize,
     uptime_secs: u64,
+    quota: Vec<QuotaUsage>,
+    fallbacks: Vec<AccountHealth>,
+    upstream_limits: Vec<AccountLimit>,
+}
+
+/// Build the HTTP router for the given state.
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/rooftop_sites", get(proxy::site_list_handler))
+        .route("/rooftop_sites/{rooftop_id}", get(proxy::site_handler))
+        .route(
+            "/rooftop_sites/{rooftop_id}/{endpoint}",
+            get(proxy::proxy_handler),
+        )
+        .route("/data/{group}/{resource}", get(proxy::data_handler))
+        .route(
+            "/world_radiation/{resource}",
+            get(proxy::world_radiation_handler),
+        )
+        .route(
+            "/archive/{rooftop_id}/actuals",
+            get(archive::actuals_handler),
+        )
+        .route(
+            "/archive/{rooftop_id}/accuracy",
+            get(accuracy::accuracy_handler),
+        )
+        .route("/health", get(health))
+        .route("/metrics", get(metrics))
+        .with_state(state)
 }
 
 async fn health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
@@ -57,51 +382,365 @@ async fn health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
         status: "ok".to_string(),
         cache_entries: state.cache.entry_count().await,
         uptime_secs: state.start_time.elapsed().as_secs(),
+        quota: state.quota.usage().await,
+        fallbacks: state.fallbacks.health(),
+        upstream_limits: state.limits.accounts(),
     })
 }
 
+async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [("Content-Type", "text/plain; version=0.0.4")],
+        state.metrics.render(&state.cache).await,
+    )
+}
+
+/// Print the effective configuration for `check-config`.
+fn print_config(config: &Config) {
+    println!("listen:           {}", config.listen);
+    println!("upstream_url:     {}", config.upstream_url);
+    println!("upstream_timeout: {}s", config.upstream_timeout);
+    println!("daily_budget:     {}", config.daily_budget);
+    println!(
+        "api_key:          {}",
+        match (&config.api_key_file, &config.api_key) {
+            (Some(path), _) => format!("from {}", path.display()),
+            (None, Some(_)) => "set".to_string(),
+            (None, None) => "none".to_string(),
+        }
+    );
+    println!("cache.dir:        {}", config.cache.dir.display());
+    println!("cache.ttl:        {}s", config.cache.ttl);
+    println!("cache.rate_limit: {}s", config.cache.rate_limit);
+    println!("cache.isolation:  {:?}", config.cache.isolation);
+    println!("cache.backend:    {:?}", config.cache.backend);
+    println!("cache.snapshots:  {}", config.cache.snapshots);
+    println!(
+        "cache.stale:      {}s while revalidating, {} if upstream fails",
+        config.cache.stale_while_revalidate,
+        match config.cache.stale_if_error {
+            Some(secs) => format!("up to {secs}s"),
+            None => "any age".to_string(),
+        }
+    );
+    let limit = |n: u64, unit: &str| match n {
+        0 => "unlimited".to_string(),
+        n => format!("{n}{unit}"),
+    };
+    println!(
+        "cache.limits:     {} entries, {} bytes, {} idle",
+        limit(config.cache.max_entries as u64, ""),
+        limit(config.cache.max_bytes, ""),
+        limit(config.cache.max_idle_days, " days"),
+    );
+    println!(
+        "fallback backoff: {}s after 429, {}s after error",
+        config.fallback.rate_limited_backoff, config.fallback.error_backoff
+    );
+    println!(
+        "schedule:         {:?}, reserve {}, sunrise offset {}min",
+        config.schedule.mode, config.schedule.reserve, config.schedule.sunrise_offset
+    );
+    println!("history:          {} days", config.history.retention_days);
+    let mut endpoints: Vec<_> = config.endpoints.iter().collect();
+    endpoints.sort_by_key(|(name, _)| name.as_str());
+    for (name, ep) in endpoints {
+        println!(
+            "endpoint {}: ttl={:?} rate_limit={:?}",
+            name, ep.ttl, ep.rate_limit
+        );
+    }
+    for site in &config.sites {
+        println!(
+            "site {}{}: refresh={}, api_key={}, location={}, history={}",
+            site.id,
+            site.name
+                .as_ref()
+                .map(|n| format!(" ({n})"))
+                .unwrap_or_default(),
+            site.refresh,
+            if site.api_key.is_some() {
+                "site"
+            } else if config.api_key.is_some() {
+                "default"
+            } else {
+                "none"
+            },
+            site.location()
+                .map(|l| format!("{},{}", l.latitude, l.longitude))
+                .unwrap_or_else(|| "none".to_string()),
+            site.history_retention_days
+                .unwrap_or(config.history.retention_days),
+        );
+        for (i, fb) in site.fallbacks.iter().enumerate() {
+            println!(
+                "  fallback {}: {} (site {}, api_key {})",
+                i + 1,
+                fb.name.as_deref().unwrap_or(&fb.site_id),
+                fb.site_id,
+                match &fb.api_key_file {
+                    Some(path) => format!("from {}", path.display()),
+                    None => "set".to_string(),
+                }
+            );
+        }
+    }
+}
+
+/// How often idle and over-limit cache entries are cleared out.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Periodically delete idle cache entries, evict any over the size limits
+/// and drop rate limit records that have run out.
+fn spawn_cache_sweep(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let window = Duration::from_secs(state.max_rate_limit());
+            state.cache.sweep(window).await;
+        }
+    });
+}
+
+/// Re-read the config and key files and swap in the new API keys and
+/// fallback accounts. A config that would be refused at startup changes
+/// nothing.
+fn reload_keys(state: &AppState, cli: &Cli) -> Result<(), String> {
+    let config = cli.resolve_config()?;
+    config.validate().map_err(|errors| errors.join("; "))?;
+    state.credentials.reload(&config);
+    state.fallbacks.reload(&config);
+    Ok(())
+}
+
+/// Reload API keys and fallback accounts on SIGHUP, so a key can be rotated
+/// without a restart. Other settings are unchanged.
+fn spawn_reload(state: Arc<AppState>, cli: Cli) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if let Err(e) = reload_keys(&state, &cli) {
+                tracing::error!("Reload failed, keeping current keys: {}", e);
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = (state, cli);
+}
+
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
 
     tracing_subscriber::fmt().with_target(false).init();
 
+    let config = match cli.resolve_config() {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let valid = config.validate();
+
+    if let Some(Command::CheckConfig) = cli.command {
+        print_config(&config);
+        match valid {
+            Ok(()) => println!("Configuration OK"),
+            Err(errors) => {
+                for e in errors {
+                    println!("error: {e}");
+                }
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Err(errors) = valid {
+        for e in errors {
+            tracing::error!("Invalid configuration: {}", e);
+        }
+        std::process::exit(1);
+    }
+
     // Ensure cache directory exists
-    if let Err(e) = std::fs::create_dir_all(&cli.cache_dir) {
+    if let Err(e) = std::fs::create_dir_all(&config.cache.dir) {
         tracing::error!(
             "Failed to create cache dir {}: {}",
-            cli.cache_dir.display(),
+            config.cache.dir.display(),
             e
         );
         std::process::exit(1);
     }
 
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.upstream_timeout))
+        .build()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::error!("Failed to build HTTP client: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let store = match store::open(&config.cache) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!("Failed to open cache store: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     let state = Arc::new(AppState {
-        cache: ProxyCache::new(&cli.cache_dir),
-        upstream_url: "https://api.solcast.com.au".to_string(),
-        client: reqwest::Client::new(),
+        cache: ProxyCache::with_store(store).with_limits(config.cache_limits()),
+        upstream_url: config.upstream_url.clone(),
+        client,
         start_time: Instant::now(),
-        ttl: cli.ttl,
-        rate_limit: cli.rate_limit,
+        ttl: config.cache.ttl,
+        rate_limit: config.cache.rate_limit,
+        stale_while_revalidate: config.cache.stale_while_revalidate,
+        stale_if_error: config.cache.stale_if_error,
+        policies: Policies::from_config(&config),
+        backoff: config.fallback.clone(),
+        quota: QuotaLedger::new(&config.cache.dir, config.daily_budget),
+        metrics: Metrics::new(),
+        night: NightPolicy::new(
+            config.locations(),
+            chrono::Duration::minutes(config.schedule.sunrise_offset),
+        ),
+        inflight: SingleFlight::new(),
+        archive: ActualsArchive::new(&config.cache.dir),
+        history: ForecastHistory::new(
+            &config.cache.dir,
+            config.history.retention_days,
+            config.history_retention(),
+        ),
+        isolation: config.cache.isolation,
+        keys: KeyRegistry::new(&config.cache.dir),
+        credentials: Credentials::from_config(&config),
+        fallbacks: FallbackPool::from_config(&config),
+        limits: UpstreamLimits::default(),
     });
 
-    let app = Router::new()
-        .route(
-            "/rooftop_sites/{rooftop_id}/{endpoint}",
-            get(proxy::proxy_handler),
-        )
-        .route("/health", get(health))
-        .with_state(state);
+    scheduler::spawn(state.clone(), ScheduleConfig::from_config(&config));
+    spawn_cache_sweep(state.clone());
+    spawn_reload(state.clone(), cli);
+
+    let app = router(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], cli.port));
     tracing::info!(
-        "Solcast proxy listening on {} (ttl={}s, rate_limit={}s, cache_dir={})",
-        addr,
-        cli.ttl,
-        cli.rate_limit,
-        cli.cache_dir.display()
+        "Solcast proxy listening on {} (upstream={}, ttl={}s, rate_limit={}s, daily_budget={}, cache_dir={})",
+        config.listen,
+        config.upstream_url,
+        config.cache.ttl,
+        config.cache.rate_limit,
+        config.daily_budget,
+        config.cache.dir.display()
     );
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(config.listen).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_flags_override_config_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+listen = "127.0.0.1:9000"
+upstream_url = "http://mirror.local/"
+
+[cache]
+ttl = 3600
+rate_limit = 600
+
+[[sites]]
+id = "site1"
+refresh = false
+"#,
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_from([
+            "solcast-proxy",
+            "--config",
+            path.to_str().unwrap(),
+            "--port",
+            "9100",
+            "--ttl",
+            "60",
+            "--cache-isolation",
+            "key",
+            "--cache-backend",
+            "sqlite",
+            "--cache-max-entries",
+            "200",
+            "--site",
+            "site1",
+            "--site-location",
+            "site2=-33.8,151.2",
+        ])
+        .unwrap();
+        let config = cli.resolve_config().unwrap();
+        assert_eq!(config.listen, "127.0.0.1:9100".parse().unwrap());
+        assert_eq!(config.upstream_url, "http://mirror.local");
+        assert_eq!(config.cache.ttl, 60);
+        assert_eq!(config.cache.rate_limit, 600);
+        assert_eq!(config.cache.isolation, Isolation::Key);
+        assert_eq!(config.cache.backend, Backend::Sqlite);
+        assert_eq!(config.cache.max_entries, 200);
+        assert_eq!(config.sites.len(), 2);
+        assert!(config.sites[0].refresh);
+        assert!(!config.sites[1].refresh);
+        assert!(config.sites[1].location().is_some());
+    }
+
+    #[test]
+    fn test_invalid_reload_keeps_keys() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        let site = |key: &str| format!("[[sites]]\nid = \"site1\"\napi_key = \"{key}\"\n");
+        std::fs::write(&path, site("old")).unwrap();
+        let cli =
+            Cli::try_parse_from(["solcast-proxy", "--config", path.to_str().unwrap()]).unwrap();
+        let state = fake_solcast::test_state("http://127.0.0.1:1", dir.path());
+        reload_keys(&state, &cli).unwrap();
+        assert_eq!(state.credentials.key_for("site1").as_deref(), Some("old"));
+
+        // The same site twice would be refused at startup
+        std::fs::write(&path, site("new").repeat(2)).unwrap();
+        let err = reload_keys(&state, &cli).unwrap_err();
+        assert!(err.contains("defined more than once"), "{err}");
+        assert_eq!(state.credentials.key_for("site1").as_deref(), Some("old"));
+
+        std::fs::write(&path, site("new")).unwrap();
+        reload_keys(&state, &cli).unwrap();
+        assert_eq!(state.credentials.key_for("site1").as_deref(), Some("new"));
+    }
+
+    #[test]
+    fn test_check_config_subcommand_parses() {
+        let cli =
+            Cli::try_parse_from(["solcast-proxy", "check-config", "--config", "x.toml"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::CheckConfig)));
+        assert_eq!(cli.config, Some(PathBuf::from("x.toml")));
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::cache::ProxyCache;
+
+/// Upper bounds (seconds) of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 9] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative count per bucket in `LATENCY_BUCKETS`.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Counters and gauges exposed on `/metrics` in Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    /// (site, endpoint, X-Cache status) -> responses
+    cache_results: Mutex<BTreeMap<(String, String, String), u64>>,
+    /// (endpoint, status code or "error") -> upstream responses
+    upstream_responses: Mutex<BTreeMap<(String, String), u64>>,
+    /// endpoint -> upstream latency
+    upstream_latency: Mutex<BTreeMap<String, Histogram>>,
+    /// (site, account, result) -> fallback attempts
+    fallback_attempts: Mutex<BTreeMap<(String, String, String), u64>>,
+    /// fallback account name -> whether it is currently in rotation
+    fallback_healthy: Mutex<BTreeMap<String, bool>>,
+    /// account hash -> last `x-rate-limit-remaining` seen upstream
+    rate_limit_remaining: Mutex<BTreeMap<String, i64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a response served to a client with the given `X-Cache` status.
+    pub fn record_cache_result(&self, site: &str, endpoint: &str, status: &str) {
+        let mut map = self.cache_results.lock().unwrap();
+        *map.entry((site.to_string(), endpoint.to_string(), status.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Record an upstream round trip. `status` is `None` when the request
+    /// failed without a response.
+    pub fn record_upstream(&self, endpoint: &str, status: Option<u16>, latency: Duration) {
+        let status = status.map_or_else(|| "error".to_string(), |s| s.to_string());
+        *self
+            .upstream_responses
+            .lock()
+            .unwrap()
+            .entry((endpoint.to_string(), status))
+            .or_default() += 1;
+        self.upstream_latency
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Count a fallback attempt on an account and how it ended.
+    pub fn record_fallback(&self, site: &str, account: &str, result: &str) {
+        *self
+            .fallback_attempts
+            .lock()
+            .unwrap()
+            .entry((site.to_string(), account.to_string(), result.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Remember whether a fallback account is in rotation after its last attempt.
+    pub fn set_fallback_healthy(&self, account: &str, healthy: bool) {
+        self.fallback_healthy
+            .lock()
+            .unwrap()
+            .insert(account.to_string(), healthy);
+    }
+
+    /// Remember the `x-rate-limit-remaining` value Solcast reported for an account.
+    pub fn set_rate_limit_remaining(&self, account: &str, remaining: i64) {
+        self.rate_limit_remaining
+            .lock()
+            .unwrap()
+            .insert(account.to_string(), remaining);
+    }
+
+    /// Render all metrics, including cache size gauges read from `cache`.
+    pub async fn render(&self, cache: &ProxyCache) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "solcast_proxy_cache_responses_total",
+            "counter",
+            "Responses served to clients by cache status",
+        );
+        for ((site, endpoint, status), n) in self.cache_results.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "solcast_proxy_cache_responses_total{{site=\"{}\",endpoint=\"{}\",status=\"{}\"}} {}",
+                escape(site),
+                escape(endpoint),
+                escape(status),
+                n
+            );
+        }
+
+        header(
+            &mut out,
+            "solcast_proxy_upstream_responses_total",
+            "counter",
+            "Upstream responses by status code (\"error\" for failed requests)",
+        );
+        let mut rate_limited: BTreeMap<String, u64> = BTreeMap::new();
+        for ((endpoint, status), n) in self.upstream_responses.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "solcast_proxy_upstream_responses_total{{endpoint=\"{}\",status=\"{}\"}} {}",
+                escape(endpoint),
+                escape(status),
+                n
+            );
+            if status == "429" {
+                *rate_limited.entry(endpoint.clone()).or_default() += n;
+            }
+        }
+
+        header(
+            &mut out,
+            "solcast_proxy_upstream_rate_limited_total",
+            "counter",
+            "Upstream 429 responses",
+        );
+        for (endpoint, n) in &rate_limited {
+            let _ = writeln!(
+                out,
+                "solcast_proxy_upstream_rate_limited_total{{endpoint=\"{}\"}} {}",
+                escape(endpoint),
+                n
+            );
+        }
+
+        header(
+            &mut out,
+            "solcast_proxy_upstream_latency_seconds",
+            "histogram",
+            "Upstream request latency",
+        );
+        for (endpoint, h) in self.upstream_latency.lock().unwrap().iter() {
+            let endpoint = escape(endpoint);
+            for (le, n) in LATENCY_BUCKETS.iter().zip(h.buckets) {
+                let _ = writeln!(
+                    out,
+                    "solcast_proxy_upstream_latency_seconds_bucket{{endpoint=\"{endpoint}\",le=\"{le}\"}} {n}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "solcast_proxy_upstream_latency_seconds_bucket{{endpoint=\"{endpoint}\",le=\"+Inf\"}} {}",
+                h.count
+            );
+            let _ = writeln!(
+                out,
+                "solcast_proxy_upstream_latency_seconds_sum{{endpoint=\"{endpoint}\"}} {}",
+                h.sum
+            );
+            let _ = writeln!(
+                out,
+                "solcast_proxy_upstream_latency_seconds_count{{endpoint=\"{endpoint}\"}} {}",
+                h.count
+            );
+        }
+
+        header(
+            &mut out,
+            "solcast_proxy_fallback_attempts_total",
+            "counter",
+            "Fallback account attempts by result",
+        );
+        for ((site, account, result), n) in self.fallback_attempts.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "solcast_proxy_fallback_attempts_total{{site=\"{}\",account=\"{}\",result=\"{}\"}} {}",
+                escape(site),
+                escape(account),
+                escape(result),
+                n
+            );
+        }
+
+        header(
+            &mut out,
+            "solcast_proxy_fallback_healthy",
+            "gauge",
+            "Whether a fallback account is in rotation (1) or backing off (0)",
+        );
+        for (account, healthy) in self.fallback_healthy.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "solcast_proxy_fallback_healthy{{account=\"{}\"}} {}",
+                escape(account),
+                u8::from(*healthy)
+            );
+        }
+
+        header(
+            &mut out,
+            "solcast_proxy_upstream_rate_limit_remaining",
+            "gauge",
+            "Last x-rate-limit-remaining reported upstream, per account hash",
+        );
+        for (account, remaining) in self.rate_limit_remaining.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "solcast_proxy_upstream_rate_limit_remaining{{account=\"{}\"}} {}",
+                escape(account),
+                remaining
+            );
+        }
+
+        let entries = cache.summaries().await;
+        header(
+            &mut out,
+            "solcast_proxy_cache_entries",
+            "gauge",
+            "Number of cached entries",
+        );
+        let _ = writeln!(out, "solcast_proxy_cache_entries {}", entries.len());
+        header(
+            &mut out,
+            "solcast_proxy_cache_bytes",
+            "gauge",
+            "Total size of cached bodies",
+        );
+        let bytes: usize = entries.iter().map(|e| e.bytes).sum();
+        let _ = writeln!(out, "solcast_proxy_cache_bytes {bytes}");
+        header(
+            &mut out,
+            "solcast_proxy_last_fetch_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful upstream fetch per cache key",
+        );
+        for e in &entries {
+            let _ = writeln!(
+                out,
+                "solcast_proxy_last_fetch_timestamp_seconds{{key=\"{}\"}} {}",
+                escape(&e.key),
+                e.fetched_at.timestamp()
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escape a label value per the Prometheus text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_render() {
+        let dir = TempDir::new().unwrap();
+        let cache = ProxyCache::new(dir.path(), 0);
+        cache
+            .set(
+                "site1",
+                "forecasts",
+                "{}".into(),
+                "application/json".into(),
+                None,
+            )
+            .await;
+
+        let metrics = Metrics::new();
+        metrics.record_cache_result("site1", "forecasts", "HIT");
+        metrics.record_cache_result("site1", "forecasts", "HIT");
+        metrics.record_cache_result("site1", "forecasts", "MISS");
+        metrics.record_upstream("forecasts", Some(200), Duration::from_millis(300));
+        metrics.record_upstream("forecasts", Some(429), Duration::from_millis(50));
+        metrics.record_upstream("forecasts", None, Duration::from_secs(30));
+        metrics.record_fallback("site1", "backup", "success");
+        metrics.set_fallback_healthy("backup", false);
+        metrics.set_rate_limit_remaining("abcd", 7);
+
+        let out = metrics.render(&cache).await;
+        assert!(out.contains(
+            "solcast_proxy_cache_responses_total{site=\"site1\",endpoint=\"forecasts\",status=\"HIT\"} 2"
+        ));
+        assert!(out.contains(
+            "solcast_proxy_upstream_responses_total{endpoint=\"forecasts\",status=\"429\"} 1"
+        ));
+        assert!(out.contains(
+            "solcast_proxy_upstream_responses_total{endpoint=\"forecasts\",status=\"error\"} 1"
+        ));
+        assert!(out.contains("solcast_proxy_upstream_rate_limited_total{endpoint=\"forecasts\"} 1"));
+        assert!(out.contains(
+            "solcast_proxy_upstream_latency_seconds_bucket{endpoint=\"forecasts\",le=\"0.1\"} 1"
+        ));
+        assert!(out.contains(
+            "solcast_proxy_upstream_latency_seconds_bucket{endpoint=\"forecasts\",le=\"0.5\"} 2"
+        ));
+        assert!(out.contains(
+            "solcast_proxy_upstream_latency_seconds_bucket{endpoint=\"forecasts\",le=\"+Inf\"} 3"
+        ));
+        assert!(out.contains(
+            "solcast_proxy_fallback_attempts_total{site=\"site1\",account=\"backup\",result=\"success\"} 1"
+        ));
+        assert!(out.contains("solcast_proxy_fallback_healthy{account=\"backup\"} 0"));
+        assert!(out.contains("solcast_proxy_upstream_rate_limit_remaining{account=\"abcd\"} 7"));
+        assert!(out.contains("solcast_proxy_cache_entries 1"));
+        assert!(out.contains("solcast_proxy_cache_bytes 2"));
+        assert!(out.contains("solcast_proxy_last_fetch_timestamp_seconds{key=\"site1:forecasts\"}"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
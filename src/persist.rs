@@ -0,0 +1,153 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::Utc;
+
+/// Distinguishes the temporary files of concurrent writes.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// `write_atomic_blocking` on the blocking thread pool.
+pub async fn write_atomic(path: &Path, data: Vec<u8>) -> io::Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || write_atomic_blocking(&path, &data))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Replace `path` with `data` so that a crash leaves either the old or the
+/// new contents, never a truncated file: write a temporary file next to it,
+/// flush it to disk, and rename it into place.
+pub fn write_atomic_blocking(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = sibling(
+        path,
+        &format!("tmp-{}", TMP_COUNTER.fetch_add(1, Ordering::Relaxed)),
+    );
+    let result = (|| {
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result?;
+    // Make the rename itself durable
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        std::fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Shift `path.1` .. `path.{keep-1}` up by one and hard-link the current
+/// `path` as `path.1`, keeping the last `keep` versions of the file. The
+/// current file stays in place, so it is never missing.
+pub fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 || !path.try_exists()? {
+        return Ok(());
+    }
+    for n in (1..keep).rev() {
+        let from = snapshot_path(path, n);
+        if from.try_exists()? {
+            std::fs::rename(&from, snapshot_path(path, n + 1))?;
+        }
+    }
+    let first = snapshot_path(path, 1);
+    match std::fs::remove_file(&first) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    std::fs::hard_link(path, &first)
+}
+
+/// The `n`th most recent rotated copy of `path`, from 1.
+pub fn snapshot_path(path: &Path, n: usize) -> PathBuf {
+    sibling(path, &n.to_string())
+}
+
+/// Move an unreadable file out of the way, so it isn't overwritten and can
+/// be inspected, and say so loudly. Returns where it went.
+pub fn quarantine(path: &Path, reason: &str) -> Option<PathBuf> {
+    let dest = sibling(
+        path,
+        &format!("corrupt-{}", Utc::now().format("%Y%m%dT%H%M%SZ")),
+    );
+    match std::fs::rename(path, &dest) {
+        Ok(()) => {
+            tracing::error!(
+                "{} is unreadable ({}), moved to {}",
+                path.display(),
+                reason,
+                dest.display()
+            );
+            Some(dest)
+        }
+        Err(e) => {
+            tracing::error!(
+                "{} is unreadable ({}) and could not be moved aside: {}",
+                path.display(),
+                reason,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// `path` with `.suffix` appended to its file name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_write_atomic_and_rotate() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+
+        for i in 1..=4 {
+            rotate(&path, 2).unwrap();
+            write_atomic(&path, format!("v{i}").into_bytes())
+                .await
+                .unwrap();
+        }
+        let read = |p: PathBuf| std::fs::read_to_string(p).unwrap();
+        assert_eq!(read(path.clone()), "v4");
+        assert_eq!(read(snapshot_path(&path, 1)), "v3");
+        assert_eq!(read(snapshot_path(&path, 2)), "v2");
+        assert!(!snapshot_path(&path, 3).exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, "{trunc").unwrap();
+
+        let dest = quarantine(&path, "test").unwrap();
+        assert!(!path.exists());
+        assert!(dest
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("state.json.corrupt-"));
+        assert_eq!(std::fs::read_to_string(dest).unwrap(), "{trunc");
+    }
+}
@@ -1,34 +1,79 @@
 use std::sync::Arc;
 
 use axum::extract::{Path, Query, State};
-use axum::http::{HeaderMap, StatusCode};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
 
+use crate::access::Isolation;
+use crate::archive::parse_time;
+use crate::cache::CacheEntry;
+use crate::cache_control::Directives;
+use crate::compress::{Body, Encoding};
+use crate::endpoints::Target;
+use crate::fallback::{AttemptResult, FallbackAccount};
+use crate::forecast::Window;
+use crate::format::Format;
+use crate::quota::key_hash;
+use crate::ratelimit::RateLimitHeaders;
 use crate::AppState;
 
 enum UpstreamResult {
-    Success { body: String, content_type: String },
-    RateLimited,
-    Error { status: StatusCode, body: String },
+    Success {
+        body: String,
+        content_type: String,
+        /// When upstream said the body stops being fresh, if it did.
+        expires_at: Option<DateTime<Utc>>,
+    },
+    /// A 429, with when upstream said the key may retry, if it did.
+    RateLimited {
+        retry_at: Option<DateTime<Utc>>,
+    },
+    Error {
+        status: StatusCode,
+        body: String,
+    },
 }
 
-struct FallbackCredentials {
-    api_key: String,
-    site_id: String,
+/// Outcome of an upstream refresh, shared by every request coalesced onto it.
+#[derive(Clone)]
+pub enum FetchOutcome {
+    /// Fresh data is in the cache (`MISS`, `FALLBACK`, or `HIT` if another
+    /// flight got there first).
+    Fetched {
+        entry: CacheEntry,
+        cache_status: &'static str,
+        age: i64,
+        /// Fallback accounts tried, in order; the last one returned the
+        /// data. Empty unless `cache_status` is `FALLBACK`.
+        fallbacks: Vec<String>,
+    },
+    /// No data obtained because of rate limiting, either our own limiter
+    /// (`upstream: false`) or Solcast's (`upstream: true`). Fetching may
+    /// resume at `retry_at`.
+    RateLimited {
+        upstream: bool,
+        retry_at: DateTime<Utc>,
+    },
+    /// The API key's daily budget is spent; no upstream call was made.
+    QuotaExhausted,
+    /// It is night at the site; fetching resumes at `until`.
+    Night { until: DateTime<Utc> },
+    /// Upstream returned a non-success status.
+    Error { status: StatusCode, body: String },
+    /// The upstream request itself failed (connect error, timeout, ...).
+    Failed(String),
 }
 
-/// Make a single upstream request with explicit site_id and api_key.
+/// Make a single upstream request for `path` with an explicit api_key.
 async fn fetch_upstream(
     state: &AppState,
-    site_id: &str,
+    path: &str,
     endpoint: &str,
     api_key: &str,
     params: &[(String, String)],
 ) -> Result<UpstreamResult, reqwest::Error> {
-    let url = format!(
-        "{}/rooftop_sites/{}/{}",
-        state.upstream_url, site_id, endpoint
-    );
+    let url = format!("{}{}", state.upstream_url, path);
 
     let mut req = state
         .client
@@ -39,8 +84,30 @@ async fn fetch_upstream(
         req = req.query(params);
     }
 
-    let response = req.send().await?;
+    let started = std::time::Instant::now();
+    let response = match req.send().await {
+        Ok(r) => r,
+        Err(e) => {
+            state
+                .metrics
+                .record_upstream(endpoint, None, started.elapsed());
+            return Err(e);
+        }
+    };
     let status = response.status();
+    state
+        .metrics
+        .record_upstream(endpoint, Some(status.as_u16()), started.elapsed());
+    let now = Utc::now();
+    let limits = RateLimitHeaders::parse(response.headers(), now);
+    if let Some(remaining) = limits.remaining {
+        state
+            .metrics
+            .set_rate_limit_remaining(&key_hash(api_key), remaining);
+    }
+    let rate_limited = status == StatusCode::TOO_MANY_REQUESTS;
+    let blocked_until = state.limits.observe(api_key, &limits, rate_limited, now);
+    let expires_at = upstream_expiry(response.headers(), now);
     let content_type = response
         .headers()
         .get("Content-Type")
@@ -48,10 +115,11 @@ async fn fetch_upstream(
         .unwrap_or("application/json")
         .to_string();
 
-    if status == StatusCode::TOO_MANY_REQUESTS {
-        let rl_info = extract_rate_limit_headers(response.headers());
-        tracing::warn!("{}/{}: upstream 429{}", site_id, endpoint, rl_info);
-        return Ok(UpstreamResult::RateLimited);
+    if rate_limited {
+        tracing::warn!("{}: upstream 429{}", path, limits);
+        return Ok(UpstreamResult::RateLimited {
+            retry_at: blocked_until,
+        });
     }
 
     if !status.is_success() {
@@ -59,105 +127,304 @@ async fn fetch_upstream(
         return Ok(UpstreamResult::Error { status, body });
     }
 
-    let rl_info = extract_rate_limit_headers(response.headers());
-    if !rl_info.is_empty() {
-        tracing::info!("{}/{}: upstream OK{}", site_id, endpoint, rl_info);
+    if let Some(until) = blocked_until {
+        tracing::warn!(
+            "{}: upstream OK{}, no calls left until {}",
+            path,
+            limits,
+            until
+        );
+    } else if limits != RateLimitHeaders::default() {
+        tracing::info!("{}: upstream OK{}", path, limits);
     }
 
     let body = response.text().await?;
-    Ok(UpstreamResult::Success { body, content_type })
+    Ok(UpstreamResult::Success {
+        body,
+        content_type,
+        expires_at,
+    })
+}
+
+/// Longest freshness upstream headers are taken to grant.
+const MAX_UPSTREAM_LIFETIME: i64 = 365 * 86400;
+
+/// When upstream's `Cache-Control` or `Expires` says a response stops being
+/// fresh, if it says. `s-maxage` is for shared caches like this one and wins
+/// over `max-age`, which wins over `Expires` (RFC 9111 4.2.1). `no-cache`
+/// and `no-store` are not honoured: serving stale data and sparing the
+/// quota both depend on keeping the response.
+fn upstream_expiry(headers: &HeaderMap, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    let date = |v: &str| {
+        DateTime::parse_from_rfc2822(v)
+            .ok()
+            .map(|t| t.with_timezone(&Utc))
+    };
+    let directive = |name: &str| {
+        header("Cache-Control")?.split(',').find_map(|d| {
+            let (key, value) = d.trim().split_once('=')?;
+            if !key.trim().eq_ignore_ascii_case(name) {
+                return None;
+            }
+            value.trim().trim_matches('"').parse::<i64>().ok()
+        })
+    };
+    let lifetime = match directive("s-maxage").or_else(|| directive("max-age")) {
+        // Time already spent in caches upstream counts against it
+        Some(max_age) => max_age - header("Age").and_then(|v| v.parse().ok()).unwrap_or(0),
+        None => {
+            let expires = header("Expires")?;
+            // An invalid date, such as "0", means already expired
+            let Some(expires) = date(expires) else {
+                return Some(now);
+            };
+            // Relative to upstream's clock, not ours
+            let sent = header("Date").and_then(date).unwrap_or(now);
+            (expires - sent).num_seconds()
+        }
+    };
+    Some(now + chrono::Duration::seconds(lifetime.clamp(0, MAX_UPSTREAM_LIFETIME)))
+}
+
+/// Try each fallback account in turn, skipping those backing off after
+/// recent failures, until one returns data. `reason` is what went wrong with
+/// the primary key, for the logs.
+async fn try_fallbacks(
+    state: &AppState,
+    fallbacks: &[FallbackAccount],
+    target: &Target,
+    reason: &str,
+) -> Option<FetchOutcome> {
+    let (site, endpoint) = (&target.site, &target.endpoint);
+    let mut tried = Vec::new();
+    for account in fallbacks {
+        if !state.fallbacks.is_healthy(account) {
+            tracing::info!(
+                "{}/{}: fallback {} skipped, backing off",
+                site,
+                endpoint,
+                account.name
+            );
+            state
+                .metrics
+                .record_fallback(site, &account.name, "unhealthy");
+            continue;
+        }
+        tried.push(account.name.clone());
+        tracing::info!(
+            "{}/{}: {}, trying fallback {}",
+            site,
+            endpoint,
+            reason,
+            account.name
+        );
+        if let Some(entry) = try_fallback(state, account, target).await {
+            tracing::info!(
+                "{}/{}: FALLBACK (fetched {}B)",
+                site,
+                endpoint,
+                entry.body.len()
+            );
+            return Some(FetchOutcome::Fetched {
+                entry,
+                cache_status: "FALLBACK",
+                age: 0,
+                fallbacks: tried,
+            });
+        }
+    }
+    None
 }
 
-/// Try the fallback account. Returns Some(Response) on success, None if unavailable/failed.
+/// Fetch from one fallback account, caching the body under the original
+/// site. Returns the new entry, `None` if unavailable or failed.
 async fn try_fallback(
     state: &AppState,
-    fallback: &FallbackCredentials,
-    rooftop_id: &str,
-    endpoint: &str,
-    cache_endpoint: &str,
-    params: &[(String, String)],
-) -> Option<Response> {
+    fallback: &FallbackAccount,
+    target: &Target,
+) -> Option<CacheEntry> {
+    let (site, endpoint) = (&target.site, &target.endpoint);
+    let Some(path) = target.fallback_path(&fallback.site_id) else {
+        tracing::info!("{}/{}: no fallback for account resources", site, endpoint);
+        return None;
+    };
+    let cache_endpoint = target.cache_endpoint();
     let fb_rate_key = format!("fallback:{}", fallback.site_id);
+    let rate_limit = state.rate_limit_for(site, endpoint);
 
     if !state
         .cache
-        .can_fetch(&fb_rate_key, cache_endpoint, state.rate_limit)
+        .can_fetch(&fb_rate_key, &cache_endpoint, rate_limit)
         .await
+        || state
+            .limits
+            .blocked_until(&fallback.api_key, Utc::now())
+            .is_some()
     {
-        tracing::info!("{}/{}: fallback also rate limited", rooftop_id, endpoint);
+        tracing::info!(
+            "{}/{}: fallback {} also rate limited",
+            site,
+            endpoint,
+            fallback.name
+        );
+        state
+            .metrics
+            .record_fallback(site, &fallback.name, "rate_limited");
         return None;
     }
 
-    tracing::info!(
-        "{}/{}: primary 429, trying fallback site",
-        rooftop_id,
-        endpoint
-    );
-    state.cache.mark_attempt(&fb_rate_key, cache_endpoint).await;
+    if !state.quota.try_consume(&fallback.api_key).await {
+        tracing::info!(
+            "{}/{}: fallback {} daily quota spent",
+            site,
+            endpoint,
+            fallback.name
+        );
+        state.metrics.record_fallback(site, &fallback.name, "quota");
+        return None;
+    }
+    state
+        .cache
+        .mark_attempt(&fb_rate_key, &cache_endpoint)
+        .await;
 
-    match fetch_upstream(
+    let (result, backoff) = match fetch_upstream(
         state,
-        &fallback.site_id,
+        &path,
         endpoint,
         &fallback.api_key,
-        params,
+        &target.upstream_params(),
     )
     .await
     {
-        Ok(UpstreamResult::Success { body, content_type }) => {
+        Ok(UpstreamResult::Success {
+            body,
+            content_type,
+            expires_at,
+        }) => {
             // Cache under the ORIGINAL site ID's key
+            let entry = store(state, target, body, content_type, expires_at).await;
             state
-                .cache
-                .set(
-                    rooftop_id,
-                    cache_endpoint,
-                    body.clone(),
-                    content_type.clone(),
-                )
-                .await;
-            tracing::info!(
-                "{}/{}: FALLBACK (fetched {}B)",
-                rooftop_id,
-                endpoint,
-                body.len()
-            );
-            Some(cached_response(&body, &content_type, "FALLBACK", 0))
+                .metrics
+                .record_fallback(site, &fallback.name, "success");
+            record_health(state, fallback, AttemptResult::Success);
+            return Some(entry);
         }
-        Ok(UpstreamResult::RateLimited) => {
-            tracing::warn!("{}/{}: fallback also 429", rooftop_id, endpoint);
+        Ok(UpstreamResult::RateLimited { retry_at }) => {
             state
-                .cache
-                .mark_failed_attempt(&fb_rate_key, cache_endpoint, state.rate_limit, 3600)
-                .await;
-            None
+                .metrics
+                .record_fallback(site, &fallback.name, "rate_limited");
+            tracing::warn!("{}/{}: fallback {} also 429", site, endpoint, fallback.name);
+            (
+                AttemptResult::RateLimited { until: retry_at },
+                rate_limited_backoff(state, retry_at),
+            )
         }
         Ok(UpstreamResult::Error { status, body }) => {
+            state.metrics.record_fallback(site, &fallback.name, "error");
             tracing::error!(
-                "{}/{}: fallback error {} - {}",
-                rooftop_id,
+                "{}/{}: fallback {} error {} - {}",
+                site,
                 endpoint,
+                fallback.name,
                 status,
                 body
             );
-            state
-                .cache
-                .mark_failed_attempt(&fb_rate_key, cache_endpoint, state.rate_limit, 60)
-                .await;
-            None
+            (AttemptResult::Error, state.backoff.error_backoff)
         }
         Err(e) => {
-            tracing::error!("{}/{}: fallback fetch failed: {}", rooftop_id, endpoint, e);
             state
-                .cache
-                .mark_failed_attempt(&fb_rate_key, cache_endpoint, state.rate_limit, 60)
-                .await;
-            None
+                .metrics
+                .record_fallback(site, &fallback.name, "failed");
+            tracing::error!(
+                "{}/{}: fallback {} fetch failed: {}",
+                site,
+                endpoint,
+                fallback.name,
+                e
+            );
+            (AttemptResult::Error, state.backoff.error_backoff)
+        }
+    };
+    state
+        .cache
+        .mark_failed_attempt(&fb_rate_key, &cache_endpoint, rate_limit, backoff)
+        .await;
+    record_health(state, fallback, result);
+    None
+}
+
+/// When our own rate limit next allows fetching an entry, at least a
+/// second from now.
+async fn next_fetch_at(
+    state: &AppState,
+    site: &str,
+    cache_endpoint: &str,
+    rate_limit: u64,
+) -> DateTime<Utc> {
+    let wait = state.cache.retry_in(site, cache_endpoint, rate_limit).await;
+    Utc::now() + chrono::Duration::seconds(wait.max(1) as i64)
+}
+
+/// Seconds to hold off after a 429: until upstream's retry time, or the
+/// configured backoff if it gave none.
+fn rate_limited_backoff(state: &AppState, retry_at: Option<DateTime<Utc>>) -> u64 {
+    match retry_at {
+        Some(at) => (at - Utc::now()).num_seconds().max(1) as u64,
+        None => state.backoff.rate_limited_backoff,
+    }
+}
+
+fn record_health(state: &AppState, account: &FallbackAccount, result: AttemptResult) {
+    state.fallbacks.record(account, result);
+    state
+        .metrics
+        .set_fallback_healthy(&account.name, state.fallbacks.is_healthy(account));
+}
+
+/// Cache a successful upstream body, archive any estimated actuals in it and
+/// keep a snapshot of plain forecasts for `?as_of=`. Returns the new entry.
+async fn store(
+    state: &AppState,
+    target: &Target,
+    body: String,
+    content_type: String,
+    expires_at: Option<DateTime<Utc>>,
+) -> CacheEntry {
+    // With validated isolation, a successful fetch has just validated the
+    // key, so it refreshes the shared entry rather than the key's own
+    let cache_endpoint = match state.isolation {
+        Isolation::Validated => target.shared_cache_endpoint(),
+        _ => target.cache_endpoint(),
+    };
+    match target.endpoint.as_str() {
+        "estimated_actuals" => {
+            state.archive.record(&target.site, &body).await;
+        }
+        "forecasts" if target.params.is_empty() => {
+            state.history.record(&target.site, &body, Utc::now()).await;
         }
+        _ => {}
     }
+    state
+        .cache
+        .set(
+            &target.site,
+            &cache_endpoint,
+            body,
+            content_type,
+            expires_at,
+        )
+        .await
 }
 
-/// Extract fallback credentials from request headers.
-fn extract_fallback(headers: &HeaderMap) -> Option<FallbackCredentials> {
+/// Extract a fallback account from request headers.
+fn extract_fallback(headers: &HeaderMap) -> Option<FallbackAccount> {
     let api_key = headers
         .get("X-Fallback-Api-Key")?
         .to_str()
@@ -168,220 +435,2272 @@ fn extract_fallback(headers: &HeaderMap) -> Option<FallbackCredentials> {
         .to_str()
         .ok()?
         .to_string();
-    Some(FallbackCredentials { api_key, site_id })
+    Some(FallbackAccount {
+        name: "request".to_string(),
+        api_key,
+        site_id,
+    })
+}
+
+/// The client's API key from its `Authorization: Bearer` header.
+fn bearer_key(headers: &HeaderMap) -> String {
+    headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("")
+        .to_string()
+}
+
+/// The API key a client's request is made with: its own, or if it sent
+/// none the key the proxy holds for the rooftop site, if it is a
+/// configured one. The site listing never uses a server-held key.
+pub fn client_key(state: &AppState, rooftop_id: Option<&str>, headers: &HeaderMap) -> String {
+    let key = bearer_key(headers);
+    if !key.is_empty() {
+        return key;
+    }
+    rooftop_id
+        .and_then(|id| state.credentials.client_key_for(id))
+        .unwrap_or_default()
 }
 
-/// Handle proxied requests to Solcast API with caching.
+/// Handle proxied requests to `/rooftop_sites/{rooftop_id}/{endpoint}`.
 pub async fn proxy_handler(
     State(state): State<Arc<AppState>>,
     Path((rooftop_id, endpoint)): Path<(String, String)>,
+    Query(mut params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(pos) = params.iter().position(|(k, _)| k == "as_of") {
+        let (_, as_of) = params.remove(pos);
+        return history_response(&state, &rooftop_id, &endpoint, &as_of, params, &headers).await;
+    }
+    handle(state, params, headers, |params| {
+        Target::rooftop(&rooftop_id, &endpoint, params)
+    })
+    .await
+}
+
+/// Handle the rooftop site listing, `/rooftop_sites`.
+pub async fn site_list_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    let api_key = client_key(&state, None, &headers);
+    handle(state, params, headers, |params| {
+        Some(Target::site_list(&api_key, params))
+    })
+    .await
+}
+
+/// Handle site metadata, `/rooftop_sites/{rooftop_id}`.
+pub async fn site_handler(
+    State(state): State<Arc<AppState>>,
+    Path(rooftop_id): Path<String>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    handle(state, params, headers, |params| {
+        Some(Target::site(&rooftop_id, params))
+    })
+    .await
+}
+
+/// Handle `/data/{forecast,live}/{resource}`.
+pub async fn data_handler(
+    State(state): State<Arc<AppState>>,
+    Path((group, resource)): Path<(String, String)>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    handle(state, params, headers, |params| {
+        Target::data(&group, &resource, params)
+    })
+    .await
+}
+
+/// Handle `/world_radiation/{resource}`.
+pub async fn world_radiation_handler(
+    State(state): State<Arc<AppState>>,
+    Path(resource): Path<String>,
     Query(params): Query<Vec<(String, String)>>,
     headers: HeaderMap,
 ) -> Response {
-    // Validate endpoint
-    if endpoint != "forecasts" && endpoint != "estimated_actuals" {
+    handle(state, params, headers, |params| {
+        Target::world_radiation(&resource, params)
+    })
+    .await
+}
+
+/// Serve the forecast snapshot that was current at `as_of`, sliced and
+/// rendered as if it had been requested then. Never goes upstream.
+async fn history_response(
+    state: &AppState,
+    rooftop_id: &str,
+    endpoint: &str,
+    as_of: &str,
+    mut params: Vec<(String, String)>,
+    headers: &HeaderMap,
+) -> Response {
+    if endpoint != "forecasts" {
+        return (
+            StatusCode::BAD_REQUEST,
+            "as_of is only supported for forecasts",
+        )
+            .into_response();
+    }
+    let as_of = match parse_time(as_of) {
+        Ok(t) => t,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let format = match Format::negotiate(&mut params, headers) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let Some(target) = Target::rooftop(rooftop_id, endpoint, params) else {
         return (StatusCode::NOT_FOUND, "Unknown endpoint").into_response();
+    };
+    if let Some((name, _)) = target.params.first() {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("as_of cannot be combined with '{name}'"),
+        )
+            .into_response();
     }
-
-    // Build cache key including query params for uniqueness
-    let cache_endpoint = if params.is_empty() {
-        endpoint.clone()
-    } else {
-        let qs: Vec<String> = params.iter().map(|(k, v)| format!("{k}={v}")).collect();
-        format!("{}?{}", endpoint, qs.join("&"))
+    if !state
+        .may_read(rooftop_id, &client_key(state, Some(rooftop_id), headers))
+        .await
+    {
+        return (StatusCode::FORBIDDEN, "API key not validated for this site").into_response();
+    }
+    let Some((fetched_at, body)) = state.history.at(rooftop_id, as_of).await else {
+        tracing::info!("{}/forecasts: no snapshot as of {}", rooftop_id, as_of);
+        return (StatusCode::NOT_FOUND, "No forecast snapshot at that time").into_response();
     };
+    tracing::info!(
+        "{}/forecasts: HISTORY as of {} (fetched {})",
+        rooftop_id,
+        as_of,
+        fetched_at
+    );
+    state
+        .metrics
+        .record_cache_result(rooftop_id, "forecasts", "HISTORY");
+    let view = View {
+        window: target.window,
+        format,
+        encoding: Encoding::Identity,
+        ttl: 0,
+        directives: Directives::default(),
+    };
+    let (body, content_type) = view.render(&body, "application/json", as_of);
+    (
+        StatusCode::OK,
+        [
+            ("Content-Type", content_type.to_string()),
+            ("X-Cache", "HISTORY".to_string()),
+            ("X-Snapshot-Time", fetched_at.to_rfc3339()),
+        ],
+        body,
+    )
+        .into_response()
+}
 
-    // Cache-Control: no-cache bypasses both TTL and rate limit
-    let force_refresh = headers
-        .get("Cache-Control")
-        .and_then(|v| v.to_str().ok())
-        .is_some_and(|v| v.contains("no-cache"));
+/// The cache partition a client reads and refreshes for a site: its own
+/// with `key` isolation, and with `validated` isolation until Solcast has
+/// accepted its key for the site.
+async fn partition(state: &AppState, site: &str, api_key: &str) -> Option<String> {
+    match state.isolation {
+        Isolation::Shared => None,
+        Isolation::Key => Some(key_hash(api_key)),
+        Isolation::Validated if state.keys.is_known(site, api_key).await => None,
+        Isolation::Validated => Some(key_hash(api_key)),
+    }
+}
 
-    // Check if cache is fresh (skipped on force refresh)
-    if !force_refresh
-        && state
-            .cache
-            .is_fresh(&rooftop_id, &cache_endpoint, state.ttl)
-            .await
+/// Negotiate the response format, build the target from the remaining
+/// params, then serve it from cache or upstream and count the result.
+async fn handle(
+    state: Arc<AppState>,
+    mut params: Vec<(String, String)>,
+    headers: HeaderMap,
+    target: impl FnOnce(Vec<(String, String)>) -> Option<Target>,
+) -> Response {
+    // Upstream is always asked for JSON; other formats are rendered locally
+    let format = match Format::negotiate(&mut params, &headers) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let Some(mut target) = target(params) else {
+        return (StatusCode::NOT_FOUND, "Unknown endpoint").into_response();
+    };
+    let api_key = client_key(&state, Some(&target.site), &headers);
+    target.partition = partition(&state, &target.site, &api_key).await;
+    let (site, endpoint) = (target.site.clone(), target.endpoint.clone());
+    let response = serve(state.clone(), target, format, headers).await;
+    if let Some(status) = response
+        .headers()
+        .get("X-Cache")
+        .and_then(|v| v.to_str().ok())
     {
-        if let Some((entry, age)) = state.cache.get(&rooftop_id, &cache_endpoint).await {
-            tracing::info!("{}/{}: HIT (age {}s)", rooftop_id, endpoint, age);
-            return cached_response(&entry.body, &entry.content_type, "HIT", age);
-        }
+        state.metrics.record_cache_result(&site, &endpoint, status);
     }
+    response
+}
 
-    if force_refresh {
-        tracing::info!("{}/{}: cache bust requested", rooftop_id, endpoint);
-    }
+async fn serve(
+    state: Arc<AppState>,
+    target: Target,
+    format: Format,
+    headers: HeaderMap,
+) -> Response {
+    let site = target.site.clone();
+    let endpoint = target.endpoint.clone();
+    let cache_endpoint = target.cache_endpoint();
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    let directives = Directives::parse(header("Cache-Control"));
+    let view = View {
+        window: target.window,
+        format,
+        encoding: Encoding::negotiate(header("Accept-Encoding")),
+        ttl: effective_ttl(&state, &target),
+        directives,
+    };
 
-    let fallback = extract_fallback(&headers);
+    // Cache-Control: no-cache bypasses both TTL and rate limit
+    let force_refresh = directives.no_cache;
 
-    // Cache is stale or missing — check rate limit (skipped on force refresh)
-    if !force_refresh
-        && !state
-            .cache
-            .can_fetch(&rooftop_id, &cache_endpoint, state.rate_limit)
-            .await
-    {
-        // Primary rate limited — try fallback before serving stale
-        if let Some(fb) = &fallback {
-            if let Some(resp) =
-                try_fallback(&state, fb, &rooftop_id, &endpoint, &cache_endpoint, &params).await
+    // Check if cache is fresh enough for the client (skipped on force refresh)
+    if !force_refresh {
+        if let Some((entry, age)) = fresh_entry(&state, &target, directives).await {
+            tracing::info!("{}/{}: HIT (age {}s)", site, endpoint, age);
+            return cached_response(&entry, view, &headers, "HIT", age);
+        }
+        if let Some((entry, age)) = state.cache.get(&site, &cache_endpoint).await {
+            let lifetime = entry.lifetime(view.ttl);
+            if directives.accepts_stale(age, lifetime) && within_stale_limit(&state, age, lifetime)
             {
-                state
-                    .cache
-                    .mark_failed_attempt(&rooftop_id, &cache_endpoint, state.rate_limit, 3600)
-                    .await;
-                return resp;
+                tracing::info!("{}/{}: STALE (age {}s, max-stale)", site, endpoint, age);
+                return cached_response(&entry, view, &headers, "STALE", age);
             }
         }
+    }
 
-        // Fallback unavailable — serve stale if available
-        if let Some((entry, age)) = state.cache.get(&rooftop_id, &cache_endpoint).await {
-            tracing::info!(
-                "{}/{}: STALE (age {}s, rate limited)",
-                rooftop_id,
-                endpoint,
-                age
-            );
-            return cached_response(&entry.body, &entry.content_type, "STALE", age);
-        }
-        tracing::warn!("{}/{}: rate limited, no cached data", rooftop_id, endpoint);
+    if directives.only_if_cached {
+        tracing::info!(
+            "{}/{}: nothing suitable cached (only-if-cached)",
+            site,
+            endpoint
+        );
         return (
-            StatusCode::TOO_MANY_REQUESTS,
-            [("Retry-After", "9000")],
-            "Rate limited and no cached data available",
+            StatusCode::GATEWAY_TIMEOUT,
+            "No suitable cached response (only-if-cached)",
         )
             .into_response();
     }
 
-    // Extract API key from Authorization header
-    let api_key = headers
-        .get("Authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.strip_prefix("Bearer "))
-        .unwrap_or("");
+    if force_refresh {
+        tracing::info!("{}/{}: cache bust requested", site, endpoint);
+    }
 
-    // Fetch upstream (mark attempt to prevent concurrent hammering; clear on failure)
-    state.cache.mark_attempt(&rooftop_id, &cache_endpoint).await;
-    tracing::info!("{}/{}: fetching upstream", rooftop_id, endpoint);
+    // Concurrent misses on the same key share one upstream round trip. Forced
+    // refreshes get their own flight so they never inherit a rate-limited
+    // result, and clients wanting fresher data than the TTL theirs, so they
+    // aren't handed the entry they turned down.
+    let flight_key = if force_refresh {
+        format!("{}#no-cache", target.flight_key())
+    } else if directives.wants_fresher() {
+        format!(
+            "{}#max-age={:?},min-fresh={:?}",
+            target.flight_key(),
+            directives.max_age,
+            directives.min_fresh
+        )
+    } else {
+        target.flight_key()
+    };
+    // The client's own fallback account goes first, then the site's pool.
+    // A key Solcast hasn't accepted yet can't have a fallback account's data
+    // stored under this site for others.
+    let fallbacks = match state.isolation {
+        Isolation::Validated if target.partition.is_some() => Vec::new(),
+        _ => extract_fallback(&headers)
+            .into_iter()
+            .chain(state.fallbacks.accounts_for(&site))
+            .collect(),
+    };
+    let request = UpstreamRequest {
+        target,
+        api_key: client_key(&state, Some(&site), &headers),
+        fallbacks,
+        force_refresh,
+        directives,
+    };
 
-    match fetch_upstream(&state, &rooftop_id, &endpoint, api_key, &params).await {
-        Ok(UpstreamResult::Success { body, content_type }) => {
-            state
-                .cache
-                .set(
-                    &rooftop_id,
-                    &cache_endpoint,
-                    body.clone(),
-                    content_type.clone(),
-                )
-                .await;
-            tracing::info!(
-                "{}/{}: MISS (fetched {}B)",
-                rooftop_id,
-                endpoint,
-                body.len()
-            );
-            cached_response(&body, &content_type, "MISS", 0)
-        }
-        Ok(UpstreamResult::RateLimited) => {
-            // Primary returned 429 — try fallback
-            if let Some(fb) = &fallback {
-                if let Some(resp) =
-                    try_fallback(&state, fb, &rooftop_id, &endpoint, &cache_endpoint, &params).await
-                {
-                    state
-                        .cache
-                        .mark_failed_attempt(&rooftop_id, &cache_endpoint, state.rate_limit, 3600)
+    // Past its TTL but within the stale-while-revalidate window: answer at
+    // once and let the refresh finish in the background
+    if !force_refresh && state.stale_while_revalidate > 0 {
+        if let Some((entry, age)) = stale_entry(&state, &site, &cache_endpoint, view).await {
+            let window = entry
+                .lifetime(view.ttl)
+                .saturating_add(state.stale_while_revalidate);
+            if (age.max(0) as u64) < window {
+                tracing::info!("{}/{}: STALE (age {}s, revalidating)", site, endpoint, age);
+                let background = state.clone();
+                tokio::spawn(async move {
+                    background
+                        .inflight
+                        .run(flight_key, refresh(background.clone(), request))
                         .await;
-                    return resp;
-                }
+                });
+                return cached_response(&entry, view, &headers, "STALE", age);
             }
+        }
+    }
 
-            // Fallback unavailable or failed — fall through to stale cache
-            state
-                .cache
-                .mark_failed_attempt(&rooftop_id, &cache_endpoint, state.rate_limit, 3600)
-                .await;
-            if let Some((entry, age)) = state.cache.get(&rooftop_id, &cache_endpoint).await {
-                return cached_response(&entry.body, &entry.content_type, "STALE", age);
+    let outcome = state
+        .inflight
+        .run(flight_key, refresh(state.clone(), request))
+        .await;
+
+    match outcome {
+        Some(FetchOutcome::Fetched {
+            entry,
+            cache_status,
+            age,
+            fallbacks,
+        }) => {
+            let mut response = cached_response(&entry, view, &headers, cache_status, age);
+            if let Some(account) = fallbacks.last() {
+                let headers = response.headers_mut();
+                // Names come from the config; one that isn't a valid header
+                // value is left out rather than failing the response
+                if let Ok(v) = HeaderValue::from_str(account) {
+                    headers.insert("X-Fallback-Account", v);
+                }
+                if let Ok(v) = HeaderValue::from_str(&fallbacks.join(", ")) {
+                    headers.insert("X-Fallback-Order", v);
+                }
             }
-            (StatusCode::TOO_MANY_REQUESTS, "Upstream rate limited").into_response()
+            response
         }
-        Ok(UpstreamResult::Error { status, body }) => {
-            tracing::error!(
-                "{}/{}: upstream error {} - {}",
-                rooftop_id,
-                endpoint,
-                status,
-                body
-            );
-            state
-                .cache
-                .mark_failed_attempt(&rooftop_id, &cache_endpoint, state.rate_limit, 60)
-                .await;
-            if let Some((entry, age)) = state.cache.get(&rooftop_id, &cache_endpoint).await {
+        Some(FetchOutcome::RateLimited {
+            upstream: false,
+            retry_at,
+        }) => {
+            // Fallback unavailable — serve stale if available
+            if let Some((entry, age)) = stale_entry(&state, &site, &cache_endpoint, view).await {
+                tracing::info!("{}/{}: STALE (age {}s, rate limited)", site, endpoint, age);
+                return cached_response(&entry, view, &headers, "STALE", age);
+            }
+            tracing::warn!("{}/{}: rate limited, no cached data", site, endpoint);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [("Retry-After", retry_after(retry_at))],
+                "Rate limited and no cached data available",
+            )
+                .into_response()
+        }
+        Some(FetchOutcome::RateLimited {
+            upstream: true,
+            retry_at,
+        }) => match stale_response(&state, &site, &cache_endpoint, view, &headers).await {
+            Some(resp) => resp,
+            None => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [("Retry-After", retry_after(retry_at))],
+                "Upstream rate limited",
+            )
+                .into_response(),
+        },
+        Some(FetchOutcome::QuotaExhausted) => {
+            if let Some((entry, age)) = stale_entry(&state, &site, &cache_endpoint, view).await {
                 tracing::info!(
-                    "{}/{}: serving stale after upstream error",
-                    rooftop_id,
-                    endpoint
+                    "{}/{}: STALE (age {}s, daily quota spent)",
+                    site,
+                    endpoint,
+                    age
                 );
-                return cached_response(&entry.body, &entry.content_type, "STALE", age);
+                return cached_response(&entry, view, &headers, "STALE", age);
             }
-            (status, body).into_response()
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [("Retry-After", secs_until_utc_midnight().to_string())],
+                "Daily quota spent and no cached data available",
+            )
+                .into_response()
         }
-        Err(e) => {
-            tracing::error!("{}/{}: upstream fetch failed: {}", rooftop_id, endpoint, e);
-            state
-                .cache
-                .mark_failed_attempt(&rooftop_id, &cache_endpoint, state.rate_limit, 60)
-                .await;
-            if let Some((entry, age)) = state.cache.get(&rooftop_id, &cache_endpoint).await {
+        Some(FetchOutcome::Night { until }) => {
+            if let Some((entry, age)) = stale_entry(&state, &site, &cache_endpoint, view).await {
                 tracing::info!(
-                    "{}/{}: serving stale after fetch error",
-                    rooftop_id,
-                    endpoint
+                    "{}/{}: STALE (age {}s, night until {})",
+                    site,
+                    endpoint,
+                    age,
+                    until
                 );
-                return cached_response(&entry.body, &entry.content_type, "STALE", age);
+                return cached_response(&entry, view, &headers, "STALE", age);
             }
             (
-                StatusCode::BAD_GATEWAY,
-                format!("Upstream fetch failed: {e}"),
+                StatusCode::TOO_MANY_REQUESTS,
+                [("Retry-After", retry_after(until))],
+                "No upstream fetches at night and no cached data available",
             )
                 .into_response()
         }
+        Some(FetchOutcome::Error { status, body }) => {
+            match stale_response(&state, &site, &cache_endpoint, view, &headers).await {
+                Some(resp) => {
+                    tracing::info!("{}/{}: serving stale after upstream error", site, endpoint);
+                    resp
+                }
+                None => (status, body).into_response(),
+            }
+        }
+        Some(FetchOutcome::Failed(e)) => {
+            match stale_response(&state, &site, &cache_endpoint, view, &headers).await {
+                Some(resp) => {
+                    tracing::info!("{}/{}: serving stale after fetch error", site, endpoint);
+                    resp
+                }
+                None => (
+                    StatusCode::BAD_GATEWAY,
+                    format!("Upstream fetch failed: {e}"),
+                )
+                    .into_response(),
+            }
+        }
+        None => {
+            tracing::error!("{}/{}: upstream fetch task aborted", site, endpoint);
+            match stale_response(&state, &site, &cache_endpoint, view, &headers).await {
+                Some(resp) => resp,
+                None => (StatusCode::BAD_GATEWAY, "Upstream fetch aborted").into_response(),
+            }
+        }
     }
 }
 
-fn extract_rate_limit_headers(headers: &reqwest::header::HeaderMap) -> String {
-    let mut parts = Vec::new();
-    for key in [
-        "x-rate-limit",
-        "x-rate-limit-remaining",
-        "x-rate-limit-reset",
-        "retry-after",
-    ] {
-        if let Some(v) = headers.get(key).and_then(|v| v.to_str().ok()) {
-            parts.push(format!("{}: {}", key, v));
-        }
+/// Refresh one entry on behalf of the background scheduler.
+///
+/// Goes through the same flight, quota and cache path as a client miss, but
+/// bypasses the TTL and per-endpoint rate limit since the schedule already
+/// spaces calls out. Joins any client fetch already in flight for the key.
+pub async fn scheduled_refresh(
+    state: &Arc<AppState>,
+    rooftop_id: &str,
+    endpoint: &str,
+    api_key: &str,
+) -> Option<FetchOutcome> {
+    let mut target = Target::rooftop(rooftop_id, endpoint, Vec::new())?;
+    target.partition = (state.isolation == Isolation::Key).then(|| key_hash(api_key));
+    let flight_key = target.flight_key();
+    let request = UpstreamRequest {
+        target,
+        api_key: api_key.to_string(),
+        fallbacks: Vec::new(),
+        force_refresh: true,
+        directives: Directives::default(),
+    };
+    state
+        .inflight
+        .run(flight_key, refresh(state.clone(), request))
+        .await
+}
+
+/// The cached entry for a key as a STALE response, if there is one the
+/// client accepts.
+async fn stale_response(
+    state: &AppState,
+    site: &str,
+    cache_endpoint: &str,
+    view: View,
+    headers: &HeaderMap,
+) -> Option<Response> {
+    let (entry, age) = stale_entry(state, site, cache_endpoint, view).await?;
+    Some(cached_response(&entry, view, headers, "STALE", age))
+}
+
+/// The cached entry for a key, if there is one the client accepts in place
+/// of fresh data upstream couldn't provide.
+async fn stale_entry(
+    state: &AppState,
+    site: &str,
+    cache_endpoint: &str,
+    view: View,
+) -> Option<(CacheEntry, i64)> {
+    let (entry, age) = state.cache.get(site, cache_endpoint).await?;
+    let lifetime = entry.lifetime(view.ttl);
+    (view.directives.accepts_fallback(age, lifetime) && within_stale_limit(state, age, lifetime))
+        .then_some((entry, age))
+}
+
+/// Whether an entry `age` seconds old that was fresh for `lifetime` seconds
+/// is recent enough to serve stale at all (`stale_if_error`).
+fn within_stale_limit(state: &AppState, age: i64, lifetime: u64) -> bool {
+    state
+        .stale_if_error
+        .is_none_or(|limit| (age.max(0) as u64) < lifetime.saturating_add(limit))
+}
+
+/// The cached entry for a target if it is fresh, also by the client's
+/// directives, and, for forecasts, covers the requested window.
+async fn fresh_entry(
+    state: &AppState,
+    target: &Target,
+    directives: Directives,
+) -> Option<(CacheEntry, i64)> {
+    let cache_endpoint = target.cache_endpoint();
+    let (entry, age) = state.cache.get(&target.site, &cache_endpoint).await?;
+    if !directives.accepts_fresh(age, entry.lifetime(effective_ttl(state, target))) {
+        return None;
     }
-    if parts.is_empty() {
-        String::new()
-    } else {
-        format!(" [{}]", parts.join(", "))
+    match &target.window {
+        Some(window) if !window.covered_by(&entry.body.text(), entry.fetched_at) => None,
+        _ => Some((entry, age)),
     }
 }
 
-fn cached_response(body: &str, content_type: &str, cache_status: &str, age: i64) -> Response {
-    (
+/// Everything needed to refresh one cache key from upstream.
+struct UpstreamRequest {
+    target: Target,
+    api_key: String,
+    /// Accounts tried in order when the primary key can't be used.
+    fallbacks: Vec<FallbackAccount>,
+    force_refresh: bool,
+    /// What the client accepts from the cache.
+    directives: Directives,
+}
+
+/// Refresh one cache key: rate limit check, upstream fetch, fallback and cache
+/// update. Runs as a single flight, so its outcome is shared by every request
+/// that was waiting on the same key.
+async fn refresh(state: Arc<AppState>, req: UpstreamRequest) -> FetchOutcome {
+    let UpstreamRequest {
+        target,
+        api_key,
+        fallbacks,
+        force_refresh,
+        directives,
+    } = req;
+    let (site, endpoint) = (&target.site, &target.endpoint);
+    let cache_endpoint = target.cache_endpoint();
+
+    // Another flight may have refreshed the entry while this request was
+    // between its freshness check and joining.
+    if !force_refresh {
+        if let Some((entry, age)) = fresh_entry(&state, &target, directives).await {
+            tracing::info!("{}/{}: HIT (age {}s)", site, endpoint, age);
+            return FetchOutcome::Fetched {
+                entry,
+                cache_status: "HIT",
+                age,
+                fallbacks: Vec::new(),
+            };
+        }
+    }
+
+    let rate_limit = state.rate_limit_for(site, endpoint);
+
+    // No upstream calls while it is dark at the site (skipped on force refresh)
+    if !force_refresh && target.follows_daylight() {
+        if let Some(until) = state.night.night_until(site, Utc::now()) {
+            return FetchOutcome::Night { until };
+        }
+    }
+
+    // Cache is stale or missing — check rate limit (skipped on force refresh)
+    if !force_refresh
+        && !state
+            .cache
+            .can_fetch(site, &cache_endpoint, rate_limit)
+            .await
+    {
+        // Primary rate limited — try fallbacks before serving stale
+        if !fallbacks.is_empty() {
+            if let Some(outcome) = try_fallbacks(&state, &fallbacks, &target, "rate limited").await
+            {
+                state
+                    .cache
+                    .mark_failed_attempt(
+                        site,
+                        &cache_endpoint,
+                        rate_limit,
+                        state.backoff.rate_limited_backoff,
+                    )
+                    .await;
+                return outcome;
+            }
+        }
+        return FetchOutcome::RateLimited {
+            upstream: false,
+            retry_at: next_fetch_at(&state, site, &cache_endpoint, rate_limit).await,
+        };
+    }
+
+    // Upstream has said this key gets nothing more until later; asking now
+    // would only earn another 429. This holds even for forced refreshes.
+    if let Some(until) = state.limits.blocked_until(&api_key, Utc::now()) {
+        tracing::info!(
+            "{}/{}: upstream rate limited this API key until {}",
+            site,
+            endpoint,
+            until
+        );
+        if let Some(outcome) = try_fallbacks(&state, &fallbacks, &target, "rate limited").await {
+            return outcome;
+        }
+        return FetchOutcome::RateLimited {
+            upstream: true,
+            retry_at: until,
+        };
+    }
+
+    // The account's daily budget is a hard limit, even for forced refreshes
+    if !state.quota.try_consume(&api_key).await {
+        tracing::warn!("{}/{}: daily quota spent for this API key", site, endpoint);
+        if let Some(outcome) = try_fallbacks(&state, &fallbacks, &target, "quota spent").await {
+            return outcome;
+        }
+        return FetchOutcome::QuotaExhausted;
+    }
+
+    // Fetch upstream (mark attempt to prevent concurrent hammering; clear on failure)
+    state.cache.mark_attempt(site, &cache_endpoint).await;
+    tracing::info!("{}/{}: fetching upstream", site, endpoint);
+
+    match fetch_upstream(
+        &state,
+        &target.path,
+        endpoint,
+        &api_key,
+        &target.upstream_params(),
+    )
+    .await
+    {
+        Ok(UpstreamResult::Success {
+            body,
+            content_type,
+            expires_at,
+        }) => {
+            if !api_key.is_empty() {
+                state.keys.record(site, &api_key).await;
+            }
+            tracing::info!("{}/{}: MISS (fetched {}B)", site, endpoint, body.len());
+            let entry = store(&state, &target, body, content_type, expires_at).await;
+            FetchOutcome::Fetched {
+                entry,
+                cache_status: "MISS",
+                age: 0,
+                fallbacks: Vec::new(),
+            }
+        }
+        Ok(UpstreamResult::RateLimited { retry_at }) => {
+            // Primary returned 429 — try fallbacks
+            let outcome = try_fallbacks(&state, &fallbacks, &target, "upstream 429").await;
+
+            // Fallbacks unavailable or failed — fall through to stale cache
+            let backoff = rate_limited_backoff(&state, retry_at);
+            state
+                .cache
+                .mark_failed_attempt(site, &cache_endpoint, rate_limit, backoff)
+                .await;
+            match outcome {
+                Some(outcome) => outcome,
+                None => FetchOutcome::RateLimited {
+                    upstream: true,
+                    retry_at: match retry_at {
+                        Some(at) => at,
+                        None => next_fetch_at(&state, site, &cache_endpoint, rate_limit).await,
+                    },
+                },
+            }
+        }
+        Ok(UpstreamResult::Error { status, body }) => {
+            tracing::error!(
+                "{}/{}: upstream error {} - {}",
+                site,
+                endpoint,
+                status,
+                body
+            );
+            state
+                .cache
+                .mark_failed_attempt(
+                    site,
+                    &cache_endpoint,
+                    rate_limit,
+                    state.backoff.error_backoff,
+                )
+                .await;
+            // A client error (bad key, bad site) would be the same with any
+            // account; a server error may be specific to this one
+            if status.is_server_error() {
+                let reason = format!("upstream {status}");
+                if let Some(outcome) = try_fallbacks(&state, &fallbacks, &target, &reason).await {
+                    return outcome;
+                }
+            }
+            FetchOutcome::Error { status, body }
+        }
+        Err(e) => {
+            tracing::error!("{}/{}: upstream fetch failed: {}", site, endpoint, e);
+            state
+                .cache
+                .mark_failed_attempt(
+                    site,
+                    &cache_endpoint,
+                    rate_limit,
+                    state.backoff.error_backoff,
+                )
+                .await;
+            if let Some(outcome) =
+                try_fallbacks(&state, &fallbacks, &target, "upstream unreachable").await
+            {
+                return outcome;
+            }
+            FetchOutcome::Failed(e.to_string())
+        }
+    }
+}
+
+/// Cache TTL for a site, stretched overnight so entries fetched before
+/// sunset stay fresh until fetching resumes.
+fn effective_ttl(state: &AppState, target: &Target) -> u64 {
+    let ttl = state.ttl_for(&target.site, &target.endpoint);
+    if !target.follows_daylight() {
+        return ttl;
+    }
+    match state.night.night_until(&target.site, Utc::now()) {
+        Some(until) => ttl + (until - Utc::now()).num_seconds().max(0) as u64,
+        None => ttl,
+    }
+}
+
+/// `Retry-After` value for a time fetching may resume, at least a second away.
+fn retry_after(at: DateTime<Utc>) -> String {
+    (at - Utc::now()).num_seconds().max(1).to_string()
+}
+
+/// Seconds until the next UTC midnight, when Solcast's daily quota resets.
+fn secs_until_utc_midnight() -> i64 {
+    let now = Utc::now();
+    let tomorrow = now.date_naive().succ_opt().unwrap_or(now.date_naive());
+    let midnight = tomorrow.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    (midnight - now).num_seconds().max(1)
+}
+
+/// What one client accepts from the cache and how a cached body is
+/// presented to it.
+#[derive(Clone, Copy)]
+struct View {
+    window: Option<Window>,
+    format: Format,
+    encoding: Encoding,
+    /// Seconds a fresh entry is declared fresh for, before upstream's expiry.
+    ttl: u64,
+    /// What the client accepts from the cache.
+    directives: Directives,
+}
+
+/// Rendered forecasts are reused for this many seconds. Solcast period ends
+/// fall on five-minute boundaries, so slicing gives the same periods
+/// anywhere within one of these.
+const VARIANT_SECS: i64 = 300;
+
+impl View {
+    /// Whether the stored body is served as it is.
+    fn is_identity(self) -> bool {
+        self.window.is_none() && self.format == Format::Json
+    }
+
+    /// Identifies what `render` gives at `now`, among an entry's variants.
+    fn variant_key(self, now: DateTime<Utc>) -> String {
+        format!(
+            "{:?}/{:?}/{}",
+            self.window,
+            self.format,
+            now.timestamp().div_euclid(VARIANT_SECS)
+        )
+    }
+
+    /// Start of the span of time `variant_key(now)` covers, the latest the
+    /// rendered view can have changed.
+    fn variant_start(now: DateTime<Utc>) -> DateTime<Utc> {
+        let secs = now.timestamp().div_euclid(VARIANT_SECS) * VARIANT_SECS;
+        DateTime::from_timestamp(secs, 0).unwrap_or(now)
+    }
+
+    /// Strong `ETag` for `body` as sent in this view's encoding, which
+    /// changes the bytes.
+    fn etag(self, body: &Body) -> String {
+        match self.encoding.header() {
+            Some(coding) => format!("\"{}-{}\"", body.hash(), coding),
+            None => format!("\"{}\"", body.hash()),
+        }
+    }
+
+    /// A JSON body sliced to the forecast window as seen at `now` and in the
+    /// client's format. Bodies that aren't JSON are passed through with their
+    /// upstream content type.
+    fn render<'a>(
+        self,
+        body: &str,
+        content_type: &'a str,
+        now: DateTime<Utc>,
+    ) -> (String, &'a str) {
+        let sliced = self.window.and_then(|window| window.slice(body, now));
+        let body = sliced.as_deref().unwrap_or(body);
+        match self.format {
+            Format::Json => (body.to_string(), content_type),
+            format => match format.render(body) {
+                Some(rendered) => (rendered, format.content_type()),
+                None => (body.to_string(), content_type),
+            },
+        }
+    }
+}
+
+/// A time as an HTTP date, as in `Last-Modified`.
+fn http_date(t: DateTime<Utc>) -> String {
+    t.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether the client already has this response: its `If-None-Match`
+/// lists `etag`, or failing that its `If-Modified-Since` is no earlier
+/// than `last_modified`.
+fn not_modified(request: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    let header = |name| {
+        request
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    if let Some(tags) = header("If-None-Match") {
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+    header("If-Modified-Since")
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        .is_some_and(|since| last_modified.timestamp() <= since.timestamp())
+}
+
+/// Serve a cached entry as the client asked for it, compressed if the
+/// client accepts it. Rendered views are kept with the entry, so a hit
+/// normally sends bytes that are already compressed, and a client that
+/// already has them gets a 304.
+fn cached_response(
+    entry: &CacheEntry,
+    view: View,
+    request: &HeaderMap,
+    cache_status: &str,
+    age: i64,
+) -> Response {
+    let (body, content_type, last_modified) = if view.is_identity() {
+        (
+            entry.body.clone(),
+            entry.content_type.clone(),
+            entry.fetched_at,
+        )
+    } else {
+        let now = Utc::now();
+        let (body, content_type) = entry.variants.get_or_insert(&view.variant_key(now), || {
+            let (body, content_type) = view.render(&entry.body.text(), &entry.content_type, now);
+            (body, content_type.to_string())
+        });
+        // Slicing may have dropped a period since the fetch
+        (
+            body,
+            content_type,
+            entry.fetched_at.max(View::variant_start(now)),
+        )
+    };
+    let etag = view.etag(&body);
+    // Stale and fallback responses get no freshness lifetime, which marks
+    // them stale (RFC 9111 4.2), and must not be served again unchecked
+    let (lifetime, cache_control) = match cache_status {
+        "HIT" | "MISS" => {
+            let lifetime = entry.lifetime(view.ttl);
+            (lifetime, format!("max-age={lifetime}"))
+        }
+        _ => (0, "max-age=0, must-revalidate".to_string()),
+    };
+    let expires = entry.fetched_at + chrono::Duration::seconds(lifetime as i64);
+    let validators = [
+        ("X-Cache", cache_status.to_string()),
+        ("X-Cache-Age", age.to_string()),
+        ("Age", age.max(0).to_string()),
+        ("Cache-Control", cache_control),
+        ("Expires", http_date(expires)),
+        ("Vary", "Accept-Encoding".to_string()),
+        ("Last-Modified", http_date(last_modified)),
+    ];
+    if not_modified(request, &etag, last_modified) {
+        return (StatusCode::NOT_MODIFIED, validators, [("ETag", etag)]).into_response();
+    }
+    let mut response = (
         StatusCode::OK,
-        [
-            ("Content-Type", content_type.to_string()),
-            ("X-Cache", cache_status.to_string()),
-            ("X-Cache-Age", age.to_string()),
-        ],
-        body.to_string(),
+        validators,
+        [("ETag", etag), ("Content-Type", content_type)],
+        body.encoded(view.encoding),
     )
-        .into_response()
+        .into_response();
+    if let Some(coding) = view.encoding.header() {
+        response
+            .headers_mut()
+            .insert("Content-Encoding", HeaderValue::from_static(coding));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_solcast::{forecast_body, serve_proxy, test_state, FakeResponse, FakeSolcast};
+    use crate::quota::QuotaLedger;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const FORECASTS: &str = "/rooftop_sites/site1/forecasts";
+
+    async fn get(url: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(url)
+            .bearer_auth("key1")
+            .send()
+            .await
+            .unwrap()
+    }
+
+    fn x_cache(resp: &reqwest::Response) -> &str {
+        resp.headers()
+            .get("X-Cache")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+    }
+
+    #[tokio::test]
+    async fn test_miss_then_hit() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(x_cache(&resp), "MISS");
+
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "HIT");
+        assert_eq!(upstream.request_count(), 1);
+
+        let req = &upstream.requests()[0];
+        assert_eq!(req.path, FORECASTS);
+        assert_eq!(req.authorization.as_deref(), Some("Bearer key1"));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_responses() {
+        use std::io::Read;
+
+        let upstream = FakeSolcast::start().await;
+        upstream.push(FORECASTS, FakeResponse::ok(forecast_body(8)));
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let state = Arc::new(state);
+        let proxy = serve_proxy(state.clone()).await;
+        let get_encoded = |accept_encoding: &'static str| {
+            reqwest::Client::new()
+                .get(format!("{proxy}{FORECASTS}"))
+                .bearer_auth("key1")
+                .header("Accept-Encoding", accept_encoding)
+                .send()
+        };
+
+        let plain = get(&format!("{proxy}{FORECASTS}")).await;
+        assert!(plain.headers().get("Content-Encoding").is_none());
+        assert_eq!(plain.headers()["Vary"], "Accept-Encoding");
+        let plain = plain.text().await.unwrap();
+
+        let resp = get_encoded("gzip").await.unwrap();
+        assert_eq!(x_cache(&resp), "HIT");
+        assert_eq!(resp.headers()["Content-Encoding"], "gzip");
+        let gzipped = resp.bytes().await.unwrap();
+        assert_eq!(crate::compress::gunzip(&gzipped).unwrap(), plain);
+
+        let resp = get_encoded("gzip, br").await.unwrap();
+        assert_eq!(resp.headers()["Content-Encoding"], "br");
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&resp.bytes().await.unwrap()[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, plain);
+
+        // The sliced view was compressed once and is reused by later hits
+        let resp = get_encoded("gzip").await.unwrap();
+        assert_eq!(resp.bytes().await.unwrap(), gzipped);
+        let (entry, _) = state.cache.get("site1", "forecasts").await.unwrap();
+        assert_eq!(entry.variants.len(), 1);
+        assert_eq!(upstream.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+        let get_with = |path: &str, headers: &[(&'static str, String)]| {
+            let mut req = reqwest::Client::new()
+                .get(format!("{proxy}{path}"))
+                .bearer_auth("key1");
+            for (name, value) in headers {
+                req = req.header(*name, value);
+            }
+            req.send()
+        };
+
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        let etag = resp.headers()["ETag"].to_str().unwrap().to_string();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+        assert!(resp.headers().contains_key("Last-Modified"));
+
+        let resp = get_with(FORECASTS, &[("If-None-Match", etag.clone())])
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(x_cache(&resp), "HIT");
+        assert_eq!(resp.headers()["ETag"], etag.as_str());
+        assert!(resp.bytes().await.unwrap().is_empty());
+
+        let resp = get_with(FORECASTS, &[("If-None-Match", "\"other\"".into())])
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The gzipped bytes are a different representation
+        let resp = get_with(
+            FORECASTS,
+            &[
+                ("If-None-Match", etag.clone()),
+                ("Accept-Encoding", "gzip".into()),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_ne!(resp.headers()["ETag"], etag.as_str());
+
+        let actuals = "/rooftop_sites/site1/estimated_actuals";
+        let resp = get(&format!("{proxy}{actuals}")).await;
+        let last_modified = resp.headers()["Last-Modified"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let resp = get_with(actuals, &[("If-Modified-Since", last_modified)])
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        let resp = get_with(
+            actuals,
+            &[("If-Modified-Since", "Thu, 01 Jan 2026 00:00:00 GMT".into())],
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(upstream.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_freshness_headers() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+        let header = |resp: &reqwest::Response, name: &str| {
+            resp.headers()[name].to_str().unwrap().to_string()
+        };
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "HIT");
+        assert_eq!(header(&resp, "Cache-Control"), "max-age=7200");
+        assert!(header(&resp, "Age").parse::<u64>().unwrap() < 2);
+        let expires = DateTime::parse_from_rfc2822(&header(&resp, "Expires")).unwrap();
+        let left = (expires.with_timezone(&Utc) - Utc::now()).num_seconds();
+        assert!((7190..=7200).contains(&left), "{left}");
+
+        // Upstream's shorter max-age caps the TTL
+        let actuals = "/rooftop_sites/site1/estimated_actuals";
+        upstream.push(
+            actuals,
+            FakeResponse::ok("{}").with_header("Cache-Control", "public, max-age=60"),
+        );
+        let resp = get(&format!("{proxy}{actuals}")).await;
+        assert_eq!(header(&resp, "Cache-Control"), "max-age=60");
+    }
+
+    #[tokio::test]
+    async fn test_stale_marked_stale() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        upstream.push(
+            FORECASTS,
+            FakeResponse::status(StatusCode::INTERNAL_SERVER_ERROR, "boom"),
+        );
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "STALE");
+        assert_eq!(
+            resp.headers()["Cache-Control"],
+            "max-age=0, must-revalidate"
+        );
+        assert!(resp.headers().contains_key("Age"));
+    }
+
+    #[test]
+    fn test_upstream_expiry() {
+        let now = DateTime::parse_from_rfc3339("2026-06-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let expiry = |headers: &[(&'static str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(*name, value.parse().unwrap());
+            }
+            upstream_expiry(&map, now).map(|t| (t - now).num_seconds())
+        };
+        assert_eq!(expiry(&[]), None);
+        assert_eq!(expiry(&[("cache-control", "no-cache")]), None);
+        assert_eq!(expiry(&[("cache-control", "max-age=300")]), Some(300));
+        assert_eq!(
+            expiry(&[("cache-control", "max-age=300, s-maxage=60")]),
+            Some(60)
+        );
+        assert_eq!(
+            expiry(&[("cache-control", "max-age=300"), ("age", "100")]),
+            Some(200)
+        );
+        // Expires is measured against upstream's Date, not our clock
+        assert_eq!(
+            expiry(&[
+                ("expires", "Mon, 01 Jun 2026 10:30:00 GMT"),
+                ("date", "Mon, 01 Jun 2026 10:00:00 GMT"),
+            ]),
+            Some(1800)
+        );
+        assert_eq!(expiry(&[("expires", "0")]), Some(0));
+    }
+
+    fn get_cache_control(
+        url: &str,
+        cache_control: &str,
+    ) -> impl std::future::Future<Output = reqwest::Result<reqwest::Response>> {
+        reqwest::Client::new()
+            .get(url)
+            .bearer_auth("key1")
+            .header("Cache-Control", cache_control)
+            .send()
+    }
+
+    #[tokio::test]
+    async fn test_client_freshness_directives() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+        let url = format!("{proxy}{FORECASTS}");
+
+        let resp = get_cache_control(&url, "only-if-cached").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(upstream.request_count(), 0);
+
+        get(&url).await;
+        let resp = get_cache_control(&url, "only-if-cached").await.unwrap();
+        assert_eq!(x_cache(&resp), "HIT");
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let resp = get_cache_control(&url, "max-age=60").await.unwrap();
+        assert_eq!(x_cache(&resp), "HIT");
+        let resp = get_cache_control(&url, "max-age=0").await.unwrap();
+        assert_eq!(x_cache(&resp), "MISS");
+        assert_eq!(upstream.request_count(), 2);
+
+        // Fresh, but not for another two hours
+        let resp = get_cache_control(&url, "min-fresh=7200").await.unwrap();
+        assert_eq!(x_cache(&resp), "MISS");
+        assert_eq!(upstream.request_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_client_stale_directives() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+        let url = format!("{proxy}{FORECASTS}");
+
+        get(&url).await;
+        let resp = get_cache_control(&url, "max-stale").await.unwrap();
+        assert_eq!(x_cache(&resp), "STALE");
+        assert_eq!(upstream.request_count(), 1);
+        let resp = get_cache_control(&url, "only-if-cached").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(upstream.request_count(), 1);
+
+        // Too old to fall back on for a client that set a max-age
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        upstream.push(
+            FORECASTS,
+            FakeResponse::status(StatusCode::INTERNAL_SERVER_ERROR, "boom"),
+        );
+        let resp = get_cache_control(&url, "max-age=0").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        upstream.push(
+            FORECASTS,
+            FakeResponse::status(StatusCode::INTERNAL_SERVER_ERROR, "boom"),
+        );
+        let resp = get(&url).await;
+        assert_eq!(x_cache(&resp), "STALE");
+    }
+
+    #[tokio::test]
+    async fn test_stale_while_revalidate() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.stale_while_revalidate = 3600;
+        let state = Arc::new(state);
+        let proxy = serve_proxy(state.clone()).await;
+        let url = format!("{proxy}{FORECASTS}");
+
+        // Nothing cached yet: the client waits for upstream
+        let resp = get(&url).await;
+        assert_eq!(x_cache(&resp), "MISS");
+        let first = state.cache.get("site1", "forecasts").await.unwrap().0;
+
+        upstream.push(
+            FORECASTS,
+            FakeResponse::ok(forecast_body(4)).with_delay(Duration::from_millis(300)),
+        );
+        let started = std::time::Instant::now();
+        let resp = get(&url).await;
+        assert_eq!(x_cache(&resp), "STALE");
+        assert!(started.elapsed() < Duration::from_millis(250));
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(upstream.request_count(), 2);
+        let refreshed = state.cache.get("site1", "forecasts").await.unwrap().0;
+        assert!(refreshed.fetched_at > first.fetched_at);
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error_limit() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.stale_if_error = Some(0);
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        upstream.push(
+            FORECASTS,
+            FakeResponse::status(StatusCode::INTERNAL_SERVER_ERROR, "boom"),
+        );
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(resp.text().await.unwrap(), "boom");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_endpoint() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        let resp = get(&format!("{proxy}/rooftop_sites/site1/bogus")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(upstream.request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_query_params_forwarded_and_cached_separately() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        let actuals = "/rooftop_sites/site1/estimated_actuals";
+        get(&format!("{proxy}{actuals}?hours=24")).await;
+        get(&format!("{proxy}{actuals}?hours=48")).await;
+        let resp = get(&format!("{proxy}{actuals}?hours=24")).await;
+        assert_eq!(x_cache(&resp), "HIT");
+
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].query.as_deref(), Some("hours=24"));
+        assert_eq!(requests[1].query.as_deref(), Some("hours=48"));
+    }
+
+    #[tokio::test]
+    async fn test_forecast_windows_sliced_from_one_fetch() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+        upstream.push(FORECASTS, FakeResponse::ok(forecast_body(8)));
+
+        let forecasts = |body: String| -> Vec<serde_json::Value> {
+            let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+            value["forecasts"].as_array().unwrap().clone()
+        };
+
+        let resp = get(&format!("{proxy}{FORECASTS}?hours=1")).await;
+        assert_eq!(x_cache(&resp), "MISS");
+        assert_eq!(forecasts(resp.text().await.unwrap()).len(), 2);
+
+        let resp = get(&format!("{proxy}{FORECASTS}?hours=3&period=PT60M")).await;
+        assert_eq!(x_cache(&resp), "HIT");
+        let hourly = forecasts(resp.text().await.unwrap());
+        assert!(hourly.len() <= 3 && !hourly.is_empty());
+        assert!(hourly.iter().all(|p| p["period"] == "PT60M"));
+
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "HIT");
+        assert_eq!(forecasts(resp.text().await.unwrap()).len(), 8);
+
+        // Longer than anything fetched so far: one more upstream call
+        let resp = get(&format!("{proxy}{FORECASTS}?hours=336")).await;
+        assert_eq!(x_cache(&resp), "MISS");
+
+        let queries: Vec<Option<String>> =
+            upstream.requests().into_iter().map(|r| r.query).collect();
+        assert_eq!(
+            queries,
+            [Some("hours=168".to_string()), Some("hours=336".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stale_when_rate_limited() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.rate_limit = 9000;
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        assert_eq!(x_cache(&get(&format!("{proxy}{FORECASTS}")).await), "MISS");
+        // ttl=0 so the entry is expired, and the rate limit blocks a refetch
+        assert_eq!(x_cache(&get(&format!("{proxy}{FORECASTS}")).await), "STALE");
+        assert_eq!(upstream.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_without_cache_returns_429() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.rate_limit = 9000;
+        let state = Arc::new(state);
+        state.cache.mark_attempt("site1", "forecasts").await;
+        let proxy = serve_proxy(state).await;
+
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("Retry-After"));
+        assert_eq!(upstream.request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_no_cache_bypasses_ttl_and_rate_limit() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        state.rate_limit = 9000;
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}{FORECASTS}"))
+            .header("Cache-Control", "no-cache")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(x_cache(&resp), "MISS");
+        assert_eq!(upstream.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_429_serves_stale() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        upstream.push(FORECASTS, FakeResponse::rate_limited());
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(x_cache(&resp), "STALE");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_429_without_cache() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        upstream.push(FORECASTS, FakeResponse::rate_limited());
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    fn retry_after_secs(resp: &reqwest::Response) -> i64 {
+        resp.headers()["Retry-After"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upstream_retry_after_blocks_key() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        upstream.push(
+            FORECASTS,
+            FakeResponse::rate_limited().with_header("retry-after", "120"),
+        );
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!((119..=120).contains(&retry_after_secs(&resp)));
+
+        // Other sites on the same key wait too, without calling upstream,
+        // even when forced
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}/rooftop_sites/site2/forecasts"))
+            .bearer_auth("key1")
+            .header("Cache-Control", "no-cache")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!((119..=120).contains(&retry_after_secs(&resp)));
+        assert_eq!(upstream.request_count(), 1);
+
+        // Another key is unaffected
+        let resp = get_as(&format!("{proxy}/rooftop_sites/site2/forecasts"), "key2").await;
+        assert_eq!(x_cache(&resp), "MISS");
+
+        let health: serde_json::Value = get(&format!("{proxy}/health")).await.json().await.unwrap();
+        let limits = health["upstream_limits"].as_array().unwrap();
+        assert_eq!(limits.len(), 1);
+        assert_eq!(limits[0]["account"], key_hash("key1"));
+        assert_eq!(limits[0]["remaining"], 0);
+        assert_eq!(limits[0]["limit"], 10);
+        assert!(limits[0]["blocked_until"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_nothing_remaining_blocks_until_reset() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        let reset = (Utc::now() + chrono::Duration::seconds(600)).timestamp();
+        upstream.push(
+            FORECASTS,
+            FakeResponse::ok(forecast_body(2))
+                .with_header("x-rate-limit-remaining", "0")
+                .with_header("x-rate-limit-reset", &reset.to_string()),
+        );
+        assert_eq!(x_cache(&get(&format!("{proxy}{FORECASTS}")).await), "MISS");
+
+        // The last call was spent: stale data rather than a certain 429
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "STALE");
+        let resp = get(&format!("{proxy}/rooftop_sites/site2/forecasts")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!((599..=600).contains(&retry_after_secs(&resp)));
+        assert_eq!(upstream.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_429_uses_fallback() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let state = Arc::new(test_state(&upstream.url(), dir.path()));
+        let proxy = serve_proxy(state.clone()).await;
+
+        upstream.push(FORECASTS, FakeResponse::rate_limited());
+        upstream.push(
+            "/rooftop_sites/fb-site/forecasts",
+            FakeResponse::ok(forecast_body(2)),
+        );
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}{FORECASTS}"))
+            .bearer_auth("key1")
+            .header("X-Fallback-Api-Key", "fb-key")
+            .header("X-Fallback-Site-Id", "fb-site")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(x_cache(&resp), "FALLBACK");
+
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].path, "/rooftop_sites/fb-site/forecasts");
+        assert_eq!(requests[1].authorization.as_deref(), Some("Bearer fb-key"));
+
+        // Fallback body is cached under the original site
+        let (entry, _) = state.cache.get("site1", "forecasts").await.unwrap();
+        assert_eq!(entry.body.text(), resp.text().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_fallback_used_when_primary_locally_rate_limited() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.rate_limit = 9000;
+        let state = Arc::new(state);
+        state.cache.mark_attempt("site1", "forecasts").await;
+        let proxy = serve_proxy(state).await;
+
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}{FORECASTS}"))
+            .header("X-Fallback-Api-Key", "fb-key")
+            .header("X-Fallback-Site-Id", "fb-site")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(x_cache(&resp), "FALLBACK");
+        assert_eq!(upstream.request_count(), 1);
+        assert_eq!(
+            upstream.requests()[0].path,
+            "/rooftop_sites/fb-site/forecasts"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fallback_429_serves_stale() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        upstream.push(FORECASTS, FakeResponse::rate_limited());
+        upstream.push(
+            "/rooftop_sites/fb-site/forecasts",
+            FakeResponse::rate_limited(),
+        );
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}{FORECASTS}"))
+            .header("X-Fallback-Api-Key", "fb-key")
+            .header("X-Fallback-Site-Id", "fb-site")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(x_cache(&resp), "STALE");
+        assert_eq!(upstream.request_count(), 3);
+    }
+
+    fn with_fallback_pool(mut state: AppState, accounts: &[&str]) -> AppState {
+        use crate::config::{Config, FallbackConfig, SiteConfig};
+        let mut config = Config::default();
+        config.sites.push(SiteConfig {
+            fallbacks: accounts
+                .iter()
+                .map(|name| FallbackConfig {
+                    name: Some(name.to_string()),
+                    site_id: format!("{name}-site"),
+                    api_key: Some(format!("{name}-key")),
+                    api_key_file: None,
+                })
+                .collect(),
+            ..SiteConfig::new("site1")
+        });
+        state.fallbacks = crate::fallback::FallbackPool::from_config(&config);
+        state
+    }
+
+    #[tokio::test]
+    async fn test_fallback_pool_tried_in_order() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let state = with_fallback_pool(test_state(&upstream.url(), dir.path()), &["a", "b"]);
+        let state = Arc::new(state);
+        let proxy = serve_proxy(state.clone()).await;
+
+        upstream.push(FORECASTS, FakeResponse::rate_limited());
+        upstream.push(
+            "/rooftop_sites/a-site/forecasts",
+            FakeResponse::rate_limited(),
+        );
+        upstream.push(
+            "/rooftop_sites/b-site/forecasts",
+            FakeResponse::ok(forecast_body(2)),
+        );
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "FALLBACK");
+        assert_eq!(resp.headers()["X-Fallback-Account"], "b");
+        assert_eq!(resp.headers()["X-Fallback-Order"], "a, b");
+        let auth: Vec<_> = upstream
+            .requests()
+            .into_iter()
+            .map(|r| r.authorization.unwrap_or_default())
+            .collect();
+        assert_eq!(auth, ["Bearer key1", "Bearer a-key", "Bearer b-key"]);
+
+        let health = state.fallbacks.health();
+        assert_eq!(health.len(), 2);
+        assert!(!health[0].healthy, "a is backing off after its 429");
+        assert!(health[1].healthy);
+
+        // a is skipped while it backs off
+        upstream.push(FORECASTS, FakeResponse::rate_limited());
+        upstream.push(
+            "/rooftop_sites/b-site/forecasts",
+            FakeResponse::ok(forecast_body(2)),
+        );
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}{FORECASTS}"))
+            .bearer_auth("key1")
+            .header("Cache-Control", "no-cache")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()["X-Fallback-Order"], "b");
+        let metrics = get(&format!("{proxy}/metrics")).await.text().await.unwrap();
+        assert!(metrics.contains(r#"solcast_proxy_fallback_healthy{account="a"} 0"#));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_5xx_uses_fallback_pool() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let state = with_fallback_pool(test_state(&upstream.url(), dir.path()), &["a"]);
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        upstream.push(
+            FORECASTS,
+            FakeResponse::status(StatusCode::BAD_GATEWAY, "down"),
+        );
+        upstream.push(
+            "/rooftop_sites/a-site/forecasts",
+            FakeResponse::ok(forecast_body(2)),
+        );
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(x_cache(&resp), "FALLBACK");
+        assert_eq!(resp.headers()["X-Fallback-Account"], "a");
+
+        // A client error isn't the fallback account's to fix
+        upstream.push(
+            "/rooftop_sites/site2/forecasts",
+            FakeResponse::status(StatusCode::UNAUTHORIZED, "bad key"),
+        );
+        let resp = get(&format!("{proxy}/rooftop_sites/site2/forecasts")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(upstream.request_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_5xx() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        upstream.push(
+            FORECASTS,
+            FakeResponse::status(StatusCode::SERVICE_UNAVAILABLE, "down"),
+        );
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.text().await.unwrap(), "down");
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        upstream.push(
+            FORECASTS,
+            FakeResponse::status(StatusCode::INTERNAL_SERVER_ERROR, "boom"),
+        );
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(x_cache(&resp), "STALE");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeout() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        upstream.push(FORECASTS, FakeResponse::timeout(Duration::from_secs(2)));
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        upstream.push(FORECASTS, FakeResponse::timeout(Duration::from_secs(2)));
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "STALE");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_share_one_upstream_call() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        state.rate_limit = 9000;
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        upstream.push(
+            FORECASTS,
+            FakeResponse::ok(forecast_body(2)).with_delay(Duration::from_millis(200)),
+        );
+        let url = format!("{proxy}{FORECASTS}");
+        let responses = get_concurrently(&url, 5).await;
+        for resp in &responses {
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(x_cache(resp), "MISS");
+        }
+        assert_eq!(upstream.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_share_upstream_429() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        upstream.push(
+            FORECASTS,
+            FakeResponse::rate_limited().with_delay(Duration::from_millis(200)),
+        );
+        let url = format!("{proxy}{FORECASTS}");
+        let responses = get_concurrently(&url, 3).await;
+        for resp in &responses {
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        assert_eq!(upstream.request_count(), 1);
+    }
+
+    /// Fire `n` requests at `url` at once and collect the responses.
+    async fn get_concurrently(url: &str, n: usize) -> Vec<reqwest::Response> {
+        let handles: Vec<_> = (0..n)
+            .map(|_| {
+                let url = url.to_string();
+                tokio::spawn(async move { get(&url).await })
+            })
+            .collect();
+        let mut responses = Vec::new();
+        for h in handles {
+            responses.push(h.await.unwrap());
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_daily_budget_serves_stale_once_spent() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.quota = QuotaLedger::new(dir.path(), 2);
+        let state = Arc::new(state);
+        let proxy = serve_proxy(state.clone()).await;
+
+        // Two different sites on the same key share the account budget
+        get(&format!("{proxy}{FORECASTS}")).await;
+        get(&format!("{proxy}/rooftop_sites/site2/forecasts")).await;
+        assert_eq!(upstream.request_count(), 2);
+
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "STALE");
+        let resp = get(&format!("{proxy}/rooftop_sites/site1/estimated_actuals")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key("Retry-After"));
+        assert_eq!(upstream.request_count(), 2);
+        assert_eq!(state.quota.used_today("key1").await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_daily_budget_falls_back_to_other_account() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.quota = QuotaLedger::new(dir.path(), 1);
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        get(&format!("{proxy}{FORECASTS}")).await;
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}{FORECASTS}"))
+            .bearer_auth("key1")
+            .header("X-Fallback-Api-Key", "fb-key")
+            .header("X-Fallback-Site-Id", "fb-site")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(x_cache(&resp), "FALLBACK");
+        assert_eq!(upstream.request_count(), 2);
+    }
+
+    /// A location where it is currently around solar midnight.
+    fn night_state(upstream: &FakeSolcast, dir: &TempDir) -> AppState {
+        use chrono::Timelike;
+        let now = Utc::now();
+        let utc_hours = now.hour() as f64 + now.minute() as f64 / 60.0;
+        let longitude = ((24.0 - utc_hours) * 15.0 + 180.0).rem_euclid(360.0) - 180.0;
+        let mut locations = std::collections::HashMap::new();
+        locations.insert(
+            "site1".to_string(),
+            crate::solar::Location {
+                latitude: 0.0,
+                longitude,
+            },
+        );
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.night = crate::solar::NightPolicy::new(locations, chrono::Duration::minutes(30));
+        state
+    }
+
+    #[tokio::test]
+    async fn test_night_extends_ttl() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let state = Arc::new(night_state(&upstream, &dir));
+        state
+            .cache
+            .set(
+                "site1",
+                "forecasts",
+                "{}".into(),
+                "application/json".into(),
+                None,
+            )
+            .await;
+        let proxy = serve_proxy(state).await;
+
+        // ttl is 0, but overnight the entry stays fresh until fetching resumes
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(x_cache(&resp), "HIT");
+        assert_eq!(upstream.request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_night_suppresses_fetch() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(night_state(&upstream, &dir))).await;
+
+        let resp = get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: i64 = resp.headers()["Retry-After"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after > 3600, "retry after {retry_after}s");
+        assert_eq!(upstream.request_count(), 0);
+
+        // Sites without a location are unaffected
+        let resp = get(&format!("{proxy}/rooftop_sites/site2/forecasts")).await;
+        assert_eq!(x_cache(&resp), "MISS");
+
+        // An explicit no-cache still goes upstream
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}{FORECASTS}"))
+            .header("Cache-Control", "no-cache")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(x_cache(&resp), "MISS");
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        upstream.push(
+            FORECASTS,
+            FakeResponse::ok(forecast_body(2)).with_header("x-rate-limit-remaining", "8"),
+        );
+        get(&format!("{proxy}{FORECASTS}")).await;
+        get(&format!("{proxy}{FORECASTS}")).await;
+
+        let body = reqwest::get(format!("{proxy}/metrics"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(body.contains(
+            "solcast_proxy_cache_responses_total{site=\"site1\",endpoint=\"forecasts\",status=\"MISS\"} 1"
+        ));
+        assert!(body.contains(
+            "solcast_proxy_cache_responses_total{site=\"site1\",endpoint=\"forecasts\",status=\"HIT\"} 1"
+        ));
+        assert!(body.contains(
+            "solcast_proxy_upstream_responses_total{endpoint=\"forecasts\",status=\"200\"} 1"
+        ));
+        assert!(body.contains(&format!(
+            "solcast_proxy_upstream_rate_limit_remaining{{account=\"{}\"}} 8",
+            key_hash("key1")
+        )));
+        assert!(body.contains("solcast_proxy_cache_entries 1"));
+    }
+
+    #[tokio::test]
+    async fn test_located_endpoint_rounds_coordinates() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        let path = "/data/forecast/rooftop_pv_power";
+        let resp = get(&format!(
+            "{proxy}{path}?latitude=-33.8688&longitude=151.2093&capacity=5"
+        ))
+        .await;
+        assert_eq!(x_cache(&resp), "MISS");
+        // A few hundred metres away, parameters in another order
+        let resp = get(&format!(
+            "{proxy}{path}?capacity=5&longitude=151.2149&latitude=-33.871"
+        ))
+        .await;
+        assert_eq!(x_cache(&resp), "HIT");
+
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, path);
+        assert_eq!(
+            requests[0].query.as_deref(),
+            Some("capacity=5&hours=168&latitude=-33.87&longitude=151.21")
+        );
+
+        let resp = get(&format!(
+            "{proxy}/world_radiation/forecasts?latitude=1&longitude=2"
+        ))
+        .await;
+        assert_eq!(x_cache(&resp), "MISS");
+        assert_eq!(upstream.requests()[1].path, "/world_radiation/forecasts");
+
+        for bogus in [
+            "/data/history/rooftop_pv_power",
+            "/data/live/bogus",
+            "/world_radiation/live",
+        ] {
+            let resp = get(&format!("{proxy}{bogus}")).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{bogus}");
+        }
+        assert_eq!(upstream.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_site_listing_and_metadata() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        // Global ttl is 0: these endpoints use their own day-long default
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+
+        let list = format!("{proxy}/rooftop_sites");
+        assert_eq!(x_cache(&get(&list).await), "MISS");
+        assert_eq!(x_cache(&get(&list).await), "HIT");
+        // Each account sees its own sites
+        let resp = reqwest::Client::new()
+            .get(&list)
+            .bearer_auth("key2")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(x_cache(&resp), "MISS");
+
+        let site = format!("{proxy}/rooftop_sites/site1");
+        assert_eq!(x_cache(&get(&site).await), "MISS");
+        assert_eq!(x_cache(&get(&site).await), "HIT");
+
+        let paths: Vec<String> = upstream.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            ["/rooftop_sites", "/rooftop_sites", "/rooftop_sites/site1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_formats_share_one_upstream_call() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        let resp = get(&format!("{proxy}{FORECASTS}?format=json&hours=24")).await;
+        assert_eq!(x_cache(&resp), "MISS");
+        assert_eq!(resp.headers()["Content-Type"], "application/json");
+
+        let resp = get(&format!("{proxy}{FORECASTS}?hours=24&format=csv")).await;
+        assert_eq!(x_cache(&resp), "HIT");
+        assert_eq!(resp.headers()["Content-Type"], "text/csv; charset=utf-8");
+        let csv = resp.text().await.unwrap();
+        assert!(csv.starts_with("PvEstimate,PvEstimate10,PvEstimate90,PeriodEnd,Period\n"));
+        assert_eq!(csv.lines().count(), 5);
+
+        let resp = reqwest::Client::new()
+            .get(format!("{proxy}{FORECASTS}?hours=24"))
+            .header("Accept", "application/xml")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(x_cache(&resp), "HIT");
+        assert!(resp.text().await.unwrap().contains("<Forecasts><Forecast>"));
+
+        let resp = get(&format!("{proxy}{FORECASTS}?format=yaml")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].query.as_deref(), Some("hours=168"));
+    }
+
+    #[tokio::test]
+    async fn test_estimated_actuals_archived() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+        let actuals = "/rooftop_sites/site1/estimated_actuals";
+        let body = |ends: &[&str]| {
+            let items: Vec<serde_json::Value> = ends
+                .iter()
+                .map(|end| serde_json::json!({"pv_estimate": 1.0, "period_end": end, "period": "PT30M"}))
+                .collect();
+            serde_json::json!({ "estimated_actuals": items }).to_string()
+        };
+        upstream.push(
+            actuals,
+            FakeResponse::ok(body(&["2026-06-20T10:00:00Z", "2026-06-20T10:30:00Z"])),
+        );
+        upstream.push(
+            actuals,
+            FakeResponse::ok(body(&["2026-06-20T10:30:00Z", "2026-06-20T11:00:00Z"])),
+        );
+        get(&format!("{proxy}{actuals}")).await;
+        get(&format!("{proxy}{actuals}")).await;
+        assert_eq!(upstream.request_count(), 2);
+
+        let resp = get(&format!(
+            "{proxy}/archive/site1/actuals?start=2026-06-20T10:30:00Z&end=2026-06-21"
+        ))
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let value: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(value["estimated_actuals"].as_array().unwrap().len(), 2);
+
+        let resp = get(&format!("{proxy}/archive/site1/actuals?format=csv")).await;
+        assert_eq!(resp.text().await.unwrap().lines().count(), 4);
+        let resp = get(&format!("{proxy}/archive/site1/actuals?start=soon")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_forecast_history_as_of() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let proxy = serve_proxy(Arc::new(test_state(&upstream.url(), dir.path()))).await;
+        upstream.push(FORECASTS, FakeResponse::ok(forecast_body(4)));
+        get(&format!("{proxy}{FORECASTS}")).await;
+        assert_eq!(upstream.request_count(), 1);
+
+        let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let resp = get(&format!("{proxy}{FORECASTS}?as_of={now}")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(x_cache(&resp), "HISTORY");
+        assert!(resp.headers().contains_key("X-Snapshot-Time"));
+        let value: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(value["forecasts"].as_array().unwrap().len(), 4);
+
+        let resp = get(&format!(
+            "{proxy}{FORECASTS}?as_of={now}&hours=1&format=csv"
+        ))
+        .await;
+        assert_eq!(resp.text().await.unwrap().lines().count(), 3);
+
+        let resp = get(&format!("{proxy}{FORECASTS}?as_of=2026-01-01")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = get(&format!("{proxy}{FORECASTS}?as_of=yesterday")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = get(&format!(
+            "{proxy}/rooftop_sites/site1/estimated_actuals?as_of={now}"
+        ))
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        // Time travel never goes upstream
+        assert_eq!(upstream.request_count(), 1);
+    }
+
+    async fn get_as(url: &str, api_key: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(url)
+            .bearer_auth(api_key)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_key_isolation_partitions_cache() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 3600;
+        state.isolation = Isolation::Key;
+        let proxy = serve_proxy(Arc::new(state)).await;
+        let url = format!("{proxy}{FORECASTS}");
+
+        assert_eq!(x_cache(&get_as(&url, "key1").await), "MISS");
+        assert_eq!(x_cache(&get_as(&url, "key1").await), "HIT");
+        assert_eq!(x_cache(&get_as(&url, "key2").await), "MISS");
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].authorization.as_deref(), Some("Bearer key2"));
+
+        let archive = format!("{proxy}/archive/site1/actuals");
+        assert_eq!(
+            get_as(&archive, "key3").await.status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(get_as(&archive, "key1").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_validated_isolation_requires_accepted_key() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 3600;
+        state.rate_limit = 3600;
+        state.isolation = Isolation::Validated;
+        let proxy = serve_proxy(Arc::new(state)).await;
+        let url = format!("{proxy}{FORECASTS}");
+
+        assert_eq!(x_cache(&get_as(&url, "key1").await), "MISS");
+        assert_eq!(x_cache(&get_as(&url, "key1").await), "HIT");
+
+        // An unknown key goes upstream itself and never sees the cached body
+        upstream.push(
+            FORECASTS,
+            FakeResponse::status(StatusCode::FORBIDDEN, "Forbidden"),
+        );
+        let resp = get_as(&url, "guess").await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = get_as(&url, "guess").await;
+        assert_ne!(resp.status(), StatusCode::OK);
+        assert_eq!(upstream.request_count(), 2);
+
+        // Once Solcast accepts a key it shares the entry it refreshed
+        assert_eq!(x_cache(&get_as(&url, "key2").await), "MISS");
+        assert_eq!(x_cache(&get_as(&url, "key2").await), "HIT");
+        assert_eq!(x_cache(&get_as(&url, "key1").await), "HIT");
+        assert_eq!(upstream.request_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_server_held_key_used_when_client_sends_none() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut config = crate::config::Config::default();
+        config.site_mut("site1").api_key = Some("server-key".into());
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.credentials = crate::credentials::Credentials::from_config(&config);
+        let proxy = serve_proxy(Arc::new(state)).await;
+
+        let resp = reqwest::get(format!("{proxy}{FORECASTS}")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        get(&format!("{proxy}{FORECASTS}")).await;
+        // No server key for site2 and no default, so the request goes out bare
+        reqwest::get(format!("{proxy}/rooftop_sites/site2/forecasts"))
+            .await
+            .unwrap();
+
+        let auth: Vec<Option<String>> = upstream
+            .requests()
+            .into_iter()
+            .map(|r| r.authorization)
+            .collect();
+        assert_eq!(
+            auth,
+            [
+                Some("Bearer server-key".to_string()),
+                Some("Bearer key1".to_string()),
+                Some("Bearer".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_default_key_not_used_for_unconfigured_sites() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut config = crate::config::Config {
+            api_key: Some("default-key".into()),
+            ..Default::default()
+        };
+        config.site_mut("site1");
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 3600;
+        state.isolation = Isolation::Validated;
+        state.credentials = crate::credentials::Credentials::from_config(&config);
+        let proxy = serve_proxy(Arc::new(state)).await;
+        let site2 = format!("{proxy}/rooftop_sites/site2/forecasts");
+
+        assert_eq!(x_cache(&get_as(&site2, "key1").await), "MISS");
+        // A keyless client neither borrows the default key nor reads the
+        // entry key1 fetched
+        let resp = reqwest::get(&site2).await.unwrap();
+        assert_ne!(x_cache(&resp), "HIT");
+        let resp = reqwest::get(format!("{proxy}/archive/site2/actuals"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        reqwest::get(format!("{proxy}/rooftop_sites"))
+            .await
+            .unwrap();
+        // The configured site still gets it
+        reqwest::get(format!("{proxy}{FORECASTS}")).await.unwrap();
+
+        let auth: Vec<Option<String>> = upstream
+            .requests()
+            .into_iter()
+            .map(|r| r.authorization)
+            .collect();
+        assert_eq!(
+            auth,
+            [
+                Some("Bearer key1".to_string()),
+                Some("Bearer".to_string()),
+                Some("Bearer".to_string()),
+                Some("Bearer default-key".to_string()),
+            ]
+        );
+    }
 }
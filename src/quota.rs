@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, RwLock};
+
+use crate::persist;
+
+/// Upstream calls made by one account on one UTC day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccountUsage {
+    day: NaiveDate,
+    used: u32,
+}
+
+/// Serializable form for disk persistence.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DiskQuota {
+    accounts: HashMap<String, AccountUsage>,
+}
+
+/// Usage summary for one account, as reported by `/health`.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaUsage {
+    pub account: String,
+    pub day: NaiveDate,
+    pub used: u32,
+    pub remaining: u32,
+    pub budget: u32,
+}
+
+/// Stable, non-reversible identifier for an API key, safe to log and persist.
+pub fn key_hash(api_key: &str) -> String {
+    let digest = Sha256::digest(api_key.as_bytes());
+    digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Daily upstream call ledger per API key.
+///
+/// Solcast's limit applies per API key per UTC day, across every site and
+/// query on that key, so this is checked in addition to the per-endpoint
+/// rate limit in `ProxyCache`.
+pub struct QuotaLedger {
+    accounts: RwLock<HashMap<String, AccountUsage>>,
+    daily_budget: u32,
+    path: PathBuf,
+    /// Serializes writes to disk, so the newest counts are written last.
+    save_lock: Mutex<()>,
+}
+
+impl QuotaLedger {
+    /// Create a ledger, loading today's counters from disk if available.
+    pub fn new(cache_dir: &Path, daily_budget: u32) -> Self {
+        let path = cache_dir.join("quota.json");
+        let accounts = Self::load_from_disk(&path).unwrap_or_default();
+        Self {
+            accounts: RwLock::new(accounts),
+            daily_budget,
+            path,
+            save_lock: Mutex::new(()),
+        }
+    }
+
+    /// Reserve one upstream call for `api_key`. Returns false (and records
+    /// nothing) if today's budget is already spent.
+    pub async fn try_consume(&self, api_key: &str) -> bool {
+        let today = Utc::now().date_naive();
+        {
+            let mut accounts = self.accounts.write().await;
+            let usage = accounts.entry(key_hash(api_key)).or_insert(AccountUsage {
+                day: today,
+                used: 0,
+            });
+            if usage.day != today {
+                usage.day = today;
+                usage.used = 0;
+            }
+            if usage.used >= self.daily_budget {
+                return false;
+            }
+            usage.used += 1;
+        }
+        self.save_to_disk().await;
+        true
+    }
+
+    /// Calls `api_key` has made so far today.
+    pub async fn used_today(&self, api_key: &str) -> u32 {
+        let today = Utc::now().date_naive();
+        let accounts = self.accounts.read().await;
+        accounts
+            .get(&key_hash(api_key))
+            .filter(|u| u.day == today)
+            .map_or(0, |u| u.used)
+    }
+
+    pub fn daily_budget(&self) -> u32 {
+        self.daily_budget
+    }
+
+    /// Today's usage for every known account.
+    pub async fn usage(&self) -> Vec<QuotaUsage> {
+        let today = Utc::now().date_naive();
+        let accounts = self.accounts.read().await;
+        let mut usage: Vec<QuotaUsage> = accounts
+            .iter()
+            .map(|(account, u)| {
+                let used = if u.day == today { u.used } else { 0 };
+                QuotaUsage {
+                    account: account.clone(),
+                    day: today,
+                    used,
+                    remaining: self.daily_budget.saturating_sub(used),
+                    budget: self.daily_budget,
+                }
+            })
+            .collect();
+        usage.sort_by(|a, b| a.account.cmp(&b.account));
+        usage
+    }
+
+    async fn save_to_disk(&self) {
+        let _guard = self.save_lock.lock().await;
+        // Read under the lock, so a slower earlier write can't land last
+        let disk = DiskQuota {
+            accounts: self.accounts.read().await.clone(),
+        };
+        let json = match serde_json::to_string_pretty(&disk) {
+            Ok(j) => j,
+            Err(e) => {
+                tracing::error!("Failed to serialize quota ledger: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = persist::write_atomic(&self.path, json.into_bytes()).await {
+            tracing::error!(
+                "Failed to write quota ledger to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn load_from_disk(path: &Path) -> Option<HashMap<String, AccountUsage>> {
+        let data = std::fs::read_to_string(path).ok()?;
+        let disk: DiskQuota = serde_json::from_str(&data).ok()?;
+        Some(disk.accounts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_key_hash_is_stable_and_opaque() {
+        assert_eq!(key_hash("abc"), key_hash("abc"));
+        assert_ne!(key_hash("abc"), key_hash("abd"));
+        assert_eq!(key_hash("abc").len(), 16);
+        assert!(!key_hash("secret-key").contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_budget_enforced_per_key() {
+        let dir = TempDir::new().unwrap();
+        let ledger = QuotaLedger::new(dir.path(), 2);
+
+        assert!(ledger.try_consume("key1").await);
+        assert!(ledger.try_consume("key1").await);
+        assert!(!ledger.try_consume("key1").await);
+        assert_eq!(ledger.used_today("key1").await, 2);
+
+        // Other accounts are unaffected
+        assert!(ledger.try_consume("key2").await);
+    }
+
+    #[tokio::test]
+    async fn test_counters_persist() {
+        let dir = TempDir::new().unwrap();
+        {
+            let ledger = QuotaLedger::new(dir.path(), 10);
+            ledger.try_consume("key1").await;
+            ledger.try_consume("key1").await;
+        }
+        let ledger = QuotaLedger::new(dir.path(), 10);
+        assert_eq!(ledger.used_today("key1").await, 2);
+        let usage = ledger.usage().await;
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].account, key_hash("key1"));
+        assert_eq!(usage[0].remaining, 8);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_saves_keep_latest_count() {
+        let dir = TempDir::new().unwrap();
+        {
+            let ledger = std::sync::Arc::new(QuotaLedger::new(dir.path(), 100));
+            let tasks: Vec<_> = (0..20)
+                .map(|_| {
+                    let ledger = ledger.clone();
+                    tokio::spawn(async move { ledger.try_consume("key1").await })
+                })
+                .collect();
+            for task in tasks {
+                assert!(task.await.unwrap());
+            }
+        }
+        let ledger = QuotaLedger::new(dir.path(), 100);
+        assert_eq!(ledger.used_today("key1").await, 20);
+    }
+
+    #[tokio::test]
+    async fn test_previous_day_resets() {
+        let dir = TempDir::new().unwrap();
+        let yesterday = Utc::now().date_naive().pred_opt().unwrap();
+        let mut accounts = HashMap::new();
+        accounts.insert(
+            key_hash("key1"),
+            AccountUsage {
+                day: yesterday,
+                used: 10,
+            },
+        );
+        let disk = DiskQuota { accounts };
+        std::fs::write(
+            dir.path().join("quota.json"),
+            serde_json::to_string(&disk).unwrap(),
+        )
+        .unwrap();
+
+        let ledger = QuotaLedger::new(dir.path(), 10);
+        assert_eq!(ledger.used_today("key1").await, 0);
+        assert!(ledger.try_consume("key1").await);
+        assert_eq!(ledger.used_today("key1").await, 1);
+    }
+}
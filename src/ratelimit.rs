@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+
+use crate::quota::key_hash;
+
+/// Longest upstream is trusted to block a key for, whatever its headers
+/// say. Solcast's limits reset daily.
+const MAX_BLOCK_SECS: i64 = 86400;
+
+/// `x-rate-limit-reset` values below this are seconds from now rather than
+/// a Unix timestamp.
+const RESET_DELTA_MAX: i64 = 1_000_000_000;
+
+/// The rate-limit headers of one upstream response.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitHeaders {
+    /// `x-rate-limit`: calls allowed per period.
+    pub limit: Option<u32>,
+    /// `x-rate-limit-remaining`: calls left in the period.
+    pub remaining: Option<i64>,
+    /// `x-rate-limit-reset`: when the period ends.
+    pub reset: Option<DateTime<Utc>>,
+    /// `retry-after`, in seconds or as an HTTP date.
+    pub retry_after: Option<DateTime<Utc>>,
+}
+
+impl RateLimitHeaders {
+    pub fn parse(headers: &HeaderMap, now: DateTime<Utc>) -> Self {
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+        };
+        Self {
+            limit: get("x-rate-limit").and_then(|v| v.parse().ok()),
+            remaining: get("x-rate-limit-remaining").and_then(|v| v.parse().ok()),
+            reset: get("x-rate-limit-reset")
+                .and_then(|v| v.parse::<i64>().ok())
+                .and_then(|v| {
+                    if v < RESET_DELTA_MAX {
+                        Some(now + Duration::seconds(v))
+                    } else {
+                        DateTime::from_timestamp(v, 0)
+                    }
+                }),
+            retry_after: get("retry-after").and_then(|v| match v.parse::<i64>() {
+                Ok(secs) => Some(now + Duration::seconds(secs)),
+                Err(_) => DateTime::parse_from_rfc2822(v)
+                    .ok()
+                    .map(|t| t.with_timezone(&Utc)),
+            }),
+        }
+    }
+
+    /// When upstream will take calls on this key again, if these headers say
+    /// it won't now: `retry-after` after a 429, otherwise the reset time once
+    /// a 429 is seen or nothing remains. Past times are ignored.
+    pub fn blocked_until(&self, rate_limited: bool, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let until = if rate_limited {
+            self.retry_after.or(self.reset)
+        } else if self.remaining.is_some_and(|r| r <= 0) {
+            self.reset
+        } else {
+            None
+        }?;
+        (until > now).then(|| until.min(now + Duration::seconds(MAX_BLOCK_SECS)))
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for RateLimitHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(limit) = self.limit {
+            parts.push(format!("limit {limit}"));
+        }
+        if let Some(remaining) = self.remaining {
+            parts.push(format!("remaining {remaining}"));
+        }
+        if let Some(reset) = self.reset {
+            parts.push(format!("reset {}", reset.to_rfc3339()));
+        }
+        if let Some(retry_after) = self.retry_after {
+            parts.push(format!("retry after {}", retry_after.to_rfc3339()));
+        }
+        if parts.is_empty() {
+            Ok(())
+        } else {
+            write!(f, " [{}]", parts.join(", "))
+        }
+    }
+}
+
+/// What upstream last reported for one account, as reported by `/health`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountLimit {
+    pub account: String,
+    pub limit: Option<u32>,
+    pub remaining: Option<i64>,
+    pub reset: Option<DateTime<Utc>>,
+    /// No upstream calls are made with the key until then.
+    pub blocked_until: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Upstream rate-limit state per API key, from the headers of the latest
+/// response made with it.
+///
+/// A key that upstream has rate limited is blocked for every site and
+/// endpoint until upstream says it may retry, so other requests don't spend
+/// calls on certain 429s. Keys are held by hash only.
+#[derive(Default)]
+pub struct UpstreamLimits {
+    accounts: Mutex<HashMap<String, AccountLimit>>,
+}
+
+impl UpstreamLimits {
+    /// Record the headers of a response made with `api_key`, and return
+    /// when the key may be used again if upstream has blocked it.
+    pub fn observe(
+        &self,
+        api_key: &str,
+        headers: &RateLimitHeaders,
+        rate_limited: bool,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let blocked_until = headers.blocked_until(rate_limited, now);
+        if headers.is_empty() && !rate_limited {
+            return None;
+        }
+        let account = key_hash(api_key);
+        let mut accounts = self.accounts.lock().unwrap();
+        let entry = accounts
+            .entry(account.clone())
+            .or_insert_with(|| AccountLimit {
+                account,
+                limit: None,
+                remaining: None,
+                reset: None,
+                blocked_until: None,
+                updated_at: now,
+            });
+        entry.limit = headers.limit.or(entry.limit);
+        entry.remaining = headers.remaining.or(entry.remaining);
+        entry.reset = headers.reset.or(entry.reset);
+        entry.blocked_until = blocked_until;
+        entry.updated_at = now;
+        blocked_until
+    }
+
+    /// When `api_key` may be used again, if upstream has blocked it.
+    pub fn blocked_until(&self, api_key: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(&key_hash(api_key))
+            .and_then(|a| a.blocked_until)
+            .filter(|until| *until > now)
+    }
+
+    /// Every account upstream has reported on, sorted by account hash.
+    pub fn accounts(&self) -> Vec<AccountLimit> {
+        let mut out: Vec<AccountLimit> = self.accounts.lock().unwrap().values().cloned().collect();
+        out.sort_by(|a, b| a.account.cmp(&b.account));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (k, v) in pairs {
+            map.insert(*k, v.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn test_parse() {
+        let now = DateTime::from_timestamp(1_800_000_000, 0).unwrap();
+        let h = RateLimitHeaders::parse(
+            &headers(&[
+                ("x-rate-limit", "10"),
+                ("x-rate-limit-remaining", "0"),
+                ("x-rate-limit-reset", "1800003600"),
+                ("retry-after", "120"),
+            ]),
+            now,
+        );
+        assert_eq!(h.limit, Some(10));
+        assert_eq!(h.remaining, Some(0));
+        assert_eq!(h.reset, Some(now + Duration::seconds(3600)));
+        assert_eq!(h.retry_after, Some(now + Duration::seconds(120)));
+        assert_eq!(
+            h.to_string(),
+            " [limit 10, remaining 0, reset 2027-01-15T09:00:00+00:00, retry after 2027-01-15T08:02:00+00:00]"
+        );
+
+        let h = RateLimitHeaders::parse(
+            &headers(&[
+                ("x-rate-limit-reset", "60"),
+                ("retry-after", "Fri, 15 Jan 2027 08:30:00 GMT"),
+            ]),
+            now,
+        );
+        assert_eq!(h.reset, Some(now + Duration::seconds(60)));
+        assert_eq!(h.retry_after, Some(now + Duration::seconds(1800)));
+        assert!(RateLimitHeaders::parse(&HeaderMap::new(), now).is_empty());
+    }
+
+    #[test]
+    fn test_blocked_until() {
+        let now = Utc::now();
+        let h = RateLimitHeaders {
+            remaining: Some(0),
+            reset: Some(now + Duration::seconds(600)),
+            retry_after: Some(now + Duration::seconds(60)),
+            ..Default::default()
+        };
+        assert_eq!(h.blocked_until(true, now), h.retry_after);
+        // Nothing left: blocked until the reset even without a 429
+        assert_eq!(h.blocked_until(false, now), h.reset);
+
+        let h = RateLimitHeaders {
+            remaining: Some(3),
+            reset: Some(now + Duration::seconds(600)),
+            ..Default::default()
+        };
+        assert_eq!(h.blocked_until(false, now), None);
+        assert_eq!(h.blocked_until(true, now), h.reset);
+
+        // A reset in the past, or absurdly far off
+        let h = RateLimitHeaders {
+            reset: Some(now - Duration::seconds(1)),
+            ..Default::default()
+        };
+        assert_eq!(h.blocked_until(true, now), None);
+        let h = RateLimitHeaders {
+            retry_after: Some(now + Duration::days(30)),
+            ..Default::default()
+        };
+        assert_eq!(
+            h.blocked_until(true, now),
+            Some(now + Duration::seconds(MAX_BLOCK_SECS))
+        );
+    }
+
+    #[test]
+    fn test_observe() {
+        let limits = UpstreamLimits::default();
+        let now = Utc::now();
+        let ok = RateLimitHeaders {
+            limit: Some(10),
+            remaining: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(limits.observe("key1", &ok, false, now), None);
+        assert_eq!(limits.blocked_until("key1", now), None);
+
+        let limited = RateLimitHeaders {
+            retry_after: Some(now + Duration::seconds(60)),
+            ..Default::default()
+        };
+        let until = limits.observe("key1", &limited, true, now);
+        assert_eq!(until, limited.retry_after);
+        assert_eq!(limits.blocked_until("key1", now), until);
+        assert_eq!(limits.blocked_until("key2", now), None);
+        assert_eq!(
+            limits.blocked_until("key1", now + Duration::seconds(61)),
+            None
+        );
+
+        let accounts = limits.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].account, key_hash("key1"));
+        // Earlier values are kept when a response doesn't repeat them
+        assert_eq!(accounts[0].remaining, Some(4));
+        assert_eq!(accounts[0].limit, Some(10));
+    }
+}
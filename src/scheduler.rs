@@ -0,0 +1,533 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
+
+use crate::config::Config;
+use crate::proxy::{self, FetchOutcome};
+use crate::solar::{self, Location, NightPolicy};
+use crate::AppState;
+
+/// Endpoints refreshed for every scheduled site.
+const ENDPOINTS: [&str; 2] = ["forecasts", "estimated_actuals"];
+
+/// How scheduled refreshes are distributed over the day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleMode {
+    /// Spread evenly over the whole UTC day.
+    Even,
+    /// Spread evenly over the local daylight window only.
+    Daylight,
+}
+
+/// A site refreshed in the background, with the key its calls are made on.
+#[derive(Debug, Clone)]
+pub struct ScheduledSite {
+    pub id: String,
+    pub api_key: String,
+}
+
+/// Settings for the background refresh task.
+#[derive(Debug, Clone)]
+pub struct ScheduleConfig {
+    pub sites: Vec<ScheduledSite>,
+    pub mode: ScheduleMode,
+    /// Local hours `[start, end)` treated as daylight in `Daylight` mode.
+    pub daylight_hours: (u32, u32),
+    /// Daily calls left unplanned so on-demand client requests still work.
+    pub reserve: u32,
+}
+
+/// Number of refresh rounds per site per day that fit in the budget, where a
+/// round fetches every endpoint once.
+pub fn rounds_per_day(daily_budget: u32, reserve: u32, sites: usize) -> u32 {
+    let calls_per_round = (sites * ENDPOINTS.len()) as u32;
+    if calls_per_round == 0 {
+        return 0;
+    }
+    daily_budget.saturating_sub(reserve) / calls_per_round
+}
+
+/// `n` instants spread evenly over `[start, end)`, each in the middle of its
+/// share of the window.
+pub fn spread(start: DateTime<Utc>, end: DateTime<Utc>, n: u32) -> Vec<DateTime<Utc>> {
+    if n == 0 || end <= start {
+        return Vec::new();
+    }
+    let step = (end - start) / n as i32;
+    (0..n as i32).map(|i| start + step * i + step / 2).collect()
+}
+
+impl ScheduleConfig {
+    /// Sites with `refresh` enabled and an API key (their own or the default).
+    pub fn from_config(config: &Config) -> Self {
+        let mut sites = Vec::new();
+        for site in config.sites.iter().filter(|s| s.refresh) {
+            match site.api_key.as_ref().or(config.api_key.as_ref()) {
+                Some(api_key) => sites.push(ScheduledSite {
+                    id: site.id.clone(),
+                    api_key: api_key.clone(),
+                }),
+                None => tracing::warn!(
+                    "{}: no API key configured, not refreshed in the background",
+                    site.id
+                ),
+            }
+        }
+        Self {
+            sites,
+            mode: config.schedule.mode,
+            daylight_hours: config.schedule.daylight_hours,
+            reserve: config.schedule.reserve,
+        }
+    }
+
+    /// Refresh rounds per day for a site: the budget of its API key is
+    /// shared between every scheduled site using that key.
+    pub fn rounds_for(&self, site: &ScheduledSite, daily_budget: u32) -> u32 {
+        let sharing = self
+            .sites
+            .iter()
+            .filter(|s| s.api_key == site.api_key)
+            .count();
+        rounds_per_day(daily_budget, self.reserve, sharing)
+    }
+
+    /// Refresh instants for one date. The date is a UTC date in `Even` mode,
+    /// and in `Daylight` mode the site's solar date if it has a location or
+    /// the local date otherwise.
+    fn slots_for(
+        &self,
+        date: NaiveDate,
+        rounds: u32,
+        location: Option<Location>,
+        sunrise_offset: Duration,
+    ) -> Vec<DateTime<Utc>> {
+        match (self.mode, location) {
+            (ScheduleMode::Even, _) => {
+                let start = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+                spread(start, start + Duration::days(1), rounds)
+            }
+            (ScheduleMode::Daylight, Some(loc)) => {
+                match solar::daylight_window(date, loc, sunrise_offset) {
+                    Some((start, end)) => spread(start, end, rounds),
+                    None => Vec::new(),
+                }
+            }
+            (ScheduleMode::Daylight, None) => {
+                let (start_h, end_h) = self.daylight_hours;
+                let local = |h: u32| {
+                    let naive = date.and_hms_opt(0, 0, 0).unwrap() + Duration::hours(h as i64);
+                    Local
+                        .from_local_datetime(&naive)
+                        .earliest()
+                        .map(|t| t.with_timezone(&Utc))
+                };
+                match (local(start_h), local(end_h)) {
+                    (Some(start), Some(end)) => spread(start, end, rounds),
+                    _ => Vec::new(),
+                }
+            }
+        }
+    }
+
+    /// The earliest planned refresh for `site` strictly after `after`.
+    /// Slots that fall at night for a located site are skipped.
+    pub fn next_slot(
+        &self,
+        night: &NightPolicy,
+        site: &str,
+        after: DateTime<Utc>,
+        rounds: u32,
+    ) -> Option<DateTime<Utc>> {
+        let location = night.location(site);
+        let today = match (self.mode, location) {
+            (ScheduleMode::Even, _) => after.date_naive(),
+            (ScheduleMode::Daylight, Some(loc)) => solar::solar_date(after, loc),
+            (ScheduleMode::Daylight, None) => after.with_timezone(&Local).date_naive(),
+        };
+        // Look a few days ahead so polar nights and sparse schedules still
+        // find a slot.
+        (-1..=7)
+            .filter_map(|d| today.checked_add_signed(Duration::days(d)))
+            .flat_map(|date| self.slots_for(date, rounds, location, night.sunrise_offset()))
+            .filter(|slot| *slot > after && night.night_until(site, *slot).is_none())
+            .min()
+    }
+}
+
+/// Start the background refresh task if any sites are configured.
+pub fn spawn(state: Arc<AppState>, config: ScheduleConfig) {
+    if config.sites.is_empty() {
+        return;
+    }
+    let budget = state.quota.daily_budget();
+    for site in &config.sites {
+        let rounds = config.rounds_for(site, budget);
+        if rounds == 0 {
+            tracing::warn!(
+                "{}: daily budget {} (reserve {}) is too small for scheduled refreshes",
+                site.id,
+                budget,
+                config.reserve
+            );
+        } else {
+            tracing::info!(
+                "{}: {} scheduled refresh(es)/day, mode {:?}",
+                site.id,
+                rounds,
+                config.mode
+            );
+        }
+    }
+    tokio::spawn(run(state, config));
+}
+
+async fn run(state: Arc<AppState>, config: ScheduleConfig) {
+    let budget = state.quota.daily_budget();
+    let mut after = Utc::now();
+    loop {
+        let planned: Vec<(&ScheduledSite, DateTime<Utc>)> = config
+            .sites
+            .iter()
+            .filter_map(|site| {
+                let rounds = config.rounds_for(site, budget);
+                config
+                    .next_slot(&state.night, &site.id, after, rounds)
+                    .map(|slot| (site, slot))
+            })
+            .collect();
+        let Some(slot) = planned.iter().map(|(_, slot)| *slot).min() else {
+            tracing::warn!("Scheduler: no refresh slots could be planned, stopping");
+            return;
+        };
+        tracing::debug!("Scheduler: next refresh at {}", slot);
+        let wait = (slot - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        for (site, _) in planned.iter().filter(|(_, s)| *s == slot) {
+            refresh_site(&state, &config, site).await;
+        }
+        after = slot;
+    }
+}
+
+/// Refresh every endpoint of one site that is no longer fresh, leaving the
+/// reserve untouched.
+pub async fn refresh_site(state: &Arc<AppState>, config: &ScheduleConfig, site: &ScheduledSite) {
+    let rooftop_id = site.id.as_str();
+    if let Some(until) = state.night.night_until(rooftop_id, Utc::now()) {
+        tracing::info!(
+            "{}: scheduled refresh skipped, night until {}",
+            rooftop_id,
+            until
+        );
+        return;
+    }
+    // The key may have been rotated by a reload since the schedule was planned
+    let api_key = state
+        .credentials
+        .key_for(rooftop_id)
+        .unwrap_or_else(|| site.api_key.clone());
+    let budget = state.quota.daily_budget().saturating_sub(config.reserve);
+    for endpoint in ENDPOINTS {
+        if state
+            .cache
+            .is_fresh(rooftop_id, endpoint, state.ttl_for(rooftop_id, endpoint))
+            .await
+        {
+            tracing::debug!(
+                "{}/{}: scheduled refresh skipped, still fresh",
+                rooftop_id,
+                endpoint
+            );
+            continue;
+        }
+        if state.quota.used_today(&api_key).await >= budget {
+            tracing::info!(
+                "{}/{}: scheduled refresh skipped, budget reserved for clients",
+                rooftop_id,
+                endpoint
+            );
+            continue;
+        }
+        match proxy::scheduled_refresh(state, rooftop_id, endpoint, &api_key).await {
+            Some(FetchOutcome::Fetched { cache_status, .. }) => {
+                tracing::info!(
+                    "{}/{}: scheduled refresh {}",
+                    rooftop_id,
+                    endpoint,
+                    cache_status
+                );
+            }
+            Some(FetchOutcome::Error { status, .. }) => {
+                tracing::warn!(
+                    "{}/{}: scheduled refresh failed with {}",
+                    rooftop_id,
+                    endpoint,
+                    status
+                );
+            }
+            Some(FetchOutcome::Failed(e)) => {
+                tracing::warn!(
+                    "{}/{}: scheduled refresh failed: {}",
+                    rooftop_id,
+                    endpoint,
+                    e
+                );
+            }
+            Some(_) | None => {
+                tracing::warn!(
+                    "{}/{}: scheduled refresh not possible",
+                    rooftop_id,
+                    endpoint
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{EndpointSettings, Policies};
+    use crate::fake_solcast::{test_state, FakeResponse, FakeSolcast};
+    use crate::quota::QuotaLedger;
+    use axum::http::StatusCode;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn site(id: &str) -> ScheduledSite {
+        ScheduledSite {
+            id: id.to_string(),
+            api_key: "key1".into(),
+        }
+    }
+
+    fn config(mode: ScheduleMode, sites: &[&str]) -> ScheduleConfig {
+        ScheduleConfig {
+            sites: sites.iter().map(|s| site(s)).collect(),
+            mode,
+            daylight_hours: (6, 20),
+            reserve: 2,
+        }
+    }
+
+    #[test]
+    fn test_rounds_per_day() {
+        assert_eq!(rounds_per_day(10, 2, 1), 4);
+        assert_eq!(rounds_per_day(10, 2, 2), 2);
+        assert_eq!(rounds_per_day(10, 2, 3), 1);
+        assert_eq!(rounds_per_day(10, 2, 5), 0);
+        assert_eq!(rounds_per_day(1, 2, 1), 0);
+        assert_eq!(rounds_per_day(10, 0, 0), 0);
+    }
+
+    #[test]
+    fn test_spread_is_centered() {
+        let start = utc("2026-06-01T00:00:00Z");
+        let slots = spread(start, start + Duration::days(1), 4);
+        assert_eq!(
+            slots,
+            vec![
+                utc("2026-06-01T03:00:00Z"),
+                utc("2026-06-01T09:00:00Z"),
+                utc("2026-06-01T15:00:00Z"),
+                utc("2026-06-01T21:00:00Z"),
+            ]
+        );
+        assert!(spread(start, start, 4).is_empty());
+        assert!(spread(start, start + Duration::days(1), 0).is_empty());
+    }
+
+    fn sydney() -> NightPolicy {
+        let mut locations = HashMap::new();
+        locations.insert(
+            "syd".to_string(),
+            Location {
+                latitude: -33.87,
+                longitude: 151.21,
+            },
+        );
+        NightPolicy::new(locations, Duration::minutes(30))
+    }
+
+    #[test]
+    fn test_next_slot_even() {
+        let cfg = config(ScheduleMode::Even, &["site1"]);
+        let night = NightPolicy::default();
+        assert_eq!(
+            cfg.next_slot(&night, "site1", utc("2026-06-01T10:00:00Z"), 4),
+            Some(utc("2026-06-01T15:00:00Z"))
+        );
+        // Strictly after, so a slot that just fired isn't repeated
+        assert_eq!(
+            cfg.next_slot(&night, "site1", utc("2026-06-01T15:00:00Z"), 4),
+            Some(utc("2026-06-01T21:00:00Z"))
+        );
+        // Rolls over to the next day
+        assert_eq!(
+            cfg.next_slot(&night, "site1", utc("2026-06-01T22:00:00Z"), 4),
+            Some(utc("2026-06-02T03:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_next_slot_even_skips_night() {
+        // Sydney in June: night from ~06:54 to ~20:30 UTC (with offset)
+        let cfg = config(ScheduleMode::Even, &["syd"]);
+        let night = sydney();
+        assert_eq!(
+            cfg.next_slot(&night, "syd", utc("2026-06-21T04:00:00Z"), 4),
+            Some(utc("2026-06-21T21:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_next_slot_daylight_uses_sun_times() {
+        let cfg = config(ScheduleMode::Daylight, &["syd"]);
+        let night = sydney();
+        let mut after = utc("2026-06-21T00:00:00Z");
+        for _ in 0..8 {
+            let slot = cfg.next_slot(&night, "syd", after, 4).unwrap();
+            assert_eq!(night.night_until("syd", slot), None, "slot {slot} at night");
+            assert!(slot > after);
+            after = slot;
+        }
+    }
+
+    #[test]
+    fn test_next_slot_daylight_stays_in_window() {
+        let cfg = config(ScheduleMode::Daylight, &["site1"]);
+        let night = NightPolicy::default();
+        let mut after = utc("2026-06-01T00:00:00Z");
+        for _ in 0..8 {
+            let slot = cfg.next_slot(&night, "site1", after, 4).unwrap();
+            let hour = slot.with_timezone(&Local).format("%H").to_string();
+            let hour: u32 = hour.parse().unwrap();
+            assert!((6..20).contains(&hour), "slot at local hour {hour}");
+            assert!(slot > after);
+            after = slot;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_site_fetches_all_endpoints() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        state.rate_limit = 9000;
+        let state = Arc::new(state);
+
+        refresh_site(
+            &state,
+            &config(ScheduleMode::Even, &["site1"]),
+            &site("site1"),
+        )
+        .await;
+        assert_eq!(upstream.request_count(), 2);
+        assert!(state.cache.is_fresh("site1", "forecasts", 7200).await);
+        assert!(
+            state
+                .cache
+                .is_fresh("site1", "estimated_actuals", 7200)
+                .await
+        );
+
+        // Fresh entries are left alone
+        refresh_site(
+            &state,
+            &config(ScheduleMode::Even, &["site1"]),
+            &site("site1"),
+        )
+        .await;
+        assert_eq!(upstream.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_site_uses_endpoint_ttls() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.ttl = 7200;
+        state.rate_limit = 9000;
+        let mut cfg = Config::default();
+        cfg.endpoints.insert(
+            "forecasts".into(),
+            EndpointSettings {
+                ttl: Some(0),
+                rate_limit: None,
+            },
+        );
+        state.policies = Policies::from_config(&cfg);
+        let state = Arc::new(state);
+        let sched = config(ScheduleMode::Even, &["site1"]);
+
+        refresh_site(&state, &sched, &site("site1")).await;
+        assert_eq!(upstream.request_count(), 2);
+
+        // Only the expired endpoint is refetched, despite the rate limit
+        refresh_site(&state, &sched, &site("site1")).await;
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].path, "/rooftop_sites/site1/forecasts");
+    }
+
+    #[test]
+    fn test_from_config_shares_budget_per_key() {
+        let mut cfg = Config {
+            api_key: Some("shared".into()),
+            ..Config::default()
+        };
+        cfg.site_mut("a");
+        cfg.site_mut("b");
+        cfg.site_mut("c").api_key = Some("own".into());
+        cfg.site_mut("d").refresh = false;
+        let sched = ScheduleConfig::from_config(&cfg);
+        assert_eq!(sched.sites.len(), 3);
+        // 10 - 2 reserve = 8 calls; two sites share "shared", one has "own"
+        assert_eq!(sched.rounds_for(&sched.sites[0], 10), 2);
+        assert_eq!(sched.rounds_for(&sched.sites[2], 10), 4);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_site_leaves_reserve() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let mut state = test_state(&upstream.url(), dir.path());
+        state.quota = QuotaLedger::new(dir.path(), 3);
+        let state = Arc::new(state);
+
+        // budget 3, reserve 2: only one scheduled call allowed
+        refresh_site(
+            &state,
+            &config(ScheduleMode::Even, &["site1"]),
+            &site("site1"),
+        )
+        .await;
+        assert_eq!(upstream.request_count(), 1);
+        assert_eq!(state.quota.used_today("key1").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_site_error_keeps_old_entry() {
+        let upstream = FakeSolcast::start().await;
+        let dir = TempDir::new().unwrap();
+        let state = Arc::new(test_state(&upstream.url(), dir.path()));
+        let cfg = config(ScheduleMode::Even, &["site1"]);
+
+        refresh_site(&state, &cfg, &site("site1")).await;
+        let (before, _) = state.cache.get("site1", "forecasts").await.unwrap();
+        upstream.push(
+            "/rooftop_sites/site1/forecasts",
+            FakeResponse::status(StatusCode::INTERNAL_SERVER_ERROR, "boom"),
+        );
+        refresh_site(&state, &cfg, &site("site1")).await;
+        let (after, _) = state.cache.get("site1", "forecasts").await.unwrap();
+        assert_eq!(before.fetched_at, after.fetched_at);
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+/// Coalesces concurrent work by key: the first caller for a key starts the
+/// work, later callers for the same key wait for and share its result.
+///
+/// The work runs in a spawned task, so it completes (and its result is
+/// delivered to the remaining waiters) even if the caller that started it
+/// is dropped, e.g. when an HTTP client disconnects mid-request.
+pub struct SingleFlight<T> {
+    inflight: Arc<Mutex<HashMap<String, watch::Receiver<Option<T>>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            inflight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `work` for `key`, or join the flight already running for it.
+    /// Returns `None` only if the work panicked.
+    pub async fn run<F>(&self, key: String, work: F) -> Option<T>
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let mut rx = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(rx) => rx.clone(),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    inflight.insert(key.clone(), rx.clone());
+                    let map = self.inflight.clone();
+                    tokio::spawn(async move {
+                        // Remove the key even if `work` panics, so the next
+                        // caller starts a new flight instead of waiting forever.
+                        let guard = RemoveOnDrop { map, key };
+                        let result = work.await;
+                        let _ = tx.send(Some(result));
+                        drop(guard);
+                    });
+                    rx
+                }
+            }
+        };
+        let result = rx.wait_for(Option::is_some).await.ok()?;
+        result.clone()
+    }
+
+    /// Number of keys with work currently in flight.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.inflight.lock().unwrap().len()
+    }
+}
+
+struct RemoveOnDrop<T> {
+    map: Arc<Mutex<HashMap<String, watch::Receiver<Option<T>>>>>,
+    key: String,
+}
+
+impl<T> Drop for RemoveOnDrop<T> {
+    fn drop(&mut self) {
+        if let Ok(mut map) = self.map.lock() {
+            map.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_concurrent_calls_share_one_run() {
+        let flight = Arc::new(SingleFlight::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let mut handles = Vec::new();
+        for _ in 0..5 {
+            let flight = flight.clone();
+            let runs = runs.clone();
+            handles.push(tokio::spawn(async move {
+                flight
+                    .run("k".into(), async move {
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        42
+                    })
+                    .await
+            }));
+        }
+        for h in handles {
+            assert_eq!(h.await.unwrap(), Some(42));
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(flight.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_different_keys_run_independently() {
+        let flight = SingleFlight::new();
+        let (a, b) = tokio::join!(
+            flight.run("a".into(), async { 1 }),
+            flight.run("b".into(), async { 2 })
+        );
+        assert_eq!((a, b), (Some(1), Some(2)));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_work_releases_key() {
+        let flight: SingleFlight<u32> = SingleFlight::new();
+        let result = flight.run("k".into(), async { panic!("boom") }).await;
+        assert_eq!(result, None);
+        assert_eq!(flight.len(), 0);
+        assert_eq!(flight.run("k".into(), async { 7 }).await, Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_work_survives_dropped_caller() {
+        let flight = Arc::new(SingleFlight::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let leader = {
+            let flight = flight.clone();
+            let runs = runs.clone();
+            tokio::spawn(async move {
+                flight
+                    .run("k".into(), async move {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        runs.fetch_add(1, Ordering::SeqCst);
+                        1
+                    })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let follower = flight.run("k".into(), async { 2 });
+        leader.abort();
+        assert_eq!(follower.await, Some(1));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+
+/// Geographic position of a site, in decimal degrees (east and north positive).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Sunrise and sunset for one date at one location.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SunTimes {
+    Normal {
+        sunrise: DateTime<Utc>,
+        sunset: DateTime<Utc>,
+    },
+    /// The sun never sets.
+    PolarDay,
+    /// The sun never rises.
+    PolarNight,
+}
+
+const J2000: f64 = 2451545.0;
+const UNIX_EPOCH_JD: f64 = 2440587.5;
+
+fn julian_to_utc(jd: f64) -> DateTime<Utc> {
+    let millis = ((jd - UNIX_EPOCH_JD) * 86_400_000.0).round() as i64;
+    DateTime::from_timestamp_millis(millis).unwrap_or_default()
+}
+
+/// Sunrise and sunset on `date` using the NOAA sunrise equation, accurate to
+/// about a minute outside the polar regions. `date` is the calendar date at
+/// the site's local solar noon.
+pub fn sun_times(date: NaiveDate, loc: Location) -> SunTimes {
+    let rad = PI / 180.0;
+    let noon = date.and_hms_opt(12, 0, 0).unwrap().and_utc();
+    let jd = noon.timestamp() as f64 / 86_400.0 + UNIX_EPOCH_JD;
+
+    // Mean solar time at the site, then solar anomaly and ecliptic longitude
+    let n = (jd - J2000 + 0.0008).round();
+    let mean_time = n - loc.longitude / 360.0;
+    let m = (357.5291 + 0.98560028 * mean_time).rem_euclid(360.0);
+    let c =
+        1.9148 * (m * rad).sin() + 0.0200 * (2.0 * m * rad).sin() + 0.0003 * (3.0 * m * rad).sin();
+    let lambda = (m + c + 180.0 + 102.9372).rem_euclid(360.0);
+    let transit =
+        J2000 + mean_time + 0.0053 * (m * rad).sin() - 0.0069 * (2.0 * lambda * rad).sin();
+
+    // Declination and hour angle at which the sun's upper limb touches the horizon
+    let sin_decl = (lambda * rad).sin() * (23.4397 * rad).sin();
+    let cos_decl = sin_decl.asin().cos();
+    let lat = loc.latitude * rad;
+    let cos_hour = ((-0.833 * rad).sin() - lat.sin() * sin_decl) / (lat.cos() * cos_decl);
+    if cos_hour < -1.0 {
+        return SunTimes::PolarDay;
+    }
+    if cos_hour > 1.0 {
+        return SunTimes::PolarNight;
+    }
+    let hour_angle = cos_hour.acos() / rad;
+    SunTimes::Normal {
+        sunrise: julian_to_utc(transit - hour_angle / 360.0),
+        sunset: julian_to_utc(transit + hour_angle / 360.0),
+    }
+}
+
+/// Calendar date at the site's local solar time.
+pub fn solar_date(now: DateTime<Utc>, loc: Location) -> NaiveDate {
+    (now + Duration::seconds((loc.longitude * 240.0) as i64)).date_naive()
+}
+
+/// Daylight interval `[start, end)` for `date`, with `start` moved
+/// `sunrise_offset` earlier than sunrise. `None` during polar night.
+pub fn daylight_window(
+    date: NaiveDate,
+    loc: Location,
+    sunrise_offset: Duration,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    match sun_times(date, loc) {
+        SunTimes::Normal { sunrise, sunset } => Some((sunrise - sunrise_offset, sunset)),
+        SunTimes::PolarDay => {
+            // The whole solar day, centred on local solar noon
+            let noon = date.and_hms_opt(12, 0, 0).unwrap().and_utc()
+                - Duration::seconds((loc.longitude * 240.0) as i64);
+            Some((noon - Duration::hours(12), noon + Duration::hours(12)))
+        }
+        SunTimes::PolarNight => None,
+    }
+}
+
+/// Suppresses upstream fetches for sites while it is dark there.
+///
+/// A site is "night" between sunset and `sunrise_offset` before the next
+/// sunrise. Sites without a configured location are never night.
+#[derive(Debug, Clone, Default)]
+pub struct NightPolicy {
+    locations: HashMap<String, Location>,
+    sunrise_offset: Duration,
+}
+
+impl NightPolicy {
+    pub fn new(locations: HashMap<String, Location>, sunrise_offset: Duration) -> Self {
+        Self {
+            locations,
+            sunrise_offset,
+        }
+    }
+
+    pub fn location(&self, rooftop_id: &str) -> Option<Location> {
+        self.locations.get(rooftop_id).copied()
+    }
+
+    pub fn sunrise_offset(&self) -> Duration {
+        self.sunrise_offset
+    }
+
+    /// The site's daylight windows around `now`, in order.
+    fn windows(&self, loc: Location, now: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let today = solar_date(now, loc);
+        [
+            today.pred_opt(),
+            Some(today),
+            today.succ_opt(),
+            today.succ_opt().and_then(|d| d.succ_opt()),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|d| daylight_window(d, loc, self.sunrise_offset))
+        .collect()
+    }
+
+    /// If it is night at the site, when fetching is next allowed.
+    pub fn night_until(&self, rooftop_id: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let loc = self.location(rooftop_id)?;
+        let windows = self.windows(loc, now);
+        if windows
+            .iter()
+            .any(|(start, end)| *start <= now && now < *end)
+        {
+            return None;
+        }
+        // Polar night with no window in range: check again in a day
+        Some(
+            windows
+                .iter()
+                .map(|(start, _)| *start)
+                .find(|start| *start > now)
+                .unwrap_or(now + Duration::days(1)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYDNEY: Location = Location {
+        latitude: -33.87,
+        longitude: 151.21,
+    };
+    const LONDON: Location = Location {
+        latitude: 51.51,
+        longitude: -0.13,
+    };
+    const TROMSO: Location = Location {
+        latitude: 69.65,
+        longitude: 18.96,
+    };
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn assert_close(actual: DateTime<Utc>, expected: &str) {
+        let diff = (actual - utc(expected)).num_seconds().abs();
+        assert!(diff <= 180, "{actual} is {diff}s away from {expected}");
+    }
+
+    #[test]
+    fn test_london_summer_solstice() {
+        // Published: sunrise 04:43 BST, sunset 21:21 BST
+        let SunTimes::Normal { sunrise, sunset } = sun_times(date("2026-06-21"), LONDON) else {
+            panic!("expected normal day");
+        };
+        assert_close(sunrise, "2026-06-21T03:43:00Z");
+        assert_close(sunset, "2026-06-21T20:21:00Z");
+    }
+
+    #[test]
+    fn test_sydney_winter() {
+        // Published: sunrise 07:00 AEST, sunset 16:54 AEST
+        let SunTimes::Normal { sunrise, sunset } = sun_times(date("2026-06-21"), SYDNEY) else {
+            panic!("expected normal day");
+        };
+        assert_close(sunrise, "2026-06-20T21:00:00Z");
+        assert_close(sunset, "2026-06-21T06:54:00Z");
+    }
+
+    #[test]
+    fn test_polar() {
+        assert_eq!(sun_times(date("2026-06-21"), TROMSO), SunTimes::PolarDay);
+        assert_eq!(sun_times(date("2026-12-21"), TROMSO), SunTimes::PolarNight);
+    }
+
+    #[test]
+    fn test_night_policy() {
+        let mut locations = HashMap::new();
+        locations.insert("syd".to_string(), SYDNEY);
+        let policy = NightPolicy::new(locations, Duration::minutes(30));
+
+        // Midday in Sydney
+        assert_eq!(policy.night_until("syd", utc("2026-06-21T02:00:00Z")), None);
+        // Unknown sites are never night
+        assert_eq!(
+            policy.night_until("other", utc("2026-06-21T14:00:00Z")),
+            None
+        );
+
+        // Midnight in Sydney: resumes 30 minutes before the 07:00 sunrise
+        let until = policy
+            .night_until("syd", utc("2026-06-21T14:00:00Z"))
+            .unwrap();
+        assert_close(until, "2026-06-21T20:30:00Z");
+
+        // Inside the pre-sunrise offset counts as day
+        assert_eq!(policy.night_until("syd", utc("2026-06-21T20:45:00Z")), None);
+    }
+
+    #[test]
+    fn test_polar_night_policy() {
+        let mut locations = HashMap::new();
+        locations.insert("tromso".to_string(), TROMSO);
+        let policy = NightPolicy::new(locations, Duration::zero());
+        let now = utc("2026-12-21T12:00:00Z");
+        assert_eq!(
+            policy.night_until("tromso", now),
+            Some(now + Duration::days(1))
+        );
+
+        let now = utc("2026-06-21T00:00:00Z");
+        assert_eq!(policy.night_until("tromso", now), None);
+    }
+}
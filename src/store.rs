@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::cache::{cache_key, CacheEntry};
+use crate::compress::{self, Body};
+use crate::config::CacheSettings;
+use crate::persist;
+
+/// Schema version of `cache.json`. Bump it when `DiskCache` changes, and
+/// teach `migrate` to convert the previous version.
+const CACHE_VERSION: u64 = 2;
+
+/// Schema version of `cache.db`, kept in SQLite's `user_version`.
+const SQLITE_VERSION: i64 = 3;
+
+/// Where cached entries are persisted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// `cache.json`, rewritten whole on every upstream fetch.
+    #[default]
+    Json,
+    /// `cache.db`, an embedded SQLite database updated one entry at a time.
+    Sqlite,
+}
+
+/// Persistent storage behind `ProxyCache`. The cache itself is served from
+/// memory; a store only loads it at startup and keeps it up to date.
+///
+/// Methods block, so async callers run them on the blocking thread pool.
+pub trait CacheStore: Send + Sync {
+    /// Every stored entry, by cache key.
+    fn load(&self) -> HashMap<String, CacheEntry>;
+    /// Insert or replace the entry for an endpoint of a site.
+    fn put(&self, site: &str, endpoint: &str, entry: &CacheEntry) -> Result<(), String>;
+    /// Delete entries by cache key.
+    fn remove(&self, keys: &[String]) -> Result<(), String>;
+    /// Where entries are kept, for logs.
+    fn describe(&self) -> String;
+}
+
+/// Open the store configured for the cache directory. Switching to SQLite
+/// imports an existing `cache.json` into the new database.
+pub fn open(settings: &CacheSettings) -> Result<Arc<dyn CacheStore>, String> {
+    match settings.backend {
+        Backend::Json => Ok(Arc::new(JsonStore::open(&settings.dir, settings.snapshots))),
+        Backend::Sqlite => {
+            let store = SqliteStore::open(&settings.dir)?;
+            let json = settings.dir.join("cache.json");
+            if store.is_empty()? && json.exists() {
+                let entries = JsonStore::open(&settings.dir, 0).load();
+                store.import(&entries)?;
+                tracing::info!(
+                    "Imported {} cache entries from {}",
+                    entries.len(),
+                    json.display()
+                );
+            }
+            Ok(Arc::new(store))
+        }
+    }
+}
+
+/// Serializable form for disk persistence (without Instant fields).
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskCache {
+    version: u64,
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// Bring a `cache.json` written by an older version up to `CACHE_VERSION`.
+fn migrate(mut disk: Value) -> Result<DiskCache, String> {
+    let object = disk.as_object_mut().ok_or("not a JSON object")?;
+    let version = match object.get("version") {
+        Some(v) => v.as_u64().ok_or("invalid version")?,
+        // Files from before the schema was versioned
+        None => 0,
+    };
+    if version > CACHE_VERSION {
+        return Err(format!(
+            "schema version {version} is newer than this build supports ({CACHE_VERSION})"
+        ));
+    }
+    // Version 0 had the same layout as 1, only without the version field.
+    // Version 2 stores bodies gzipped, as base64 `body_gzip`.
+    if version < 2 {
+        let entries = object.get_mut("entries").and_then(Value::as_object_mut);
+        for entry in entries.into_iter().flat_map(|e| e.values_mut()) {
+            let Some(entry) = entry.as_object_mut() else {
+                continue;
+            };
+            if let Some(Value::String(body)) = entry.remove("body") {
+                entry.insert(
+                    "body_gzip".into(),
+                    serde_json::to_value(Body::new(&body)).unwrap(),
+                );
+            }
+        }
+    }
+    object.insert("version".into(), CACHE_VERSION.into());
+    serde_json::from_value(disk).map_err(|e| e.to_string())
+}
+
+/// Every entry in one `cache.json`, written atomically with the last
+/// `snapshots` versions kept as `cache.json.1` .. `.N`.
+pub struct JsonStore {
+    path: PathBuf,
+    snapshots: usize,
+    /// Everything stored, since each write replaces the whole file.
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl JsonStore {
+    pub fn open(cache_dir: &Path, snapshots: usize) -> Self {
+        Self {
+            path: cache_dir.join("cache.json"),
+            snapshots,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Load `cache.json`. An unreadable file is quarantined and the newest
+    /// readable snapshot is used instead.
+    fn load_from_disk(&self) -> Option<HashMap<String, CacheEntry>> {
+        match Self::read_file(&self.path) {
+            Ok(disk) => return disk.map(|d| d.entries),
+            Err(reason) => {
+                persist::quarantine(&self.path, &reason);
+            }
+        }
+        for n in 1..=self.snapshots {
+            let snapshot = persist::snapshot_path(&self.path, n);
+            match Self::read_file(&snapshot) {
+                Ok(Some(disk)) => {
+                    tracing::warn!("Recovered cache from {}", snapshot.display());
+                    return Some(disk.entries);
+                }
+                Ok(None) => {}
+                Err(reason) => {
+                    tracing::error!("{} is unreadable too ({})", snapshot.display(), reason)
+                }
+            }
+        }
+        tracing::error!("No readable cache snapshot, starting empty");
+        None
+    }
+
+    /// A cache file, migrated to the current schema; `None` if it doesn't exist.
+    fn read_file(path: &Path) -> Result<Option<DiskCache>, String> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        let disk: Value = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+        migrate(disk).map(Some)
+    }
+}
+
+impl CacheStore for JsonStore {
+    fn load(&self) -> HashMap<String, CacheEntry> {
+        let entries = self.load_from_disk().unwrap_or_default();
+        self.entries.lock().unwrap().clone_from(&entries);
+        entries
+    }
+
+    fn put(&self, site: &str, endpoint: &str, entry: &CacheEntry) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(cache_key(site, endpoint), entry.clone());
+        self.write(&entries)
+    }
+
+    fn remove(&self, keys: &[String]) -> Result<(), String> {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        for key in keys {
+            entries.remove(key);
+        }
+        if entries.len() == before {
+            return Ok(());
+        }
+        self.write(&entries)
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+impl JsonStore {
+    /// Replace `cache.json` with `entries`, rotating snapshots first.
+    fn write(&self, entries: &HashMap<String, CacheEntry>) -> Result<(), String> {
+        let disk = DiskCache {
+            version: CACHE_VERSION,
+            entries: entries.clone(),
+        };
+        let json = serde_json::to_string_pretty(&disk).map_err(|e| e.to_string())?;
+        if let Err(e) = persist::rotate(&self.path, self.snapshots) {
+            tracing::warn!(
+                "Failed to rotate cache snapshots of {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+        persist::write_atomic_blocking(&self.path, json.as_bytes()).map_err(|e| e.to_string())
+    }
+}
+
+/// Entries as rows of an SQLite database, `cache.db`, so a fetch writes
+/// only its own entry.
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open or create `cache.db`. A file that isn't a usable database is
+    /// quarantined and replaced with an empty one.
+    pub fn open(cache_dir: &Path) -> Result<Self, String> {
+        let path = cache_dir.join("cache.db");
+        let conn = match Self::connect(&path) {
+            Ok(conn) => conn,
+            Err(reason) if path.exists() => {
+                persist::quarantine(&path, &reason);
+                for suffix in ["-wal", "-shm"] {
+                    let mut name = path.clone().into_os_string();
+                    name.push(suffix);
+                    let _ = std::fs::remove_file(name);
+                }
+                Self::connect(&path)?
+            }
+            Err(reason) => return Err(format!("{}: {}", path.display(), reason)),
+        };
+        Ok(Self {
+            path,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn connect(path: &Path) -> Result<Connection, String> {
+        let mut conn = Connection::open(path).map_err(|e| e.to_string())?;
+        // WAL keeps readers unblocked and each upsert a small append
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(|e| e.to_string())?;
+        conn.pragma_update(None, "synchronous", "NORMAL")
+            .map_err(|e| e.to_string())?;
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        if version > SQLITE_VERSION {
+            return Err(format!(
+                "schema version {version} is newer than this build supports ({SQLITE_VERSION})"
+            ));
+        }
+        if version < 1 {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS entries (
+                     site TEXT NOT NULL,
+                     endpoint TEXT NOT NULL,
+                     body TEXT NOT NULL,
+                     content_type TEXT NOT NULL,
+                     fetched_at TEXT NOT NULL,
+                     PRIMARY KEY (site, endpoint)
+                 );
+                 CREATE INDEX IF NOT EXISTS entries_fetched_at ON entries (fetched_at);
+                 PRAGMA user_version = 1;",
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        if version < 2 {
+            Self::gzip_bodies(&mut conn).map_err(|e| e.to_string())?;
+        }
+        if version < 3 {
+            conn.execute_batch(
+                "ALTER TABLE entries ADD COLUMN expires_at TEXT;
+                 PRAGMA user_version = 3;",
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(conn)
+    }
+
+    /// Schema 2: replace the text `body` column with a gzipped `body_gzip`.
+    fn gzip_bodies(conn: &mut Connection) -> rusqlite::Result<()> {
+        let tx = conn.transaction()?;
+        tx.execute_batch("ALTER TABLE entries ADD COLUMN body_gzip BLOB")?;
+        let rows = tx
+            .prepare("SELECT site, endpoint, body FROM entries")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (site, endpoint, body) in rows {
+            tx.execute(
+                "UPDATE entries SET body_gzip = ?3 WHERE site = ?1 AND endpoint = ?2",
+                params![site, endpoint, compress::gzip(body.as_bytes())],
+            )?;
+        }
+        tx.execute_batch(
+            "ALTER TABLE entries DROP COLUMN body;
+             PRAGMA user_version = 2;",
+        )?;
+        tx.commit()
+    }
+
+    fn is_empty(&self) -> Result<bool, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT 1 FROM entries LIMIT 1", [], |_| Ok(()))
+            .optional()
+            .map(|row| row.is_none())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Insert entries keyed `site:endpoint` in one transaction.
+    fn import(&self, entries: &HashMap<String, CacheEntry>) -> Result<(), String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        for (key, entry) in entries {
+            let Some((site, endpoint)) = key.split_once(':') else {
+                continue;
+            };
+            upsert(&tx, site, endpoint, entry)?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+}
+
+fn upsert(conn: &Connection, site: &str, endpoint: &str, entry: &CacheEntry) -> Result<(), String> {
+    conn.prepare_cached(
+        "INSERT INTO entries (site, endpoint, body_gzip, content_type, fetched_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (site, endpoint) DO UPDATE SET
+             body_gzip = excluded.body_gzip,
+             content_type = excluded.content_type,
+             fetched_at = excluded.fetched_at,
+             expires_at = excluded.expires_at",
+    )
+    .and_then(|mut stmt| {
+        stmt.execute(params![
+            site,
+            endpoint,
+            entry.body.gzip(),
+            entry.content_type,
+            // Fixed width, so rows sort by time
+            entry.fetched_at.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            entry
+                .expires_at
+                .map(|t| t.to_rfc3339_opts(SecondsFormat::Nanos, true)),
+        ])
+    })
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+impl CacheStore for SqliteStore {
+    fn load(&self) -> HashMap<String, CacheEntry> {
+        let conn = self.conn.lock().unwrap();
+        let rows = conn
+            .prepare("SELECT site, endpoint, body_gzip, content_type, fetched_at, expires_at FROM entries")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Vec<u8>>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, String>(4)?,
+                        row.get::<_, Option<String>>(5)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()
+            });
+        let rows = match rows {
+            Ok(rows) => rows,
+            Err(e) => {
+                tracing::error!("Failed to read {}: {}", self.path.display(), e);
+                return HashMap::new();
+            }
+        };
+        rows.into_iter()
+            .filter_map(
+                |(site, endpoint, body, content_type, fetched_at, expires_at)| {
+                    let Ok(fetched_at) = DateTime::parse_from_rfc3339(&fetched_at) else {
+                        tracing::warn!(
+                            "{}/{}: bad fetched_at in cache.db, skipped",
+                            site,
+                            endpoint
+                        );
+                        return None;
+                    };
+                    let Ok(body) = Body::from_gzip(body) else {
+                        tracing::warn!("{}/{}: bad body in cache.db, skipped", site, endpoint);
+                        return None;
+                    };
+                    let mut entry =
+                        CacheEntry::new(body, content_type, fetched_at.with_timezone(&Utc));
+                    entry.expires_at = expires_at
+                        .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+                        .map(|t| t.with_timezone(&Utc));
+                    Some((cache_key(&site, &endpoint), entry))
+                },
+            )
+            .collect()
+    }
+
+    fn put(&self, site: &str, endpoint: &str, entry: &CacheEntry) -> Result<(), String> {
+        upsert(&self.conn.lock().unwrap(), site, endpoint, entry)
+    }
+
+    fn remove(&self, keys: &[String]) -> Result<(), String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        for key in keys {
+            let Some((site, endpoint)) = key.split_once(':') else {
+                continue;
+            };
+            tx.execute(
+                "DELETE FROM entries WHERE site = ?1 AND endpoint = ?2",
+                params![site, endpoint],
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entry(body: &str) -> CacheEntry {
+        CacheEntry::new(Body::new(body), "application/json".into(), Utc::now())
+    }
+
+    #[test]
+    fn test_unversioned_json_migrated() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("cache.json"),
+            r#"{"entries":{"site1:forecasts":{"body":"{}","content_type":"application/json","fetched_at":"2026-06-01T00:00:00Z"}}}"#,
+        )
+        .unwrap();
+        let store = JsonStore::open(dir.path(), 2);
+        assert_eq!(store.load()["site1:forecasts"].body.text(), "{}");
+
+        store.put("site2", "forecasts", &entry("{}")).unwrap();
+        let data = std::fs::read_to_string(dir.path().join("cache.json")).unwrap();
+        assert!(data.contains(&format!("\"version\": {CACHE_VERSION}")));
+        assert!(data.contains("site1:forecasts"));
+        assert!(data.contains("body_gzip") && !data.contains("\"body\""));
+    }
+
+    #[test]
+    fn test_corrupt_json_quarantined_and_snapshot_used() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("cache.json");
+        {
+            let store = JsonStore::open(dir.path(), 2);
+            store.load();
+            for site in ["site1", "site2", "site3"] {
+                store.put(site, "forecasts", &entry("{}")).unwrap();
+            }
+        }
+        // Crash mid-write with the old in-place write
+        std::fs::write(&path, "{\"version\":1,\"entr").unwrap();
+
+        // cache.json.1 is the file from before the last put
+        assert_eq!(JsonStore::open(dir.path(), 2).load().len(), 2);
+        let quarantined: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("cache.json.corrupt-"))
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+
+        // A file from a newer build is kept aside rather than overwritten
+        std::fs::write(&path, r#"{"version":99,"entries":{}}"#).unwrap();
+        assert!(JsonStore::open(dir.path(), 0).load().is_empty());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_sqlite_upsert_and_reopen() {
+        let dir = TempDir::new().unwrap();
+        {
+            let store = SqliteStore::open(dir.path()).unwrap();
+            assert!(store.load().is_empty());
+            store.put("site1", "forecasts", &entry("old")).unwrap();
+            let mut new = entry("new");
+            new.expires_at = Some(new.fetched_at + chrono::Duration::seconds(60));
+            store.put("site1", "forecasts", &new).unwrap();
+            store
+                .put("site1", "estimated_actuals?hours=24", &entry("ea"))
+                .unwrap();
+        }
+        let store = SqliteStore::open(dir.path()).unwrap();
+        let entries = store.load();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["site1:forecasts"].body.text(), "new");
+        assert_eq!(entries["site1:forecasts"].lifetime(7200), 60);
+
+        store.remove(&["site1:forecasts".to_string()]).unwrap();
+        let entries = SqliteStore::open(dir.path()).unwrap().load();
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key("site1:estimated_actuals?hours=24"));
+        assert_eq!(
+            entries["site1:estimated_actuals?hours=24"].body.text(),
+            "ea"
+        );
+    }
+
+    #[test]
+    fn test_sqlite_imports_json_and_replaces_corrupt_db() {
+        let dir = TempDir::new().unwrap();
+        let json = JsonStore::open(dir.path(), 0);
+        json.load();
+        json.put("site1", "forecasts", &entry("from json")).unwrap();
+
+        let settings = CacheSettings {
+            dir: dir.path().to_path_buf(),
+            backend: Backend::Sqlite,
+            ..CacheSettings::default()
+        };
+        let store = open(&settings).unwrap();
+        assert_eq!(store.load()["site1:forecasts"].body.text(), "from json");
+        drop(store);
+
+        std::fs::write(dir.path().join("cache.db"), "not a database").unwrap();
+        let store = SqliteStore::open(dir.path()).unwrap();
+        assert!(store.load().is_empty());
+        store.put("site1", "forecasts", &entry("{}")).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_v1_bodies_gzipped() {
+        let dir = TempDir::new().unwrap();
+        {
+            let conn = Connection::open(dir.path().join("cache.db")).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE entries (
+                     site TEXT NOT NULL,
+                     endpoint TEXT NOT NULL,
+                     body TEXT NOT NULL,
+                     content_type TEXT NOT NULL,
+                     fetched_at TEXT NOT NULL,
+                     PRIMARY KEY (site, endpoint)
+                 );
+                 INSERT INTO entries VALUES
+                     ('site1', 'forecasts', '{\"forecasts\":[]}', 'application/json',
+                      '2026-06-01T00:00:00.000000000Z');
+                 PRAGMA user_version = 1;",
+            )
+            .unwrap();
+        }
+        let store = SqliteStore::open(dir.path()).unwrap();
+        assert_eq!(
+            store.load()["site1:forecasts"].body.text(),
+            "{\"forecasts\":[]}"
+        );
+        store.put("site1", "forecasts", &entry("{}")).unwrap();
+        assert_eq!(store.load()["site1:forecasts"].body.text(), "{}");
+    }
+}